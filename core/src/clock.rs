@@ -0,0 +1,40 @@
+use std::future::Future;
+use std::pin::Pin;
+use tokio::time::{Duration, Instant};
+
+/// Source of time for timers and timeouts
+///
+/// Everything in a node that waits goes through a `Clock`, so a simulation
+/// can run a whole cluster on virtual time.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// Clock backed by the tokio timer
+///
+/// Under a runtime with paused time (`start_paused`) this is a virtual clock
+/// that jumps straight to the next pending timer whenever all tasks are idle.
+#[derive(Clone, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Run `fut` for at most `duration` of `clock` time, `None` if it did not complete in time
+pub(crate) async fn timeout<F: Future>(clock: &dyn Clock, duration: Duration, fut: F) -> Option<F::Output> {
+    tokio::select! {
+        // Polling in a fixed order keeps simulations reproducible
+        biased;
+        out = fut => Some(out),
+        _ = clock.sleep(duration) => None,
+    }
+}
@@ -10,8 +10,14 @@ pub enum RuftError {
     Storage(String),
     InvalidState(String),
     Serialization(String),
+    /// A peer belongs to another cluster than this node
+    ClusterMismatch(String),
 }
 
+/// Metadata key marking a status that carries a [`RuftError::ClusterMismatch`]
+const ERROR_KEY: &str = "ruft-error";
+const CLUSTER_MISMATCH: &str = "cluster-mismatch";
+
 impl fmt::Display for RuftError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -20,6 +26,7 @@ impl fmt::Display for RuftError {
             RuftError::Storage(msg) => write!(f, "Storage error: {}", msg),
             RuftError::InvalidState(msg) => write!(f, "Invalid state: {}", msg),
             RuftError::Serialization(msg) => write!(f, "Serialization error: {}", msg),
+            RuftError::ClusterMismatch(msg) => write!(f, "Cluster mismatch: {}", msg),
         }
     }
 }
@@ -42,6 +49,23 @@ impl From<io::Error> for RuftError {
 
 impl From<tonic::Status> for RuftError {
     fn from(err: tonic::Status) -> Self {
+        if err.metadata().get(ERROR_KEY).is_some_and(|v| v == CLUSTER_MISMATCH) {
+            return RuftError::ClusterMismatch(err.message().to_string());
+        }
         RuftError::Rpc(err)
     }
 }
+
+impl From<RuftError> for tonic::Status {
+    fn from(err: RuftError) -> Self {
+        match err {
+            RuftError::Rpc(status) => status,
+            RuftError::ClusterMismatch(msg) => {
+                let mut status = tonic::Status::failed_precondition(msg);
+                status.metadata_mut().insert(ERROR_KEY, tonic::metadata::MetadataValue::from_static(CLUSTER_MISMATCH));
+                status
+            }
+            other => tonic::Status::internal(other.to_string()),
+        }
+    }
+}
@@ -2,14 +2,22 @@
 #[allow(unused_extern_crates)]
 extern crate std;
 
+mod clock;
 mod error;
 mod node;
+mod random;
 mod repeat_timer;
 mod role;
 pub mod rpc;
-mod storage;
+#[cfg(test)]
+mod sim;
 mod sm;
+mod storage;
+pub mod trace;
 
+pub use clock::{Clock, TokioClock};
 pub use error::{Result, RuftError};
-pub use node::{Config, ConfigBuilder, Ruft};
-pub use sm::Sm;
+pub use node::{Config, ConfigBuilder, Durability, Metrics, Ruft, RuftBuilder, TlsConfig};
+pub use random::{Random, SeededRandom, ThreadRandom};
+pub use sm::{Sm, SmView};
+pub use uuid::Uuid;
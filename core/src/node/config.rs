@@ -1,4 +1,43 @@
-use crate::rpc::Endpoint;
+use crate::rpc::{Backoff, Endpoint};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// When appended log entries are fsynced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Fsync after every append before it counts toward a commit
+    EveryWrite,
+    /// Collect the appends of concurrent proposals and fsync them together once
+    /// `window` has passed since the first of them or `max_bytes` are pending
+    GroupCommit { window: Duration, max_bytes: u64 },
+    /// Fsync in the background every `interval` and treat entries as durable once
+    /// written; a crash can lose committed entries, only meant for testing
+    Interval(Duration),
+}
+
+/// PEM files for mutual TLS between peers
+///
+/// Every node presents `cert_file` to its peers and accepts only peers whose
+/// certificate chains to `ca_file`. A certificate identifies node `id` by the DNS
+/// subject alternative name [`crate::rpc::peer_name`]`(id)`, e.g. `node-3`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub ca_file: PathBuf,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(ca_file: impl Into<PathBuf>, cert_file: impl Into<PathBuf>, key_file: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            ca_file: ca_file.into(),
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+        }
+    }
+}
 
 /// Configuration for a Raft node
 #[derive(Clone, Debug)]
@@ -6,6 +45,30 @@ pub struct Config {
     pub origin_endpoint: Vec<Endpoint>,
     pub data_dir: String,
     pub heartbeat_interval_millis: u64,
+    /// Deadline of an RPC to a peer, the heartbeat interval when `None`
+    pub rpc_timeout_millis: Option<u64>,
+    /// Delay between attempts to reconnect to an unreachable peer
+    pub reconnect_backoff: Backoff,
+    /// NDJSON file receiving every state transition, see [`crate::trace`]
+    pub trace_file: Option<String>,
+    /// Take a snapshot once this many applied entries are not covered by the last one
+    pub snapshot_entries: Option<u64>,
+    /// Take a snapshot once the log file grows beyond this many bytes
+    pub snapshot_log_bytes: Option<u64>,
+    /// Size of the chunks a snapshot is streamed to a follower in
+    pub snapshot_chunk_bytes: usize,
+    /// Number of snapshots kept in `<data_dir>/snapshots`, older ones are deleted
+    pub snapshot_retain: usize,
+    /// How appends to the log are made durable
+    pub durability: Durability,
+    /// Address the RPC server binds, resolved from the node's own endpoint when `None`
+    pub listen_addr: Option<SocketAddr>,
+    /// Mutual TLS for peer traffic, plaintext when `None`
+    pub tls: Option<TlsConfig>,
+    /// Cluster the node must belong to, picked by the first leader and replicated in its no-op entry when `None`
+    pub cluster_id: Option<Uuid>,
+    /// Whether a leader hands leadership to the most up to date follower when shut down
+    pub transfer_leadership_on_shutdown: bool,
 }
 
 impl Config {
@@ -14,6 +77,11 @@ impl Config {
         ConfigBuilder::default()
     }
 
+    /// Upper bound for a single peer RPC, a slow peer must not stall the timer
+    pub fn rpc_timeout(&self) -> Duration {
+        Duration::from_millis(self.rpc_timeout_millis.unwrap_or(self.heartbeat_interval_millis))
+    }
+
     /// Deprecated: use Config::builder() instead
     #[deprecated(since = "0.2.0", note = "Use Config::builder() instead")]
     pub fn new(endpoints: Vec<Endpoint>) -> Self {
@@ -44,16 +112,43 @@ impl Default for Config {
             origin_endpoint: vec![],
             data_dir: "/tmp/ruft".into(),
             heartbeat_interval_millis: 3000,
+            rpc_timeout_millis: None,
+            reconnect_backoff: Backoff::default(),
+            trace_file: None,
+            snapshot_entries: None,
+            snapshot_log_bytes: None,
+            snapshot_chunk_bytes: DEFAULT_SNAPSHOT_CHUNK_BYTES,
+            snapshot_retain: DEFAULT_SNAPSHOT_RETAIN,
+            durability: Durability::EveryWrite,
+            listen_addr: None,
+            tls: None,
+            cluster_id: None,
+            transfer_leadership_on_shutdown: true,
         }
     }
 }
 
+const DEFAULT_SNAPSHOT_CHUNK_BYTES: usize = 1 << 20;
+const DEFAULT_SNAPSHOT_RETAIN: usize = 2;
+
 /// Builder for Config with full chain-able API
 #[derive(Default)]
 pub struct ConfigBuilder {
     endpoints: Vec<Endpoint>,
     data_dir: Option<String>,
     heartbeat_interval: Option<u64>,
+    rpc_timeout: Option<u64>,
+    reconnect_backoff: Option<Backoff>,
+    trace_file: Option<String>,
+    snapshot_entries: Option<u64>,
+    snapshot_log_bytes: Option<u64>,
+    snapshot_chunk_bytes: Option<usize>,
+    snapshot_retain: Option<usize>,
+    durability: Option<Durability>,
+    listen_addr: Option<SocketAddr>,
+    tls: Option<TlsConfig>,
+    cluster_id: Option<Uuid>,
+    transfer_leadership_on_shutdown: Option<bool>,
 }
 
 impl ConfigBuilder {
@@ -85,12 +180,105 @@ impl ConfigBuilder {
         self
     }
 
+    /// Set the deadline of RPCs to peers in milliseconds, the heartbeat interval by default
+    pub fn rpc_timeout(mut self, millis: u64) -> Self {
+        self.rpc_timeout = Some(millis);
+        self
+    }
+
+    /// Set how long to wait between attempts to reconnect to an unreachable peer, 50ms doubling up to 5s by default
+    pub fn reconnect_backoff(mut self, backoff: Backoff) -> Self {
+        self.reconnect_backoff = Some(backoff);
+        self
+    }
+
+    /// Trace every state transition to an NDJSON file, for checking against the TLA+ spec
+    pub fn trace_file(mut self, path: impl Into<String>) -> Self {
+        self.trace_file = Some(path.into());
+        self
+    }
+
+    /// Snapshot automatically after this many entries, disabled by default
+    pub fn snapshot_entries(mut self, entries: u64) -> Self {
+        self.snapshot_entries = Some(entries);
+        self
+    }
+
+    /// Snapshot automatically once the log file exceeds this many bytes, disabled by default
+    pub fn snapshot_log_bytes(mut self, bytes: u64) -> Self {
+        self.snapshot_log_bytes = Some(bytes);
+        self
+    }
+
+    /// Stream snapshots to followers in chunks of this many bytes, 1 MiB by default
+    pub fn snapshot_chunk_bytes(mut self, bytes: usize) -> Self {
+        self.snapshot_chunk_bytes = Some(bytes);
+        self
+    }
+
+    /// Keep this many snapshots on disk, 2 by default; the newest valid one is loaded at startup
+    pub fn snapshot_retain(mut self, count: usize) -> Self {
+        self.snapshot_retain = Some(count);
+        self
+    }
+
+    /// Set how log appends are made durable, an fsync for every write by default
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = Some(durability);
+        self
+    }
+
+    /// Bind the RPC server to `addr` instead of the node's endpoint
+    ///
+    /// Any address works, e.g. `0.0.0.0` or `[::]` to listen on every interface, or
+    /// port 0 to let the OS pick one, reported by [`crate::Ruft::local_addr`].
+    pub fn listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.listen_addr = Some(addr);
+        self
+    }
+
+    /// Encrypt and authenticate peer traffic with mutual TLS
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Pin the cluster the node belongs to
+    ///
+    /// Without it the first leader of a new cluster picks a random id and
+    /// replicates it through the log, so a cluster re-created at the same
+    /// addresses gets a new id. A node started without members takes the id of
+    /// the first leader it hears from. Peers of another cluster are refused, and
+    /// so is a data directory written for another cluster.
+    pub fn cluster_id(mut self, cluster_id: Uuid) -> Self {
+        self.cluster_id = Some(cluster_id);
+        self
+    }
+
+    /// Let a leader hand over leadership before it shuts down, on by default
+    pub fn transfer_leadership_on_shutdown(mut self, transfer: bool) -> Self {
+        self.transfer_leadership_on_shutdown = Some(transfer);
+        self
+    }
+
     /// Build the Config
     pub fn build(self) -> Config {
         Config {
             origin_endpoint: self.endpoints,
             data_dir: self.data_dir.unwrap_or_else(|| "/tmp/ruft".into()),
             heartbeat_interval_millis: self.heartbeat_interval.unwrap_or(3000),
+            rpc_timeout_millis: self.rpc_timeout,
+            reconnect_backoff: self.reconnect_backoff.unwrap_or_default(),
+            trace_file: self.trace_file,
+            snapshot_entries: self.snapshot_entries,
+            snapshot_log_bytes: self.snapshot_log_bytes,
+            snapshot_chunk_bytes: self.snapshot_chunk_bytes.unwrap_or(DEFAULT_SNAPSHOT_CHUNK_BYTES),
+            snapshot_retain: self.snapshot_retain.unwrap_or(DEFAULT_SNAPSHOT_RETAIN),
+            durability: self.durability.unwrap_or(Durability::EveryWrite),
+            listen_addr: self.listen_addr,
+            tls: self.tls,
+            cluster_id: self.cluster_id,
+            transfer_leadership_on_shutdown: self.transfer_leadership_on_shutdown.unwrap_or(true),
         }
     }
 }
@@ -98,11 +286,12 @@ impl ConfigBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rpc::NodeId;
 
     #[test]
     fn test_config_builder() {
-        let ep1 = Endpoint::new(1, "localhost".into(), 5001);
-        let ep2 = Endpoint::new(2, "localhost".into(), 5002);
+        let ep1 = Endpoint::new(NodeId::new(1), "localhost".into(), 5001);
+        let ep2 = Endpoint::new(NodeId::new(2), "localhost".into(), 5002);
 
         let config = Config::builder().add_member(ep1).add_member(ep2).data_dir("/var/lib/raft").heartbeat_interval(1000).build();
 
@@ -1,115 +1,693 @@
-use crate::rpc::Endpoint;
-use crate::storage::MmapStorage;
+use crate::rpc::{Endpoint, LegacyEndpoint, NodeId};
+use crate::storage::{DirLock, Format, HEADER_SIZE, MmapStorage, Pod, crc32c_append, write_atomic};
 use crate::{Config, Result, RuftError};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::io;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Meta {
-    initialized: bool,
+/// Initial size of the meta file
+const META_FILE_SIZE: usize = 4096;
+/// Two copies of the hot fields follow the format header
+const HOT_START: usize = HEADER_SIZE;
+/// The two cold slots share the rest of the file and grow with it
+const COLD_START: usize = 128;
+/// Sequence number (u64), payload length (u32) and CRC32C (u32) in little endian
+const SLOT_HEADER_SIZE: usize = 16;
+
+/// Version 1 put a header in front of the slots, version 2 moved term, vote and commit index out of them,
+/// version 3 added the cluster id to the cold fields, version 4 widened node ids to 64 bits
+pub(crate) const META_FORMAT: Format = Format {
+    name: "meta",
+    magic: *b"RMET",
+    migrations: &[migrate_headerless, split_hot_fields, add_cluster_id, widen_node_ids],
+};
+
+/// Fields written on the election and commit path, updated in place without serialization
+///
+/// Stored in native byte order. `seq` orders the two copies, `flags` tells
+/// whether `voted_for` holds a vote.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct HotMeta {
+    seq: u64,
     term: u64,
-    voted_for: Option<u64>,
-    log_id: u64,
+    /// Raw [`NodeId`] of the vote
+    voted_for: u64,
     committed_index: u64,
+    flags: u32,
+    checksum: u32,
+}
+
+// Only integers in repr(C), the two u32 fill the last 8 bytes so there is no padding
+unsafe impl Pod for HotMeta {}
+
+const VOTED: u32 = 1;
+
+impl HotMeta {
+    fn checksum(&self) -> u32 {
+        let crc = [self.seq, self.term, self.voted_for, self.committed_index]
+            .iter()
+            .fold(0, |crc, field| crc32c_append(crc, &field.to_le_bytes()));
+        crc32c_append(crc, &self.flags.to_le_bytes())
+    }
+
+    fn is_valid(&self) -> bool {
+        self.seq != 0 && self.checksum == self.checksum()
+    }
+
+    fn voted_for(&self) -> Option<NodeId> {
+        (self.flags & VOTED != 0).then_some(NodeId::from(self.voted_for))
+    }
+
+    fn set_voted_for(&mut self, candidate_id: Option<NodeId>) {
+        self.voted_for = candidate_id.map_or(0, u64::from);
+        self.flags = if candidate_id.is_some() { self.flags | VOTED } else { self.flags & !VOTED };
+    }
+
+    /// The bytes as they lie in the mapped file
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(size_of::<HotMeta>());
+        for field in [self.seq, self.term, self.voted_for, self.committed_index] {
+            bytes.extend_from_slice(&field.to_ne_bytes());
+        }
+        bytes.extend_from_slice(&self.flags.to_ne_bytes());
+        bytes.extend_from_slice(&self.checksum.to_ne_bytes());
+        bytes
+    }
+}
+
+/// Fields that rarely change, serialized into the cold slots
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct ColdMeta {
+    log_id: u64,
     members: Vec<Endpoint>,
+    /// Cluster the node belongs to, `None` until it is bootstrapped or learns it from a leader
+    cluster_id: Option<Uuid>,
 }
 
+/// Term, vote and membership that must survive a restart
+///
+/// Term, vote and commit index are a fixed [`HotMeta`] written in place, so
+/// the election path touches a few bytes only. Members and other cold fields
+/// are serialized into slots behind them. Hot copies and cold slots both come
+/// in pairs written alternately, each stamped with an increasing sequence
+/// number and a checksum. A torn write only damages the copy being written,
+/// so loading falls back to the other one and never to a reset term.
 pub struct PersistentMeta {
-    data: Meta,
+    hot: HotMeta,
+    cold: ColdMeta,
+    /// Sequence number of the cold slot written last
+    cold_seq: u64,
     storage: MmapStorage,
+    /// Keeps other processes from mapping the same file
+    _lock: DirLock,
+}
+
+/// File offset of the hot copy written with `seq`
+fn hot_offset(seq: u64) -> usize {
+    HOT_START + (seq % 2) as usize * size_of::<HotMeta>()
+}
+
+/// Checksum over the slot header and the payload
+fn slot_checksum(seq: u64, payload: &[u8]) -> u32 {
+    let crc = crc32c_append(0, &seq.to_le_bytes());
+    let crc = crc32c_append(crc, &(payload.len() as u32).to_le_bytes());
+    crc32c_append(crc, payload)
+}
+
+/// Size of each cold slot in a meta file of `capacity` bytes
+fn slot_size(capacity: usize) -> usize {
+    (capacity - COLD_START) / 2
+}
+
+/// File offset of the cold slot written with `seq`
+fn slot_offset(seq: u64, slot_size: usize) -> usize {
+    COLD_START + (seq % 2) as usize * slot_size
+}
+
+fn encode_slot(seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut slot = Vec::with_capacity(SLOT_HEADER_SIZE + payload.len());
+    slot.extend_from_slice(&seq.to_le_bytes());
+    slot.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    slot.extend_from_slice(&slot_checksum(seq, payload).to_le_bytes());
+    slot.extend_from_slice(payload);
+    slot
+}
+
+fn serialize<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The sequence number and content of a slot, `None` if the slot is empty, torn or corrupt
+fn decode_slot<T: DeserializeOwned>(slot: &[u8]) -> Option<(u64, T)> {
+    let seq = u64::from_le_bytes(slot.get(0..8)?.try_into().ok()?);
+    let len = u32::from_le_bytes(slot.get(8..12)?.try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(slot.get(12..16)?.try_into().ok()?);
+    let payload = slot.get(SLOT_HEADER_SIZE..SLOT_HEADER_SIZE + len)?;
+    if seq == 0 || slot_checksum(seq, payload) != checksum {
+        return None;
+    }
+    bincode::deserialize(payload).ok().map(|value| (seq, value))
+}
+
+/// Meta of the first releases, a single bincode blob at the start of the file
+#[derive(Deserialize)]
+struct MetaV0 {
+    initialized: bool,
+    term: u64,
+    voted_for: Option<u64>,
+    log_id: u64,
+    committed_index: u64,
+    members: Vec<LegacyEndpoint>,
+}
+
+/// Meta of version 1, the whole of it serialized into each of two slots
+#[derive(Serialize, Deserialize)]
+struct MetaV1 {
+    term: u64,
+    voted_for: Option<u64>,
+    log_id: u64,
+    committed_index: u64,
+    members: Vec<LegacyEndpoint>,
+}
+
+/// Cold fields of version 2
+#[derive(Serialize, Deserialize)]
+struct ColdMetaV2 {
+    log_id: u64,
+    members: Vec<LegacyEndpoint>,
+}
+
+/// Cold fields of version 3
+#[derive(Serialize, Deserialize)]
+struct ColdMetaV3 {
+    log_id: u64,
+    members: Vec<LegacyEndpoint>,
+    cluster_id: Option<Uuid>,
+}
+
+/// Version 0 to 1: turn a file without header into two slots
+///
+/// Headerless files either hold the checksummed slots at 2048 byte boundaries or the
+/// single blob of the first releases.
+fn migrate_headerless(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let meta = match body.chunks(2048).filter_map(decode_slot::<MetaV1>).max_by_key(|(seq, _)| *seq) {
+        Some((_, meta)) => meta,
+        None => match bincode::deserialize::<MetaV0>(&body) {
+            Ok(v0) if v0.initialized => MetaV1 {
+                term: v0.term,
+                voted_for: v0.voted_for,
+                log_id: v0.log_id,
+                committed_index: v0.committed_index,
+                members: v0.members,
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "no valid meta in a file without format header")),
+        },
+    };
+    let size = (META_FILE_SIZE - HEADER_SIZE) / 2;
+    let mut slots = vec![0u8; 2 * size];
+    let slot = encode_slot(1, &serialize(&meta)?);
+    slots[size..size + slot.len()].copy_from_slice(&slot);
+    Ok(slots)
+}
+
+/// Version 1 to 2: move term, vote and commit index out of the slots into the hot copies
+fn split_hot_fields(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let (_, meta) = body
+        .chunks(body.len() / 2)
+        .take(2)
+        .filter_map(decode_slot::<MetaV1>)
+        .max_by_key(|(seq, _)| *seq)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "both slots are corrupt"))?;
+
+    let mut hot = HotMeta {
+        seq: 1,
+        term: meta.term,
+        committed_index: meta.committed_index,
+        ..Default::default()
+    };
+    hot.set_voted_for(meta.voted_for.map(NodeId::from));
+    hot.checksum = hot.checksum();
+    let slot = encode_slot(
+        1,
+        &serialize(&ColdMetaV2 {
+            log_id: meta.log_id,
+            members: meta.members,
+        })?,
+    );
+
+    let mut capacity = META_FILE_SIZE;
+    while slot_size(capacity) < slot.len() {
+        capacity *= 2;
+    }
+    let mut file = vec![0u8; capacity];
+    file[hot_offset(1)..hot_offset(1) + size_of::<HotMeta>()].copy_from_slice(&hot.to_bytes());
+    let offset = slot_offset(1, slot_size(capacity));
+    file[offset..offset + slot.len()].copy_from_slice(&slot);
+    Ok(file.split_off(HEADER_SIZE))
+}
+
+/// Version 2 to 3: add an unknown cluster id to the cold fields
+fn add_cluster_id(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    rewrite_cold(body, |meta: ColdMetaV2| ColdMetaV3 {
+        log_id: meta.log_id,
+        members: meta.members,
+        cluster_id: None,
+    })
+}
+
+/// Version 3 to 4: widen the ids of the members from 8 to 64 bits
+fn widen_node_ids(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    rewrite_cold(body, |meta: ColdMetaV3| ColdMeta {
+        log_id: meta.log_id,
+        members: meta.members.into_iter().map(Endpoint::from).collect(),
+        cluster_id: meta.cluster_id,
+    })
+}
+
+/// Rewrite the newest cold slot of a body of version 2 or later, the hot copies stay as they are
+fn rewrite_cold<T: DeserializeOwned, U: Serialize>(body: Vec<u8>, upgrade: impl FnOnce(T) -> U) -> io::Result<Vec<u8>> {
+    let cold = &body[COLD_START - HEADER_SIZE..];
+    let (_, meta) = cold
+        .chunks(slot_size(body.len() + HEADER_SIZE))
+        .take(2)
+        .filter_map(decode_slot::<T>)
+        .max_by_key(|(seq, _)| *seq)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "both member slots are corrupt"))?;
+    let slot = encode_slot(1, &serialize(&upgrade(meta))?);
+
+    let mut capacity = body.len() + HEADER_SIZE;
+    while slot_size(capacity) < slot.len() {
+        capacity *= 2;
+    }
+    let mut file = vec![0u8; capacity];
+    file[HEADER_SIZE..COLD_START].copy_from_slice(&body[..COLD_START - HEADER_SIZE]);
+    let offset = slot_offset(1, slot_size(capacity));
+    file[offset..offset + slot.len()].copy_from_slice(&slot);
+    Ok(file.split_off(HEADER_SIZE))
+}
+
+/// Upgrade a meta file written in an older format, before it is mapped
+fn migrate(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let bytes = std::fs::read(path)?;
+    if bytes.iter().all(|b| *b == 0) {
+        return Ok(());
+    }
+    let (version, body) = META_FORMAT.read(&bytes)?;
+    if version < META_FORMAT.version() {
+        let mut file = META_FORMAT.encode(&META_FORMAT.upgrade(version, body.to_vec())?);
+        file.resize(file.len().max(META_FILE_SIZE), 0);
+        write_atomic(path, &file)?;
+    }
+    Ok(())
 }
 
 impl PersistentMeta {
     pub fn new(config: &Config) -> Result<Self> {
+        let lock = DirLock::acquire(Path::new(&config.data_dir))?;
         let path = format!("{}/meta.bin", config.data_dir);
         let meta_path = PathBuf::from(&path);
-        let storage = MmapStorage::open_or_create(meta_path, 4096).map_err(|e| RuftError::Storage(format!("Failed to open meta file {}: {}", path, e)))?;
-
-        // Try to load existing data, or initialize new
-        let data = storage.read_serialized::<Meta>().unwrap_or_else(|_| {
-            // Initialize new meta
-            Meta {
-                initialized: true,
-                term: 0,
-                voted_for: None,
+        migrate(&meta_path).map_err(|e| RuftError::Storage(format!("Failed to migrate meta file {}: {}", path, e)))?;
+        let storage = MmapStorage::open_or_create(meta_path, META_FILE_SIZE as u64).map_err(|e| RuftError::Storage(format!("Failed to open meta file {}: {}", path, e)))?;
+        let capacity = storage.capacity();
+        let file = storage.read_at(0, capacity).map_err(|e| RuftError::Storage(format!("Failed to read meta file {}: {}", path, e)))?;
+
+        // Nothing was ever written, a fresh file is zero-filled
+        if file.iter().all(|b| *b == 0) {
+            return Self::create(storage, lock, config).map_err(|e| RuftError::Storage(format!("Failed to initialize meta file {}: {}", path, e)));
+        }
+
+        let hot = [0, 1]
+            .into_iter()
+            .filter_map(|seq| storage.with_ref_at(hot_offset(seq), |hot: &HotMeta| *hot).ok())
+            .filter(HotMeta::is_valid)
+            .max_by_key(|hot| hot.seq);
+        let cold = file[COLD_START..].chunks(slot_size(capacity)).take(2).filter_map(decode_slot::<ColdMeta>).max_by_key(|(seq, _)| *seq);
+        // Starting over with term 0 could vote twice in a term, refuse instead
+        match (hot, cold) {
+            (Some(hot), Some((cold_seq, cold))) => Ok(PersistentMeta {
+                hot,
+                cold,
+                cold_seq,
+                storage,
+                _lock: lock,
+            }),
+            (None, _) => Err(RuftError::Storage(format!("Both copies of term and vote in meta file {} are corrupt", path))),
+            (_, None) => Err(RuftError::Storage(format!("Both member slots of meta file {} are corrupt", path))),
+        }
+    }
+
+    fn create(mut storage: MmapStorage, lock: DirLock, config: &Config) -> io::Result<Self> {
+        storage.write_at(0, &META_FORMAT.header())?;
+        let mut meta = PersistentMeta {
+            hot: HotMeta::default(),
+            cold: ColdMeta {
                 log_id: 0,
-                committed_index: 0,
                 members: config.origin_endpoint.clone(),
-            }
-        });
+                cluster_id: None,
+            },
+            cold_seq: 0,
+            storage,
+            _lock: lock,
+        };
+        meta.write_hot()?;
+        meta.write_cold()?;
+        Ok(meta)
+    }
 
-        let mut holder = PersistentMeta { data, storage };
+    fn persist_hot(&mut self) -> Result<()> {
+        self.write_hot().map_err(|e| RuftError::Storage(format!("Failed to persist term and vote: {}", e)))
+    }
 
-        // Persist if newly initialized
-        if !holder.data.initialized {
-            holder.data.initialized = true;
-            holder.persist()?;
-        }
+    fn persist_cold(&mut self) -> Result<()> {
+        self.write_cold().map_err(|e| RuftError::Storage(format!("Failed to persist meta: {}", e)))
+    }
 
-        Ok(holder)
+    /// Overwrite the hot copy not holding the latest values
+    fn write_hot(&mut self) -> io::Result<()> {
+        let mut hot = self.hot;
+        hot.seq += 1;
+        hot.checksum = hot.checksum();
+        self.storage.with_mut_at(hot_offset(hot.seq), |slot: &mut HotMeta| *slot = hot)?;
+        self.hot = hot;
+        Ok(())
     }
 
-    fn persist(&mut self) -> Result<()> {
-        self.storage.write_serialized(&self.data).map_err(|e| RuftError::Storage(format!("Failed to persist meta: {}", e)))
+    /// Write the cold fields into the slot not holding the latest copy
+    fn write_cold(&mut self) -> io::Result<()> {
+        let payload = serialize(&self.cold)?;
+        if SLOT_HEADER_SIZE + payload.len() > slot_size(self.storage.capacity()) {
+            self.grow(SLOT_HEADER_SIZE + payload.len())?;
+        }
+        let seq = self.cold_seq + 1;
+        self.storage.write_at(slot_offset(seq, slot_size(self.storage.capacity())), &encode_slot(seq, &payload))?;
+        self.cold_seq = seq;
+        Ok(())
     }
 
+    /// Enlarge the file until a cold slot holds `len` bytes
+    ///
+    /// Growing moves the second slot while the first one stays in place, so the
+    /// newest copy is moved into the first slot beforehand. The moved second slot
+    /// starts past the old end of the file and is empty.
+    fn grow(&mut self, len: usize) -> io::Result<()> {
+        let size = slot_size(self.storage.capacity());
+        if self.cold_seq % 2 == 1 {
+            let newest = self.storage.read_at(slot_offset(self.cold_seq, size), size)?;
+            let payload_len = u32::from_le_bytes(newest[8..12].try_into().unwrap()) as usize;
+            let payload = newest[SLOT_HEADER_SIZE..SLOT_HEADER_SIZE + payload_len].to_vec();
+            let seq = self.cold_seq + 1;
+            self.storage.write_at(slot_offset(seq, size), &encode_slot(seq, &payload))?;
+            self.cold_seq = seq;
+        }
+        let mut capacity = self.storage.capacity();
+        while slot_size(capacity) < len {
+            capacity *= 2;
+        }
+        self.storage.resize(capacity)
+    }
+
+    #[allow(dead_code)]
     pub fn next_log_id(&mut self) -> Result<u64> {
-        self.data.log_id += 1;
-        self.persist()?;
-        Ok(self.data.log_id)
+        self.cold.log_id += 1;
+        self.persist_cold()?;
+        Ok(self.cold.log_id)
     }
 
     pub fn next_term(&mut self) -> Result<u64> {
-        self.data.term += 1;
-        self.data.voted_for = None; // Clear vote when entering new term
-        self.persist()?;
-        Ok(self.data.term)
+        self.hot.term += 1;
+        self.hot.set_voted_for(None); // Clear vote when entering new term
+        self.persist_hot()?;
+        Ok(self.hot.term)
     }
 
     pub fn set_term(&mut self, term: u64) -> Result<()> {
-        if term > self.data.term {
-            self.data.term = term;
-            self.data.voted_for = None;
-            self.persist()?;
+        if term > self.hot.term {
+            self.hot.term = term;
+            self.hot.set_voted_for(None);
+            self.persist_hot()?;
         }
         Ok(())
     }
 
     pub fn term(&self) -> u64 {
-        self.data.term
+        self.hot.term
     }
 
+    #[allow(dead_code)]
     pub fn log_id(&self) -> u64 {
-        self.data.log_id
+        self.cold.log_id
     }
 
     pub fn committed_index(&self) -> u64 {
-        self.data.committed_index
+        self.hot.committed_index
     }
 
     pub fn set_committed_index(&mut self, index: u64) -> Result<()> {
-        if index > self.data.committed_index {
-            self.data.committed_index = index;
-            self.persist()?;
+        if index > self.hot.committed_index {
+            self.hot.committed_index = index;
+            self.persist_hot()?;
         }
         Ok(())
     }
 
+    /// Lower the commit index, when the log lost committed entries it had not synced
+    pub fn reset_committed_index(&mut self, index: u64) -> Result<()> {
+        self.hot.committed_index = index;
+        self.persist_hot()
+    }
+
+    /// Write everything mapped back to the file
+    pub fn flush(&self) -> Result<()> {
+        self.storage.flush().map_err(|e| RuftError::Storage(format!("Failed to flush meta: {}", e)))
+    }
+
     pub fn members(&self) -> Vec<Endpoint> {
-        self.data.members.clone()
+        self.cold.members.clone()
     }
 
     pub fn update_members(&mut self, members: Vec<Endpoint>) -> Result<()> {
-        self.data.members = members;
-        self.persist()
+        self.cold.members = members;
+        self.persist_cold()
+    }
+
+    pub fn cluster_id(&self) -> Option<Uuid> {
+        self.cold.cluster_id
+    }
+
+    pub fn set_cluster_id(&mut self, cluster_id: Uuid) -> Result<()> {
+        self.cold.cluster_id = Some(cluster_id);
+        self.persist_cold()
+    }
+
+    pub fn voted_for(&self) -> Option<NodeId> {
+        self.hot.voted_for()
+    }
+
+    pub fn set_voted_for(&mut self, candidate_id: NodeId) -> Result<()> {
+        self.hot.set_voted_for(Some(candidate_id));
+        self.persist_hot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str) -> Config {
+        let dir = format!("/tmp/raft/meta_test/{}", name);
+        let _ = std::fs::remove_dir_all(&dir);
+        Config::builder().data_dir(dir).build()
+    }
+
+    fn meta_path(config: &Config) -> String {
+        format!("{}/meta.bin", config.data_dir)
+    }
+
+    fn corrupt(config: &Config, offset: usize) {
+        let path = meta_path(config);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let config = config("reload");
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        meta.set_term(3).unwrap();
+        meta.set_voted_for(NodeId::new(2)).unwrap();
+        drop(meta);
+
+        let meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.term(), meta.voted_for()), (3, Some(NodeId::new(2))));
+    }
+
+    #[test]
+    fn test_torn_write_falls_back_to_other_slot() {
+        let config = config("torn");
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        meta.set_term(3).unwrap();
+        meta.set_voted_for(NodeId::new(2)).unwrap();
+        let seq = meta.hot.seq;
+        drop(meta);
+
+        // The last write went to the copy of its sequence number
+        corrupt(&config, hot_offset(seq) + 8);
+        let meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.term(), meta.voted_for(), meta.hot.seq), (3, None, seq - 1));
+    }
+
+    #[test]
+    fn test_grows_for_long_member_lists() {
+        let config = config("grow");
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        meta.set_term(2).unwrap();
+        meta.set_voted_for(NodeId::new(3)).unwrap();
+        assert_eq!(meta.cold_seq % 2, 1);
+
+        // The newest copy sits in the second slot when the file has to grow
+        let members: Vec<Endpoint> = (1..=60)
+            .map(|id| Endpoint::new(NodeId::new(id), format!("node-{}.a-rather-long-availability-zone.example.com", id), 5000))
+            .collect();
+        meta.update_members(members.clone()).unwrap();
+        assert!(meta.storage.capacity() > META_FILE_SIZE);
+        drop(meta);
+
+        let meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.term(), meta.voted_for(), meta.members()), (2, Some(NodeId::new(3)), members));
+    }
+
+    #[test]
+    fn test_both_slots_corrupt_is_an_error() {
+        let config = config("corrupt");
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        meta.set_term(3).unwrap();
+        drop(meta);
+
+        corrupt(&config, hot_offset(0) + 8);
+        corrupt(&config, hot_offset(1) + 8);
+        assert!(matches!(PersistentMeta::new(&config), Err(RuftError::Storage(_))));
+
+        let config = super::tests::config("corrupt_cold");
+        drop(PersistentMeta::new(&config).unwrap());
+        corrupt(&config, slot_offset(0, slot_size(META_FILE_SIZE)) + SLOT_HEADER_SIZE);
+        corrupt(&config, slot_offset(1, slot_size(META_FILE_SIZE)) + SLOT_HEADER_SIZE);
+        assert!(matches!(PersistentMeta::new(&config), Err(RuftError::Storage(_))));
+    }
+
+    #[test]
+    fn test_hot_fields_leave_members_untouched() {
+        let config = config("hot");
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        let cold = || std::fs::read(meta_path(&config)).unwrap()[COLD_START..].to_vec();
+        let before = cold();
+
+        meta.next_term().unwrap();
+        meta.set_voted_for(NodeId::new(1)).unwrap();
+        meta.set_committed_index(7).unwrap();
+        assert_eq!(cold(), before);
+        assert_eq!(meta.cold_seq, 1);
+        drop(meta);
+
+        let meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.term(), meta.voted_for(), meta.committed_index()), (1, Some(NodeId::new(1)), 7));
+    }
+
+    #[test]
+    fn test_migrate_headerless_meta() {
+        #[derive(Serialize)]
+        struct Legacy {
+            initialized: bool,
+            term: u64,
+            voted_for: Option<u64>,
+            log_id: u64,
+            committed_index: u64,
+            members: Vec<LegacyEndpoint>,
+        }
+
+        let config = config("migrate");
+        std::fs::create_dir_all(&config.data_dir).unwrap();
+        let members = vec![Endpoint::new(NodeId::new(200), "localhost".into(), 5001)];
+        let legacy = Legacy {
+            initialized: true,
+            term: 4,
+            voted_for: Some(1),
+            log_id: 0,
+            committed_index: 9,
+            members: legacy_members(),
+        };
+        let mut bytes = bincode::serialize(&legacy).unwrap();
+        bytes.resize(META_FILE_SIZE, 0);
+        std::fs::write(meta_path(&config), bytes).unwrap();
+
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.term(), meta.voted_for(), meta.committed_index(), meta.members()), (4, Some(NodeId::new(1)), 9, members));
+        meta.set_term(5).unwrap();
+        drop(meta);
+
+        let bytes = std::fs::read(meta_path(&config)).unwrap();
+        assert_eq!(META_FORMAT.read(&bytes).unwrap().0, META_FORMAT.version());
+        assert_eq!(PersistentMeta::new(&config).unwrap().term(), 5);
+    }
+
+    #[test]
+    fn test_migrate_meta_without_cluster_id() {
+        let config = config("migrate_v2");
+        std::fs::create_dir_all(&config.data_dir).unwrap();
+        let members = vec![Endpoint::new(NodeId::new(200), "localhost".into(), 5001)];
+        let v1 = MetaV1 {
+            term: 4,
+            voted_for: Some(2),
+            log_id: 0,
+            committed_index: 6,
+            members: legacy_members(),
+        };
+        let size = (META_FILE_SIZE - HEADER_SIZE) / 2;
+        let mut slots = vec![0u8; 2 * size];
+        let slot = encode_slot(1, &serialize(&v1).unwrap());
+        slots[..slot.len()].copy_from_slice(&slot);
+        let mut bytes = META_FORMAT.header().to_vec();
+        bytes[4..6].copy_from_slice(&2u16.to_le_bytes());
+        bytes.extend(split_hot_fields(slots).unwrap());
+        std::fs::write(meta_path(&config), bytes).unwrap();
+
+        let mut meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.term(), meta.voted_for(), meta.committed_index(), meta.members()), (4, Some(NodeId::new(2)), 6, members));
+        assert_eq!(meta.cluster_id(), None);
+        let cluster_id = Uuid::from_u128(7);
+        meta.set_cluster_id(cluster_id).unwrap();
+        // Ids no longer fit the old 8 bits once migrated
+        let members = vec![Endpoint::new(NodeId::new(1 << 40), "localhost".into(), 5001)];
+        meta.update_members(members.clone()).unwrap();
+        drop(meta);
+
+        let meta = PersistentMeta::new(&config).unwrap();
+        assert_eq!((meta.cluster_id(), meta.members()), (Some(cluster_id), members));
     }
 
-    pub fn voted_for(&self) -> Option<u64> {
-        self.data.voted_for
+    /// Members as written before node ids were widened
+    fn legacy_members() -> Vec<LegacyEndpoint> {
+        vec![LegacyEndpoint {
+            id: 200,
+            host: "localhost".into(),
+            port: 5001,
+        }]
     }
 
-    pub fn set_voted_for(&mut self, candidate_id: u64) -> Result<()> {
-        self.data.voted_for = Some(candidate_id);
-        self.persist()
+    #[test]
+    fn test_newer_format_is_refused() {
+        let config = config("newer");
+        drop(PersistentMeta::new(&config).unwrap());
+        let mut bytes = std::fs::read(meta_path(&config)).unwrap();
+        bytes[4] += 1;
+        std::fs::write(meta_path(&config), bytes).unwrap();
+        assert!(matches!(PersistentMeta::new(&config), Err(RuftError::Storage(_))));
     }
 }
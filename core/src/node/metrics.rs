@@ -0,0 +1,28 @@
+use crate::node::Durability;
+use crate::rpc::{NodeId, PeerHealth};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Point-in-time view of a node, see [`crate::Ruft::metrics`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metrics {
+    pub id: NodeId,
+    /// Cluster the node belongs to, `None` until it hears from a leader
+    pub cluster_id: Option<Uuid>,
+    /// Follower, Candidate, Leader or Learner
+    pub state: String,
+    pub term: u64,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub last_log_index: u64,
+    /// Last index covered by the newest snapshot
+    pub snapshot_index: u64,
+    /// Policy the log is made durable with
+    pub durability: Durability,
+    /// Last log index known to be on disk
+    pub synced_index: u64,
+    /// Number of log fsyncs since the node started
+    pub log_syncs: u64,
+    /// Health of the connection to every peer, by peer id
+    pub peers: BTreeMap<NodeId, PeerHealth>,
+}
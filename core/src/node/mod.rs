@@ -1,7 +1,10 @@
 mod config;
 mod meta;
+mod metrics;
+#[allow(clippy::module_inception)]
 pub(crate) mod node; // fixme: pub for rpc
 mod ruft;
 
-pub use crate::node::config::{Config, ConfigBuilder};
-pub use crate::node::ruft::Ruft;
+pub use crate::node::config::{Config, ConfigBuilder, Durability, TlsConfig};
+pub use crate::node::metrics::Metrics;
+pub use crate::node::ruft::{Ruft, RuftBuilder};
@@ -1,37 +1,520 @@
+use crate::clock::{self, Clock};
 use crate::node::meta::PersistentMeta;
+use crate::random::Random;
 use crate::repeat_timer::{RepeatTimer, RepeatTimerHandle};
-use crate::role::{Candidate, Follower, Leader, Learner, RaftState};
-use crate::rpc::client::{init_remote_client, RemoteClient};
-use crate::rpc::command::{CmdReq, CmdResp};
-use crate::rpc::server::run_server;
-use crate::rpc::Endpoint;
-use crate::{Config, Result, RuftError};
+use crate::role::{Candidate, Follower, Leader, Learner, RaftState, SnapshotTransfer};
+use crate::rpc::command::{CmdReq, CmdResp, ErrorCode};
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, Endpoint, EntryType, InstallSnapshotChunk, InstallSnapshotResponse, LogEntry, Member, NodeId, PreVoteRequest, PreVoteResponse, RaftRpcClient,
+    RaftRpcHandler, RequestVoteRequest, RequestVoteResponse, TimeoutNowRequest, TimeoutNowResponse, Transport,
+};
+use crate::storage::{RaftLog, SnapshotStore, SnapshotWriter, crc32c};
+use crate::trace::{TraceEvent, TraceMessage, Tracer};
+use crate::{Config, Durability, Metrics, Result, RuftError, Sm, SmView};
+use bytes::Bytes;
 use dashmap::DashMap;
-use rand::Rng;
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
-use tracing::{error, info};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Upper bound for the number of entries shipped in one AppendEntries request
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+/// Address of a peer and the client connecting to it
+type Peer = (Endpoint, Arc<dyn RaftRpcClient>);
+
+/// A command proposed through this node, waiting for its entry to be applied
+struct Proposal {
+    term: u64,
+    applied: bool,
+    result: Option<Bytes>,
+}
 
 /// Common data shared across all states
 struct CommonData {
     endpoint: Endpoint,
     meta: PersistentMeta,
+    log: RaftLog,
     config: Config,
-    remote_clients: DashMap<Endpoint, RemoteClient>,
+    transport: Arc<dyn Transport>,
+    random: Arc<dyn Random>,
+    /// Client of every peer by id, along with the address it connects to
+    remote_clients: DashMap<NodeId, Peer>,
     timer: Option<RepeatTimerHandle>,
+    /// Publishes the commit index to proposals waiting in `Node::submit`
+    commit_tx: watch::Sender<u64>,
+    /// Publishes the leader as known by this node to `Node::wait_for_leader`
+    leader_tx: watch::Sender<Option<Endpoint>>,
+    tracer: Option<Tracer>,
+    sm: Box<dyn Sm>,
+    last_applied: u64,
+    /// Proposals of `Node::submit` by log index
+    proposals: HashMap<u64, Proposal>,
+    snapshots: SnapshotStore,
+    /// Snapshot being received from the leader
+    incoming_snapshot: Option<SnapshotWriter>,
+    /// Number of log fsyncs, and whether a group commit is waiting for its window to close
+    log_syncs: u64,
+    sync_scheduled: bool,
+    /// Set when the leader hands leadership to us, the next timeout starts an election right away
+    timeout_now: bool,
+}
+
+impl CommonData {
+    /// Number of votes needed to win an election
+    fn quorum(&self) -> usize {
+        self.meta.members().len() / 2 + 1
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.log.last_index()
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.log.last_term()
+    }
+
+    fn commit_index(&self) -> u64 {
+        self.meta.committed_index()
+    }
+
+    /// Last index that counts as stored on this node when committing
+    fn durable_index(&self) -> u64 {
+        match self.config.durability {
+            Durability::Interval(_) => self.last_log_index(),
+            _ => self.log.synced_index(),
+        }
+    }
+
+    fn set_commit_index(&mut self, index: u64) -> Result<()> {
+        if index > self.commit_index() {
+            self.meta.set_committed_index(index)?;
+            self.apply_committed()?;
+            self.commit_tx.send_replace(index);
+        }
+        Ok(())
+    }
+
+    /// Our cluster id as sent in peer RPCs, empty while unknown
+    fn cluster_id(&self) -> Vec<u8> {
+        self.meta.cluster_id().map(|id| id.as_bytes().to_vec()).unwrap_or_default()
+    }
+
+    /// Refuse an RPC of `from` if it names another cluster than ours
+    ///
+    /// An empty id comes from a peer that does not know its cluster yet. Once we
+    /// know ours, such a peer may not raise our `term`: it could be a fresh node
+    /// pointed at the wrong hosts. With `adopt`, a node that does not know its
+    /// own cluster joins the sender's.
+    fn check_cluster(&mut self, from: NodeId, cluster_id: &[u8], term: u64, adopt: bool) -> Result<()> {
+        if cluster_id.is_empty() {
+            return match self.meta.cluster_id() {
+                Some(ours) if term > self.meta.term() => Err(RuftError::ClusterMismatch(format!("Node {} sent term {} without a cluster id, we belong to {}", from, term, ours))),
+                _ => Ok(()),
+            };
+        }
+        let theirs = Uuid::from_slice(cluster_id).map_err(|_| RuftError::ClusterMismatch(format!("Node {} sent a malformed cluster id", from)))?;
+        match self.meta.cluster_id() {
+            Some(ours) if ours != theirs => Err(RuftError::ClusterMismatch(format!("Node {} belongs to cluster {}, we belong to {}", from, theirs, ours))),
+            None if adopt => {
+                info!("Joining cluster {} of node {}", theirs, from);
+                self.meta.set_cluster_id(theirs)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set_leader(&self, leader: Option<Endpoint>) {
+        self.leader_tx.send_if_modified(|known| {
+            let changed = *known != leader;
+            *known = leader;
+            changed
+        });
+    }
+
+    /// Feed newly committed commands to the state machine and address changes to the membership
+    fn apply_committed(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index() {
+            let index = self.last_applied + 1;
+            let entry = self
+                .log
+                .entry(index)
+                .ok_or_else(|| RuftError::InvalidState(format!("Committed entry {} is missing from the log", index)))?;
+            let term = entry.term;
+            let result = match EntryType::try_from(entry.entry_type) {
+                Ok(EntryType::Command) => Some(self.sm.apply(index, &entry.command)),
+                Ok(EntryType::Address) => {
+                    let command = entry.command.clone();
+                    self.apply_address(&command)?;
+                    Some(None)
+                }
+                Ok(EntryType::Noop) if !entry.command.is_empty() => {
+                    let command = entry.command.clone();
+                    self.apply_cluster_id(&command)?;
+                    None
+                }
+                _ => None,
+            };
+            // The index may hold an entry of a newer leader that replaced our proposal
+            if let Some(result) = result
+                && let Some(proposal) = self.proposals.get_mut(&index)
+                && proposal.term == term
+            {
+                proposal.applied = true;
+                proposal.result = result;
+            }
+            self.last_applied = index;
+        }
+        Ok(())
+    }
+
+    /// Join the cluster named by a committed no-op entry, the first one committed names the cluster for good
+    fn apply_cluster_id(&mut self, command: &[u8]) -> Result<()> {
+        let Ok(cluster_id) = Uuid::from_slice(command) else {
+            warn!("Skipping malformed cluster id in a no-op entry");
+            return Ok(());
+        };
+        if self.meta.cluster_id().is_some() {
+            return Ok(());
+        }
+        info!("Node {} belongs to cluster {}", self.endpoint.id(), cluster_id);
+        self.meta.set_cluster_id(cluster_id)
+    }
+
+    /// Move the member named in a committed address change, peer clients follow on the next tick
+    fn apply_address(&mut self, command: &[u8]) -> Result<()> {
+        let moved = Member::decode(command)
+            .map_err(|e| RuftError::Serialization(e.to_string()))
+            .and_then(|member| Endpoint::try_from(&member));
+        let moved = match moved {
+            Ok(moved) => moved,
+            Err(e) => {
+                warn!("Skipping malformed address change: {}", e);
+                return Ok(());
+            }
+        };
+        self.move_member(moved)
+    }
+
+    /// Give the member with the id of `moved` its address, members are identified by id alone
+    fn move_member(&mut self, moved: Endpoint) -> Result<()> {
+        let mut members = self.meta.members();
+        let Some(member) = members.iter_mut().find(|m| m.id() == moved.id()) else {
+            warn!("Ignoring address change of {}, not a member", moved);
+            return Ok(());
+        };
+        if *member == moved {
+            return Ok(());
+        }
+        info!("Member {} moved to {}:{}", member, moved.host(), moved.port());
+        *member = moved;
+        self.meta.update_members(members)
+    }
+
+    /// Whether the applied entries or the log size reached the configured snapshot limits
+    fn snapshot_due(&self) -> bool {
+        let entries = self.last_applied - self.log.snapshot_index();
+        let too_many = self.config.snapshot_entries.is_some_and(|max| entries >= max);
+        let too_big = self.config.snapshot_log_bytes.is_some_and(|max| self.log.size() >= max);
+        entries > 0 && (too_many || too_big)
+    }
+
+    /// Freeze the state machine at the last applied entry, `None` if the log is already compacted up to it
+    fn freeze(&self) -> Result<Option<Frozen>> {
+        let index = self.last_applied;
+        if index == self.log.snapshot_index() {
+            return Ok(None);
+        }
+        let term = self
+            .log
+            .term_at(index)
+            .ok_or_else(|| RuftError::InvalidState(format!("Applied entry {} is missing from the log", index)))?;
+        Ok(Some(Frozen {
+            index,
+            term,
+            members: self.meta.members(),
+            view: self.sm.view(),
+        }))
+    }
+
+    /// Drop the log up to a snapshot just written, unless a snapshot from the leader got past it meanwhile
+    fn compact_to(&mut self, index: u64, term: u64) -> Result<()> {
+        if index <= self.log.snapshot_index() || self.log.term_at(index) != Some(term) {
+            return Ok(());
+        }
+        self.log
+            .compact(index, term)
+            .map_err(|e| RuftError::Storage(format!("Failed to compact log {}: {}", self.log.path().display(), e)))?;
+        info!("Node {} took a snapshot at index {} term {}", self.endpoint.id(), index, term);
+        Ok(())
+    }
+
+    /// Store the next chunk of a snapshot from the leader of `term`, installing the snapshot after its last chunk
+    fn receive_snapshot_chunk(&mut self, term: u64, chunk: &InstallSnapshotChunk) -> Result<InstallSnapshotResponse> {
+        let (index, snapshot_term) = (chunk.last_included_index, chunk.last_included_term);
+        let respond = |next_offset, installed| InstallSnapshotResponse { term, next_offset, installed };
+        // Our state machine is already past the snapshot
+        if index <= self.last_applied {
+            return Ok(respond(chunk.offset + chunk.data.len() as u64, true));
+        }
+
+        let writer = match &mut self.incoming_snapshot {
+            Some(writer) if (writer.index(), writer.term()) == (index, snapshot_term) => writer,
+            slot => {
+                let writer = self
+                    .snapshots
+                    .writer(index, snapshot_term)
+                    .map_err(|e| RuftError::Storage(format!("Failed to start receiving snapshot at index {}: {}", index, e)))?;
+                slot.insert(writer)
+            }
+        };
+        // Point the leader to the end of what we have, after a broken stream it resumes there
+        if chunk.offset != writer.len() {
+            return Ok(respond(writer.len(), false));
+        }
+        if crc32c(&chunk.data) != chunk.checksum {
+            warn!("Snapshot chunk at offset {} from node {} is corrupt", chunk.offset, chunk.leader_id);
+            return Ok(respond(writer.len(), false));
+        }
+        writer
+            .write_all(&chunk.data)
+            .map_err(|e| RuftError::Storage(format!("Failed to write snapshot chunk at offset {}: {}", chunk.offset, e)))?;
+        if !chunk.done {
+            return Ok(respond(writer.len(), false));
+        }
+
+        let next_offset = writer.len();
+        if let Some(writer) = self.incoming_snapshot.take() {
+            let members = chunk.members.iter().map(Endpoint::try_from).collect::<Result<_>>()?;
+            self.snapshots
+                .finish(writer, members)
+                .map_err(|e| RuftError::Storage(format!("Failed to store snapshot at index {}: {}", index, e)))?;
+        }
+        self.install_snapshot(index, snapshot_term)?;
+        Ok(respond(next_offset, true))
+    }
+
+    /// Replace the state machine and the log with the snapshot just stored, which ends at `index` with `term`
+    fn install_snapshot(&mut self, index: u64, term: u64) -> Result<()> {
+        let mut snapshot = self
+            .snapshots
+            .load()
+            .map_err(|e| RuftError::Storage(format!("Failed to load snapshot at index {}: {}", index, e)))?
+            .filter(|snapshot| (snapshot.manifest.index, snapshot.manifest.term) == (index, term))
+            .ok_or_else(|| RuftError::Storage(format!("Snapshot at index {} term {} is not readable after storing it", index, term)))?;
+
+        // Until the state machine holds the snapshot the log must keep what it replaces
+        self.sm
+            .restore(&mut snapshot.data)
+            .map_err(|e| RuftError::Storage(format!("Failed to restore snapshot at index {}: {}", index, e)))?;
+        self.last_applied = index;
+
+        // Entries after the snapshot stay if our log agrees with it, otherwise the whole log is stale
+        let log = if self.log.term_at(index) == Some(term) {
+            self.log.compact(index, term)
+        } else {
+            self.log.reset(index, term)
+        };
+        log.map_err(|e| RuftError::Storage(format!("Failed to compact log {}: {}", self.log.path().display(), e)))?;
+        // Address changes compacted into the snapshot only survive in its member list
+        let members = self.meta.members();
+        for moved in snapshot.manifest.members {
+            if members.iter().any(|m| m.id() == moved.id()) {
+                self.move_member(moved)?;
+            }
+        }
+        if index > self.commit_index() {
+            self.meta.set_committed_index(index)?;
+            self.commit_tx.send_replace(index);
+        }
+        info!("Node {} installed a snapshot at index {} term {}", self.endpoint.id(), index, term);
+        Ok(())
+    }
+
+    /// Append a new entry at the end of the local log and return its index
+    fn append_entry(&mut self, term: u64, entry_type: EntryType, command: Vec<u8>) -> Result<u64> {
+        let index = self.last_log_index() + 1;
+        let entry = LogEntry {
+            index,
+            term,
+            command,
+            entry_type: entry_type as i32,
+        };
+        self.append_log(&[entry])?;
+        Ok(index)
+    }
+
+    fn append_log(&mut self, entries: &[LogEntry]) -> Result<()> {
+        self.log
+            .append(entries)
+            .map_err(|e| RuftError::Storage(format!("Failed to append to log {}: {}", self.log.path().display(), e)))?;
+        let sync_now = match self.config.durability {
+            Durability::EveryWrite => true,
+            Durability::GroupCommit { max_bytes, .. } => self.log.pending_bytes() >= max_bytes,
+            Durability::Interval(_) => false,
+        };
+        if sync_now { self.sync_log() } else { Ok(()) }
+    }
+
+    /// Fsync whatever was appended to the log since the last sync
+    fn sync_log(&mut self) -> Result<()> {
+        let synced = self.log.sync().map_err(|e| RuftError::Storage(format!("Failed to sync log {}: {}", self.log.path().display(), e)))?;
+        if synced {
+            self.log_syncs += 1;
+        }
+        Ok(())
+    }
+
+    /// Start a group commit window for unsynced appends, `None` if one is already open or not needed
+    fn schedule_sync(&mut self) -> Option<Duration> {
+        let Durability::GroupCommit { window, .. } = self.config.durability else {
+            return None;
+        };
+        if self.sync_scheduled || self.log.pending_bytes() == 0 {
+            return None;
+        }
+        self.sync_scheduled = true;
+        Some(window)
+    }
+
+    fn truncate_log(&mut self, index: u64) -> Result<()> {
+        self.log
+            .truncate_from(index)
+            .map_err(|e| RuftError::Storage(format!("Failed to truncate log {}: {}", self.log.path().display(), e)))
+    }
+
+    /// Raft election restriction: only vote for candidates whose log is at least as up-to-date as ours
+    fn is_up_to_date(&self, last_log_term: u64, last_log_index: u64) -> bool {
+        (last_log_term, last_log_index) >= (self.last_log_term(), self.last_log_index())
+    }
+
+    /// Check the entries of the leader of our term against our log, append them and follow its commit index
+    fn append_from_leader(&mut self, req: &AppendEntriesRequest) -> Result<AppendEntriesResponse> {
+        self.restart_timer();
+
+        // Entries up to our snapshot are committed and therefore match, check the log after them
+        let snapshot_index = self.log.snapshot_index();
+        let (prev_log_index, prev_log_term, entries) = if req.prev_log_index < snapshot_index {
+            let skip = ((snapshot_index - req.prev_log_index) as usize).min(req.entries.len());
+            (snapshot_index, self.log.snapshot_term(), &req.entries[skip..])
+        } else {
+            (req.prev_log_index, req.prev_log_term, req.entries.as_slice())
+        };
+
+        // Consistency check: our log must contain the entry preceding the new ones
+        if self.log.term_at(prev_log_index) != Some(prev_log_term) {
+            let last_log_index = self.last_log_index().min(req.prev_log_index.saturating_sub(1));
+            return Ok(AppendEntriesResponse {
+                term: req.term,
+                success: false,
+                last_log_index,
+            });
+        }
+
+        // Skip entries we already have, drop a conflicting suffix, then append the rest
+        let mut new_entries = entries;
+        while let Some(entry) = new_entries.first() {
+            match self.log.term_at(entry.index) {
+                Some(t) if t == entry.term => new_entries = &new_entries[1..],
+                Some(_) => {
+                    if entry.index <= self.commit_index() {
+                        return Err(RuftError::InvalidState(format!("Leader tried to overwrite committed entry {}", entry.index)));
+                    }
+                    self.truncate_log(entry.index)?;
+                    break;
+                }
+                None => break,
+            }
+        }
+        self.append_log(new_entries)?;
+        // The leader counts acknowledged entries toward a commit, so they have to be on disk first
+        if !matches!(self.config.durability, Durability::Interval(_)) {
+            self.sync_log()?;
+        }
+
+        let last_new_index = req.prev_log_index + req.entries.len() as u64;
+        self.set_commit_index(req.leader_commit.min(last_new_index))?;
+
+        let last_log_index = self.last_log_index();
+        Ok(AppendEntriesResponse {
+            term: req.term,
+            success: true,
+            last_log_index,
+        })
+    }
+
+    /// Drop the clients of former members and of members that moved
+    ///
+    /// Returns the dropped clients of moved members, to be closed, and the members
+    /// left without a client. Clients of the others are kept, along with their
+    /// connection and health.
+    fn prune_peers(&self) -> (Vec<Peer>, Vec<Endpoint>) {
+        let my_id = self.endpoint.id();
+        let members: Vec<Endpoint> = self.meta.members().into_iter().filter(|m| m.id() != my_id).collect();
+        self.remote_clients.retain(|id, _| members.iter().any(|m| m.id() == *id));
+        let moved = members
+            .iter()
+            .filter_map(|m| self.remote_clients.remove_if(&m.id(), |_, (endpoint, _)| endpoint != m))
+            .map(|(_, peer)| peer)
+            .collect();
+        let missing = members.into_iter().filter(|m| !self.remote_clients.contains_key(&m.id())).collect();
+        (moved, missing)
+    }
+
+    fn find_member(&self, id: NodeId) -> Option<Endpoint> {
+        self.meta.members().into_iter().find(|m| m.id() == id)
+    }
+
+    /// Peers ordered by id, so the order RPCs go out in doesn't depend on hashing
+    fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<_> = self.remote_clients.iter().map(|e| e.value().clone()).collect();
+        peers.sort_by_key(|(endpoint, _)| endpoint.id());
+        peers
+    }
+
+    fn restart_timer(&self) {
+        if let Some(timer) = &self.timer {
+            timer.restart();
+        }
+    }
+
+    /// Record an action of the TLA+ spec together with the state it left us in
+    fn trace(&self, action: &str, role: &str, term: u64, quorum: Vec<NodeId>, msg: impl FnOnce() -> Option<TraceMessage>) {
+        let Some(tracer) = &self.tracer else { return };
+        tracer.record(&TraceEvent {
+            node: self.endpoint.id(),
+            action: action.to_string(),
+            term,
+            role: role.to_string(),
+            voted_for: self.meta.voted_for(),
+            log_start: self.log.snapshot_index(),
+            log: self.log.terms(),
+            commit: self.commit_index(),
+            quorum,
+            msg: msg(),
+        });
+    }
 }
 
 /// Type-safe node with specific state
 /// Each state (Follower, Candidate, Leader, Learner) has its own data
-struct NodeData<S: RaftState> {
+pub(crate) struct NodeData<S: RaftState> {
     common: CommonData,
     pub state: S,
 }
 
 /// Runtime representation of a Raft node
 /// Uses enum to allow state transitions while maintaining type safety per state
-pub enum RaftNode {
+pub(crate) enum RaftNode {
     Follower(NodeData<Follower>),
     Candidate(NodeData<Candidate>),
     Leader(NodeData<Leader>),
@@ -39,121 +522,273 @@ pub enum RaftNode {
 }
 
 impl RaftNode {
-    /// Transition from Follower to Candidate (election timeout)
-    fn transition_candidate(self) -> Result<Self> {
-        fn make_candidate(mut common: CommonData) -> Result<RaftNode> {
-            let new_term = common.meta.next_term()?;
-            let id = common.endpoint.id();
-
-            Ok(RaftNode::Candidate(NodeData {
-                common,
-                state: Candidate {
-                    term: new_term,
-                    votes_received: 1,
-                    voted_for: id,
-                },
-            }))
+    /// Transition to Candidate (election timeout), starting a new term and voting for ourselves
+    ///
+    /// The node comes back as it was if the new term or the vote can't be stored.
+    fn transition_candidate(mut self) -> (Self, Result<()>) {
+        if matches!(self, RaftNode::Learner(_)) {
+            return (self, Ok(()));
         }
+        let common = self.common_mut();
+        let id = common.endpoint.id();
+        let new_term = match common.meta.next_term().and_then(|term| common.meta.set_voted_for(id).map(|_| term)) {
+            Ok(term) => term,
+            Err(e) => return (self, Err(e)),
+        };
 
-        match self {
-            RaftNode::Follower(node) => make_candidate(node.common),
-            RaftNode::Leader(node) => make_candidate(node.common),
-            RaftNode::Candidate(_) | RaftNode::Learner(_) => Ok(self),
-        }
+        let common = self.into_common();
+        common.set_leader(None);
+        let node = RaftNode::Candidate(NodeData {
+            common,
+            state: Candidate {
+                term: new_term,
+                votes_received: 1,
+                voted_for: id,
+            },
+        });
+        node.trace("Timeout", vec![], || None);
+        (node, Ok(()))
     }
 
-    /// Transition from Candidate to Leader (won election)
-    fn transition_leader(self) -> Result<Self> {
-        if let RaftNode::Candidate(node) = self {
-            let members = node.common.meta.members();
-            let last_log_index = node.common.meta.log_id();
+    /// Transition from Candidate to Leader (won election with the votes of `voters`)
+    fn transition_leader(self, voters: Vec<NodeId>) -> (Self, Result<()>) {
+        let mut node = match self {
+            RaftNode::Candidate(node) => node,
+            node => return (node, Ok(())),
+        };
+        // A no-op entry from the new term lets entries of previous terms be committed.
+        // The first leader of a new cluster also names the cluster in it.
+        let cluster_id = match node.common.meta.cluster_id() {
+            Some(_) => vec![],
+            None => random_uuid(node.common.random.as_ref()).as_bytes().to_vec(),
+        };
+        if let Err(e) = node.common.append_entry(node.state.term, EntryType::Noop, cluster_id) {
+            return (RaftNode::Candidate(node), Err(e));
+        }
 
-            // Initialize leader state
-            let mut next_index = std::collections::HashMap::new();
-            let mut match_index = std::collections::HashMap::new();
+        let members = node.common.meta.members();
+        let last_log_index = node.common.log.last_index();
 
-            for member in members {
-                if member != node.common.endpoint {
-                    next_index.insert(member.clone(), last_log_index + 1);
-                    match_index.insert(member, 0);
-                }
+        // Initialize leader state
+        let mut next_index = std::collections::HashMap::new();
+        let mut match_index = std::collections::HashMap::new();
+
+        for member in members {
+            if member.id() != node.common.endpoint.id() {
+                next_index.insert(member.id(), last_log_index + 1);
+                match_index.insert(member.id(), 0);
             }
+        }
 
-            info!("Node {} became leader for term {}", node.common.endpoint.id(), node.state.term);
+        info!("Node {} became leader for term {}", node.common.endpoint.id(), node.state.term);
+        node.common.set_leader(Some(node.common.endpoint.clone()));
 
-            Ok(RaftNode::Leader(NodeData {
-                common: node.common,
-                state: Leader {
-                    term: node.state.term,
-                    next_index,
-                    match_index,
-                },
-            }))
-        } else {
-            Ok(self)
-        }
+        let node = RaftNode::Leader(NodeData {
+            common: node.common,
+            state: Leader {
+                term: node.state.term,
+                next_index,
+                match_index,
+                snapshot_transfers: HashMap::new(),
+            },
+        });
+        node.trace("BecomeLeader", voters, || None);
+        (node, Ok(()))
     }
 
-    /// Transition from Candidate to Follower (lost election or discovered higher term)
-    fn transition_follower(self, new_term: u64, leader: Endpoint) -> Result<Self> {
-        match self {
-            RaftNode::Candidate(mut node) => {
-                if new_term > node.state.term() {
-                    node.common.meta.set_term(new_term)?;
-                }
+    /// Transition to Follower (lost election or discovered higher term)
+    ///
+    /// The node comes back as it was if the new term can't be stored.
+    fn transition_follower(mut self, new_term: u64, leader: Option<Endpoint>) -> (Self, Result<()>) {
+        let term = self.current_term();
+        let newer = new_term > term;
+        if newer && let Err(e) = self.common_mut().meta.set_term(new_term) {
+            return (self, Err(e));
+        }
+        self.common().set_leader(leader.clone());
 
-                Ok(RaftNode::Follower(NodeData {
-                    common: node.common,
-                    state: Follower {
-                        term: new_term,
-                        leader,
-                        voted_for: None,
-                    },
-                }))
+        let node = match self {
+            RaftNode::Learner(mut node) => {
+                node.state.term = new_term.max(term);
+                node.state.leader = leader;
+                RaftNode::Learner(node)
             }
-            RaftNode::Leader(mut node) => {
-                if new_term > node.state.term() {
-                    node.common.meta.set_term(new_term)?;
-                }
-
-                Ok(RaftNode::Follower(NodeData {
-                    common: node.common,
+            node => {
+                let common = node.into_common();
+                let voted_for = common.meta.voted_for();
+                RaftNode::Follower(NodeData {
+                    common,
                     state: Follower {
-                        term: new_term,
+                        term: new_term.max(term),
                         leader,
-                        voted_for: None,
+                        voted_for,
                     },
-                }))
+                })
             }
-            // Already follower or learner
-            other => Ok(other),
+        };
+        if newer {
+            node.trace("UpdateTerm", vec![], || None);
+        }
+        (node, Ok(()))
+    }
+
+    /// Discovered a higher term without knowing its leader yet
+    fn step_down(self, new_term: u64) -> (Self, Result<()>) {
+        // The leader of the new term is unknown until its first heartbeat
+        self.transition_follower(new_term, None)
+    }
+
+    /// Step down if a peer reported a term newer than ours
+    fn observe_term(self, term: u64) -> (Self, Result<()>) {
+        if term > self.current_term() {
+            info!("Discovered higher term {}, stepping down", term);
+            self.step_down(term)
+        } else {
+            (self, Ok(()))
         }
     }
 }
 
 impl RaftNode {
-    pub fn new(endpoint: Endpoint, config: Config) -> Result<Self> {
-        let meta = PersistentMeta::new(&config)?;
+    pub fn new(endpoint: Endpoint, config: Config, transport: Arc<dyn Transport>, random: Arc<dyn Random>, mut sm: Box<dyn Sm>) -> Result<Self> {
+        let mut meta = PersistentMeta::new(&config)?;
+        Self::init_cluster_id(&config, &mut meta)?;
+        let log_path = PathBuf::from(format!("{}/log.bin", config.data_dir));
+        let mut log = RaftLog::open(log_path).map_err(|e| RuftError::Storage(format!("Failed to open log in {}: {}", config.data_dir, e)))?;
+        let snapshots_dir = PathBuf::from(format!("{}/snapshots", config.data_dir));
+        let snapshots = SnapshotStore::open(snapshots_dir, config.snapshot_retain).map_err(|e| RuftError::Storage(format!("Failed to open snapshots in {}: {}", config.data_dir, e)))?;
+        let last_applied = Self::recover(&config, &mut meta, &mut log, &snapshots, sm.as_mut())?;
         let term = meta.term();
+        let voted_for = meta.voted_for();
+
+        let (commit_tx, _) = watch::channel(meta.committed_index());
+        let (leader_tx, _) = watch::channel(None);
+        let tracer = match &config.trace_file {
+            Some(path) => Some(Tracer::open(path.as_ref()).map_err(|e| RuftError::Storage(format!("Failed to open trace file {}: {}", path, e)))?),
+            None => None,
+        };
 
         let common = CommonData {
             endpoint: endpoint.clone(),
             meta,
+            log,
             config,
+            transport,
+            random,
             remote_clients: DashMap::new(),
             timer: None,
+            commit_tx,
+            leader_tx,
+            tracer,
+            sm,
+            last_applied,
+            proposals: HashMap::new(),
+            snapshots,
+            incoming_snapshot: None,
+            log_syncs: 0,
+            sync_scheduled: false,
+            timeout_now: false,
         };
+        // The state machine lives in memory only, replay what was committed after the snapshot
+        let mut common = common;
+        common.apply_committed()?;
 
-        // Start as Follower with a dummy leader (will be updated on first heartbeat)
-        let dummy_leader = endpoint;
-        Ok(RaftNode::Follower(NodeData {
-            common,
-            state: Follower {
-                term,
-                leader: dummy_leader,
-                voted_for: None,
-            },
-        }))
+        // The leader is unknown until the first heartbeat. A node the persisted
+        // membership leaves out keeps following the log without a vote.
+        let members = common.meta.members();
+        let node = if members.is_empty() || members.iter().any(|m| m.id() == endpoint.id()) {
+            RaftNode::Follower(NodeData {
+                common,
+                state: Follower { term, leader: None, voted_for },
+            })
+        } else {
+            RaftNode::Learner(NodeData {
+                common,
+                state: Learner { term, leader: None },
+            })
+        };
+        node.trace("Restart", vec![], || None);
+        Ok(node)
+    }
+
+    /// Settle the cluster the node belongs to before it talks to peers
+    ///
+    /// A configured id must match the persisted one, a data directory of another
+    /// cluster is refused. Without one, the first leader of a new cluster picks a
+    /// random id and replicates it in its no-op entry, every node adopts it once
+    /// the entry is committed. Until then peer RPCs carry no id.
+    fn init_cluster_id(config: &Config, meta: &mut PersistentMeta) -> Result<()> {
+        match (meta.cluster_id(), config.cluster_id) {
+            (Some(persisted), Some(configured)) if persisted != configured => Err(RuftError::InvalidState(format!(
+                "Data in {} belongs to cluster {}, the configuration names cluster {}",
+                config.data_dir, persisted, configured
+            ))),
+            (Some(_), _) => Ok(()),
+            (None, Some(configured)) => meta.set_cluster_id(configured),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Bring snapshot, log and meta back to a consistent state after a restart or crash
+    ///
+    /// Restores the newest valid snapshot into the state machine, fits the log to it and
+    /// checks the log against meta. Returns the index the state machine is at, the
+    /// committed entries after it are replayed by the caller.
+    fn recover(config: &Config, meta: &mut PersistentMeta, log: &mut RaftLog, snapshots: &SnapshotStore, sm: &mut dyn Sm) -> Result<u64> {
+        let dir = &config.data_dir;
+        let snapshot = snapshots.load().map_err(|e| RuftError::Storage(format!("Failed to load snapshot in {}: {}", dir, e)))?;
+        let last_applied = match snapshot {
+            Some(snapshot) if snapshot.manifest.index < log.snapshot_index() => {
+                return Err(RuftError::Storage(format!(
+                    "Log in {} starts after index {} but the newest valid snapshot ends at {}",
+                    dir,
+                    log.snapshot_index(),
+                    snapshot.manifest.index
+                )));
+            }
+            Some(mut snapshot) => {
+                log.compact(snapshot.manifest.index, snapshot.manifest.term)
+                    .map_err(|e| RuftError::Storage(format!("Log in {} does not fit the snapshot: {}", dir, e)))?;
+                sm.restore(&mut snapshot.data)
+                    .map_err(|e| RuftError::Storage(format!("Failed to restore snapshot in {}: {}", dir, e)))?;
+                snapshot.manifest.index
+            }
+            None if log.snapshot_index() > 0 => {
+                return Err(RuftError::Storage(format!("Log in {} starts after index {} but there is no snapshot", dir, log.snapshot_index())));
+            }
+            None => 0,
+        };
+
+        // Terms only grow and meta is written before an entry of a new term is accepted
+        if log.last_term() > meta.term() {
+            return Err(RuftError::Storage(format!(
+                "Log in {} holds entries of term {} but meta is at term {}",
+                dir,
+                log.last_term(),
+                meta.term()
+            )));
+        }
+        // Only committed entries are snapshotted, the commit may not have reached meta before the crash
+        meta.set_committed_index(last_applied)?;
+        if meta.committed_index() > log.last_index() {
+            // Entries are synced before they count toward a commit, except under an interval policy
+            if !matches!(config.durability, Durability::Interval(_)) {
+                return Err(RuftError::Storage(format!(
+                    "Log in {} ends at index {} but meta has index {} committed",
+                    dir,
+                    log.last_index(),
+                    meta.committed_index()
+                )));
+            }
+            warn!(
+                "Log in {} lost committed entries {}..={} that were not synced, fetching them from the leader again",
+                dir,
+                log.last_index() + 1,
+                meta.committed_index()
+            );
+            meta.reset_committed_index(log.last_index())?;
+        }
+        Ok(last_applied)
     }
 
     /// Get common data regardless of current state
@@ -166,6 +801,15 @@ impl RaftNode {
         }
     }
 
+    fn into_common(self) -> CommonData {
+        match self {
+            RaftNode::Follower(node) => node.common,
+            RaftNode::Candidate(node) => node.common,
+            RaftNode::Leader(node) => node.common,
+            RaftNode::Learner(node) => node.common,
+        }
+    }
+
     fn common_mut(&mut self) -> &mut CommonData {
         match self {
             RaftNode::Follower(node) => &mut node.common,
@@ -184,6 +828,16 @@ impl RaftNode {
         }
     }
 
+    /// Leader as known by this node
+    fn leader(&self) -> Option<Endpoint> {
+        match self {
+            RaftNode::Follower(node) => node.state.leader.clone(),
+            RaftNode::Learner(node) => node.state.leader.clone(),
+            RaftNode::Leader(node) => Some(node.common.endpoint.clone()),
+            RaftNode::Candidate(_) => None,
+        }
+    }
+
     pub fn state_name(&self) -> &'static str {
         match self {
             RaftNode::Follower(_) => "Follower",
@@ -193,181 +847,1342 @@ impl RaftNode {
         }
     }
 
-    async fn init_rpc_clients(&self) -> Result<()> {
-        self.common().remote_clients.clear();
+    fn metrics(&self) -> Metrics {
+        let common = self.common();
+        Metrics {
+            id: common.endpoint.id(),
+            cluster_id: common.meta.cluster_id(),
+            state: self.state_name().to_string(),
+            term: self.current_term(),
+            commit_index: common.commit_index(),
+            last_applied: common.last_applied,
+            last_log_index: common.last_log_index(),
+            snapshot_index: common.log.snapshot_index(),
+            durability: common.config.durability,
+            synced_index: common.log.synced_index(),
+            log_syncs: common.log_syncs,
+            peers: common.remote_clients.iter().map(|e| (*e.key(), e.value().1.health())).collect(),
+        }
+    }
 
-        let members = self.common().meta.members();
-        let my_endpoint = &self.common().endpoint;
+    fn trace(&self, action: &str, quorum: Vec<NodeId>, msg: impl FnOnce() -> Option<TraceMessage>) {
+        self.common().trace(action, self.state_name(), self.current_term(), quorum, msg);
+    }
 
-        for endpoint in members {
-            if &endpoint == my_endpoint {
-                continue;
+    /// Append an entry to the leader's log, returning its index, term and a commit index watcher
+    fn propose(&mut self, entry_type: EntryType, data: Vec<u8>) -> std::result::Result<(u64, watch::Receiver<u64>), CmdResp> {
+        // Only leader can process commands
+        match self {
+            RaftNode::Leader(node) => {
+                let term = node.state.term;
+                let index = node.common.append_entry(term, entry_type, data).map_err(|e| CmdResp::Rejected {
+                    code: ErrorCode::StorageFull,
+                    message: e.to_string(),
+                })?;
+                node.common.trace("ClientRequest", "Leader", term, vec![], || None);
+                node.common.proposals.insert(index, Proposal { term, applied: false, result: None });
+                Ok((index, node.common.commit_tx.subscribe()))
             }
+            // Redirect to the leader, if we know it
+            node => Err(CmdResp::NotLeader { leader: node.leader() }),
+        }
+    }
 
-            match init_remote_client(&endpoint).await {
-                Ok(client) => {
-                    self.common().remote_clients.insert(endpoint, client);
-                }
-                Err(e) => {
-                    error!("Failed to init remote client for {}: {}", endpoint, e);
+    /// The leader of our term hands leadership to us, campaign without waiting for the election timeout
+    fn handle_timeout_now(&mut self, req: &TimeoutNowRequest) -> TimeoutNowResponse {
+        let term = self.current_term();
+        let RaftNode::Follower(node) = self else {
+            return TimeoutNowResponse { term, accepted: false };
+        };
+        if req.term != term {
+            return TimeoutNowResponse { term, accepted: false };
+        }
+        info!("Node {} hands leadership to us in term {}", req.leader_id, term);
+        node.common.timeout_now = true;
+        node.common.restart_timer();
+        TimeoutNowResponse { term, accepted: true }
+    }
+
+    fn handle_pre_vote(&self, req: &PreVoteRequest) -> PreVoteResponse {
+        let term = self.current_term();
+        // A pre-vote never changes our term, it only tells the candidate whether a real election could succeed
+        let vote_granted = !matches!(self, RaftNode::Leader(_)) && req.term > term && self.common().is_up_to_date(req.last_log_term, req.last_log_index);
+        PreVoteResponse { term, vote_granted }
+    }
+
+    fn handle_request_vote(self, req: &RequestVoteRequest) -> (Self, Result<RequestVoteResponse>) {
+        let (mut node, stepped) = if req.term > self.current_term() { self.step_down(req.term) } else { (self, Ok(())) };
+        let resp = stepped.and_then(|_| node.vote(req));
+        (node, resp)
+    }
+
+    /// Grant our vote of this term to the candidate of `req` if it is still free
+    fn vote(&mut self, req: &RequestVoteRequest) -> Result<RequestVoteResponse> {
+        let term = self.current_term();
+        let candidate = NodeId::from(req.candidate_id);
+        let vote_granted = match self {
+            RaftNode::Follower(n) if req.term == term => {
+                let available = n.state.voted_for.is_none_or(|id| id == candidate);
+                if available && n.common.is_up_to_date(req.last_log_term, req.last_log_index) {
+                    n.common.meta.set_voted_for(candidate)?;
+                    n.state.voted_for = Some(candidate);
+                    n.common.restart_timer();
+                    true
+                } else {
+                    false
                 }
             }
+            _ => false,
+        };
+
+        self.trace("HandleRequestVoteRequest", vec![], || {
+            Some(TraceMessage {
+                kind: "RequestVoteRequest".into(),
+                from: candidate,
+                term: req.term,
+                index: req.last_log_index,
+                log_term: req.last_log_term,
+                entries: vec![],
+                commit: 0,
+                success: vote_granted,
+            })
+        });
+        Ok(RequestVoteResponse { term, vote_granted })
+    }
+
+    fn handle_install_snapshot(self, chunk: &InstallSnapshotChunk) -> (Self, Result<InstallSnapshotResponse>) {
+        let term = self.current_term();
+        if chunk.term < term {
+            let resp = InstallSnapshotResponse {
+                term,
+                next_offset: 0,
+                installed: false,
+            };
+            return (self, Ok(resp));
         }
 
+        let leader = self.common().find_member(chunk.leader_id.into());
+        let (mut node, followed) = self.transition_follower(chunk.term, leader);
+        let resp = followed.and_then(|_| node.install_chunk(chunk));
+        (node, resp)
+    }
+
+    /// Store a chunk of the leader's snapshot, installing the snapshot with the last one
+    fn install_chunk(&mut self, chunk: &InstallSnapshotChunk) -> Result<InstallSnapshotResponse> {
+        let common = self.common_mut();
+        common.restart_timer();
+        let applied = common.last_applied;
+        let resp = common.receive_snapshot_chunk(chunk.term, chunk)?;
+        if self.common().last_applied != applied {
+            self.trace("InstallSnapshot", vec![], || {
+                Some(TraceMessage {
+                    kind: "InstallSnapshotRequest".into(),
+                    from: chunk.leader_id.into(),
+                    term: chunk.term,
+                    index: chunk.last_included_index,
+                    log_term: chunk.last_included_term,
+                    entries: vec![],
+                    commit: 0,
+                    success: true,
+                })
+            });
+        }
+        Ok(resp)
+    }
+
+    fn handle_append_entries(self, req: &AppendEntriesRequest) -> (Self, Result<AppendEntriesResponse>) {
+        let (node, resp) = self.append_entries(req);
+        let resp = resp.inspect(|resp| {
+            node.trace("HandleAppendEntriesRequest", vec![], || {
+                Some(TraceMessage {
+                    kind: "AppendEntriesRequest".into(),
+                    from: req.leader_id.into(),
+                    term: req.term,
+                    index: req.prev_log_index,
+                    log_term: req.prev_log_term,
+                    entries: req.entries.iter().map(|e| e.term).collect(),
+                    commit: req.leader_commit,
+                    success: resp.success,
+                })
+            });
+        });
+        (node, resp)
+    }
+
+    fn append_entries(self, req: &AppendEntriesRequest) -> (Self, Result<AppendEntriesResponse>) {
+        let term = self.current_term();
+        if req.term < term {
+            let last_log_index = self.common().last_log_index();
+            return (self, Ok(AppendEntriesResponse { term, success: false, last_log_index }));
+        }
+
+        let leader = self.common().find_member(req.leader_id.into());
+        let (mut node, followed) = self.transition_follower(req.term, leader);
+        let resp = followed.and_then(|_| node.common_mut().append_from_leader(req));
+        (node, resp)
+    }
+}
+
+impl NodeData<Leader> {
+    /// The next AppendEntries for `peer`, `None` if the entries it needs are compacted
+    fn append_request(&self, peer: &Endpoint) -> Option<AppendEntriesRequest> {
+        let next_index = self.state.next_index.get(&peer.id()).copied().unwrap_or(self.common.last_log_index() + 1);
+        let prev_log_index = next_index - 1;
+        Some(AppendEntriesRequest {
+            term: self.state.term,
+            leader_id: self.common.endpoint.id().into(),
+            prev_log_index,
+            prev_log_term: self.common.log.term_at(prev_log_index)?,
+            entries: self.common.log.entries_from(next_index, MAX_ENTRIES_PER_APPEND),
+            leader_commit: self.common.commit_index(),
+            cluster_id: self.common.cluster_id(),
+        })
+    }
+
+    fn on_append_response(&mut self, peer: &Endpoint, req: &AppendEntriesRequest, resp: &AppendEntriesResponse) -> Result<()> {
+        self.common.trace("HandleAppendEntriesResponse", "Leader", self.state.term, vec![], || {
+            Some(TraceMessage {
+                kind: "AppendEntriesResponse".into(),
+                from: peer.id(),
+                term: resp.term,
+                index: resp.last_log_index,
+                log_term: 0,
+                entries: vec![],
+                commit: 0,
+                success: resp.success,
+            })
+        });
+        if resp.success {
+            let matched = req.prev_log_index + req.entries.len() as u64;
+            let match_index = self.state.match_index.entry(peer.id()).or_insert(0);
+            *match_index = (*match_index).max(matched);
+            let next_index = self.state.next_index.entry(peer.id()).or_insert(0);
+            *next_index = (*next_index).max(matched + 1);
+            return self.advance_commit();
+        }
+
+        // Only back off if the response belongs to the latest probe, responses may arrive out of order
+        if let Some(next_index) = self.state.next_index.get_mut(&peer.id())
+            && *next_index == req.prev_log_index + 1
+        {
+            *next_index = (resp.last_log_index + 1).min(*next_index - 1).max(1);
+        }
         Ok(())
     }
 
-    pub async fn update_members(&mut self, endpoints: Vec<Endpoint>) -> Result<()> {
-        self.common_mut().meta.update_members(endpoints)?;
-        self.init_rpc_clients().await?;
+    /// Mark a snapshot transfer to `peer` as running and return the offset to resume at, `None` if one is running already
+    fn begin_snapshot_transfer(&mut self, peer: &Endpoint) -> Option<u64> {
+        let index = self.common.log.snapshot_index();
+        let transfer = self.state.snapshot_transfers.entry(peer.id()).or_default();
+        if transfer.in_flight {
+            return None;
+        }
+        if transfer.index != index {
+            *transfer = SnapshotTransfer { index, ..Default::default() };
+        }
+        transfer.in_flight = true;
+        Some(transfer.offset)
+    }
+
+    /// Record how a snapshot transfer ended: installed, or stopped at the offset the follower reported
+    fn on_snapshot_sent(&mut self, peer: &Endpoint, result: Result<(u64, InstallSnapshotResponse)>) -> Result<()> {
+        let (index, resp) = match result {
+            Ok(sent) => sent,
+            Err(e) => {
+                debug!("Snapshot transfer to {} broke off: {}", peer, e);
+                if let Some(transfer) = self.state.snapshot_transfers.get_mut(&peer.id()) {
+                    transfer.in_flight = false;
+                }
+                return Ok(());
+            }
+        };
+        if !resp.installed {
+            let offset = resp.next_offset;
+            self.state.snapshot_transfers.insert(peer.id(), SnapshotTransfer { index, offset, in_flight: false });
+            return Ok(());
+        }
+
+        info!("Peer {} installed the snapshot at index {}", peer, index);
+        self.state.snapshot_transfers.remove(&peer.id());
+        let match_index = self.state.match_index.entry(peer.id()).or_insert(0);
+        *match_index = (*match_index).max(index);
+        let next_index = self.state.next_index.entry(peer.id()).or_insert(0);
+        *next_index = (*next_index).max(index + 1);
+        self.advance_commit()
+    }
+
+    /// Commit the highest index stored on a majority, Raft only counts replicas for entries of the current term
+    fn advance_commit(&mut self) -> Result<()> {
+        let mut matched: Vec<u64> = self.state.match_index.values().copied().collect();
+        matched.push(self.common.durable_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let quorum = self.common.quorum();
+        let Some(&index) = matched.get(quorum - 1) else {
+            return Ok(());
+        };
+        if index > self.common.commit_index() && self.common.log.term_at(index) == Some(self.state.term) {
+            debug!("Leader {} commits up to {}", self.common.endpoint.id(), index);
+            self.common.set_commit_index(index)?;
+            let replicas = self.state.match_index.iter().filter(|(_, m)| **m >= index).map(|(peer, _)| *peer);
+            let quorum = std::iter::once(self.common.endpoint.id()).chain(replicas).collect();
+            self.common.trace("AdvanceCommitIndex", "Leader", self.state.term, quorum, || None);
+        }
         Ok(())
     }
+}
 
-    pub async fn submit(&self, _cmd: CmdReq) -> CmdResp {
-        // Only leader can process commands
-        match self {
-            RaftNode::Leader(_) => {
-                // TODO: Implement log replication
-                CmdResp::Success { data: None }
-            }
-            RaftNode::Follower(node) => {
-                // Redirect to leader
-                CmdResp::NotLeader {
-                    leader: Some(node.state.leader.clone()),
+/// Reply channel of an event that asks the node for an answer
+type Reply<T> = oneshot::Sender<Result<T>>;
+
+/// A proposal the leader appended: its index, a commit index watcher and how long to wait for the commit
+type Admitted = (u64, watch::Receiver<u64>, Duration);
+
+/// State machine frozen at an applied entry, written out as a snapshot on a blocking task
+struct Frozen {
+    index: u64,
+    term: u64,
+    members: Vec<Endpoint>,
+    view: Box<dyn SmView>,
+}
+
+/// Messages to the task that owns the node
+///
+/// RPCs, timer ticks, client proposals and storage completions all arrive here
+/// and are handled one at a time. Network calls run in tasks of their own and
+/// post their results back as events, so the node never waits on a peer.
+enum Event {
+    PreVote(PreVoteRequest, Reply<PreVoteResponse>),
+    RequestVote(RequestVoteRequest, Reply<RequestVoteResponse>),
+    AppendEntries(AppendEntriesRequest, Reply<AppendEntriesResponse>),
+    InstallSnapshot(InstallSnapshotChunk, Reply<InstallSnapshotResponse>),
+    TimeoutNow(TimeoutNowRequest, Reply<TimeoutNowResponse>),
+    /// The election or heartbeat timer fired
+    Timeout,
+    /// A pre-vote round for `term` got a majority
+    PreVoteWon(u64),
+    /// Answers to the RequestVote round of `term`
+    Votes {
+        term: u64,
+        responses: Vec<(Endpoint, RequestVoteResponse)>,
+    },
+    /// Answer of `peer` to an AppendEntries sent as leader of `term`
+    Appended {
+        term: u64,
+        peer: Endpoint,
+        req: AppendEntriesRequest,
+        resp: AppendEntriesResponse,
+    },
+    /// Send AppendEntries to every follower now, the sender is told once all answers are in
+    Replicate(oneshot::Sender<()>),
+    /// A snapshot transfer to `peer` started as leader of `term` ended
+    SnapshotSent {
+        term: u64,
+        peer: Endpoint,
+        result: Result<(u64, InstallSnapshotResponse)>,
+    },
+    /// Connecting a client to a member finished
+    Connected(Endpoint, Result<Arc<dyn RaftRpcClient>>),
+    /// A client proposes an entry, answered once it is appended
+    Propose {
+        entry_type: EntryType,
+        data: Vec<u8>,
+        reply: oneshot::Sender<std::result::Result<Admitted, CmdResp>>,
+    },
+    UpdateMembers(Vec<Endpoint>, Reply<()>),
+    /// The group commit window opened by a proposal closed
+    GroupCommit,
+    /// The interval of [`Durability::Interval`] passed
+    SyncLog,
+    /// Take a snapshot now, answered with its index once it is stored
+    Snapshot(Reply<u64>),
+    /// Writing the snapshot at `index` of `term` finished
+    SnapshotTaken {
+        index: u64,
+        term: u64,
+        result: Result<()>,
+    },
+    /// Look at or change the node outside of the protocol, for queries like metrics
+    With(Box<dyn FnOnce(&mut RaftNode) + Send>),
+    /// Stop for good after syncing storage, answering with our id, the peer clients left to close and whether the sync succeeded
+    Shutdown(oneshot::Sender<(NodeId, Vec<Peer>, Result<()>)>),
+    /// Stop right away as if the process died
+    #[cfg(test)]
+    Halt(oneshot::Sender<()>),
+}
+
+/// The task that owns the node, see [`Event`]
+struct Actor {
+    clock: Arc<dyn Clock>,
+    /// Lets the tasks making network calls post their results back
+    events: mpsc::UnboundedSender<Event>,
+    /// Members a client is being connected to
+    connecting: HashSet<Endpoint>,
+    /// The snapshot being written and who waits for it
+    snapshotting: Option<(JoinHandle<()>, Vec<Reply<u64>>)>,
+    /// Who asked for a snapshot while one was being written, served by the next one
+    snapshot_queued: Vec<Reply<u64>>,
+}
+
+impl Actor {
+    async fn run(mut self, mut node: RaftNode, mut events: mpsc::UnboundedReceiver<Event>) {
+        self.refresh_peers(&node);
+        while let Some(event) = events.recv().await {
+            node = match event {
+                Event::PreVote(req, reply) => {
+                    let resp = node
+                        .common_mut()
+                        .check_cluster(req.candidate_id.into(), &req.cluster_id, req.term, false)
+                        .map(|_| node.handle_pre_vote(&req));
+                    let _ = reply.send(resp);
+                    node
                 }
+                Event::RequestVote(req, reply) => answer(node, reply, req.candidate_id.into(), &req.cluster_id, req.term, false, |node| node.handle_request_vote(&req)),
+                Event::AppendEntries(req, reply) => answer(node, reply, req.leader_id.into(), &req.cluster_id, req.term, true, |node| node.handle_append_entries(&req)),
+                Event::InstallSnapshot(chunk, reply) => answer(node, reply, chunk.leader_id.into(), &chunk.cluster_id, chunk.term, true, |node| node.handle_install_snapshot(&chunk)),
+                Event::TimeoutNow(req, reply) => {
+                    let resp = node
+                        .common_mut()
+                        .check_cluster(req.leader_id.into(), &req.cluster_id, req.term, false)
+                        .map(|_| node.handle_timeout_now(&req));
+                    let _ = reply.send(resp);
+                    node
+                }
+                Event::Timeout => self.on_timeout(node),
+                Event::PreVoteWon(term) => {
+                    // Unless something happened while we were waiting for pre-votes
+                    let still_electing = matches!(node, RaftNode::Follower(_) | RaftNode::Candidate(_));
+                    if still_electing && node.current_term() + 1 == term { self.campaign(node) } else { node }
+                }
+                Event::Votes { term, responses } => self.on_votes(node, term, responses),
+                Event::Appended { term, peer, req, resp } => {
+                    if resp.term > term {
+                        logged(node.observe_term(resp.term), "step down")
+                    } else {
+                        if let RaftNode::Leader(leader) = &mut node
+                            && leader.state.term == term
+                            && let Err(e) = leader.on_append_response(&peer, &req, &resp)
+                        {
+                            error!("Failed to handle AppendEntries response of {}: {}", peer, e);
+                        }
+                        node
+                    }
+                }
+                Event::Replicate(done) => self.replicate(node, Some(done)),
+                Event::SnapshotSent { term, peer, result } => match &result {
+                    Ok((_, resp)) if resp.term > term => logged(node.observe_term(resp.term), "step down after snapshot transfer"),
+                    _ => {
+                        if let RaftNode::Leader(leader) = &mut node
+                            && leader.state.term == term
+                            && let Err(e) = leader.on_snapshot_sent(&peer, result)
+                        {
+                            error!("Failed to handle snapshot transfer to {}: {}", peer, e);
+                        }
+                        node
+                    }
+                },
+                Event::Connected(endpoint, client) => {
+                    self.connecting.remove(&endpoint);
+                    match client {
+                        // The member may have moved or left while connecting
+                        Ok(client) if node.common().meta.members().contains(&endpoint) => {
+                            node.common().remote_clients.entry(endpoint.id()).or_insert((endpoint, client));
+                        }
+                        Ok(_) => {}
+                        Err(e) => error!("Failed to init remote client for {}: {}", endpoint, e),
+                    }
+                    node
+                }
+                Event::Propose { entry_type, data, reply } => self.propose(node, entry_type, data, reply),
+                Event::UpdateMembers(endpoints, reply) => {
+                    let result = node.common_mut().meta.update_members(endpoints);
+                    if result.is_ok() {
+                        self.refresh_peers(&node);
+                    }
+                    let _ = reply.send(result);
+                    node
+                }
+                Event::GroupCommit => {
+                    node.common_mut().sync_scheduled = false;
+                    let result = match &mut node {
+                        RaftNode::Leader(leader) => leader.common.sync_log().and_then(|_| leader.advance_commit()),
+                        node => node.common_mut().sync_log(),
+                    };
+                    if let Err(e) = result {
+                        error!("Group commit failed: {}", e);
+                    }
+                    node
+                }
+                Event::SyncLog => {
+                    if let Err(e) = node.common_mut().sync_log() {
+                        error!("Periodic log sync failed: {}", e);
+                    }
+                    node
+                }
+                Event::Snapshot(reply) => {
+                    self.snapshot(&node, vec![reply]);
+                    node
+                }
+                Event::SnapshotTaken { index, term, result } => {
+                    let waiters = self.snapshotting.take().map(|(_, waiters)| waiters).unwrap_or_default();
+                    let result = result.and_then(|_| node.common_mut().compact_to(index, term));
+                    if let Err(e) = &result {
+                        error!("Snapshot at index {} failed: {}", index, e);
+                    }
+                    for waiter in waiters {
+                        let _ = waiter.send(result.as_ref().map(|_| index).map_err(|e| RuftError::Storage(e.to_string())));
+                    }
+                    let queued = std::mem::take(&mut self.snapshot_queued);
+                    if !queued.is_empty() {
+                        self.snapshot(&node, queued);
+                    }
+                    node
+                }
+                Event::With(f) => {
+                    f(&mut node);
+                    node
+                }
+                Event::Shutdown(reply) => {
+                    self.finish_snapshot().await;
+                    let _ = reply.send(stop(node));
+                    return;
+                }
+                #[cfg(test)]
+                Event::Halt(done) => {
+                    if let Some(timer) = &node.common().timer {
+                        timer.stop();
+                    }
+                    // A dead process writes nothing more, the next incarnation must not see the writer
+                    self.finish_snapshot().await;
+                    drop(node);
+                    let _ = done.send(());
+                    return;
+                }
+            };
+            if self.snapshotting.is_none() && node.common().snapshot_due() {
+                self.snapshot(&node, Vec::new());
             }
-            _ => CmdResp::NotLeader { leader: None },
         }
     }
+
+    /// Write a snapshot of the applied state on a blocking task, the node goes on meanwhile
+    ///
+    /// The log is compacted once [`Event::SnapshotTaken`] arrives, `waiters` are answered then.
+    fn snapshot(&mut self, node: &RaftNode, waiters: Vec<Reply<u64>>) {
+        if self.snapshotting.is_some() {
+            self.snapshot_queued.extend(waiters);
+            return;
+        }
+        let common = node.common();
+        let frozen = match common.freeze() {
+            Ok(Some(frozen)) => frozen,
+            Ok(None) => {
+                for waiter in waiters {
+                    let _ = waiter.send(Ok(common.log.snapshot_index()));
+                }
+                return;
+            }
+            Err(e) => {
+                error!("Failed to freeze the state machine: {}", e);
+                for waiter in waiters {
+                    let _ = waiter.send(Err(RuftError::InvalidState(e.to_string())));
+                }
+                return;
+            }
+        };
+        let (snapshots, events) = (common.snapshots.clone(), self.events.clone());
+        let task = tokio::task::spawn_blocking(move || {
+            let Frozen { index, term, members, view } = frozen;
+            let result = snapshots
+                .save(index, term, members, |w| view.snapshot(w))
+                .map(|_| ())
+                .map_err(|e| RuftError::Storage(format!("Failed to save snapshot at index {}: {}", index, e)));
+            let _ = events.send(Event::SnapshotTaken { index, term, result });
+        });
+        self.snapshotting = Some((task, waiters));
+    }
+
+    /// Wait for the snapshot being written, so it is complete or absent once the node is gone
+    async fn finish_snapshot(&mut self) {
+        if let Some((task, _)) = self.snapshotting.take() {
+            let _ = task.await;
+        }
+    }
+
+    fn on_timeout(&mut self, mut node: RaftNode) -> RaftNode {
+        // Follow members that moved or left since the last tick
+        self.refresh_peers(&node);
+        match &mut node {
+            RaftNode::Follower(follower) => {
+                // Stop redirecting clients to a leader we no longer hear from
+                follower.state.leader = None;
+                follower.common.set_leader(None);
+                // Handed leadership by the leader, which is still alive, so a pre-vote would fail
+                if std::mem::take(&mut follower.common.timeout_now) {
+                    self.campaign(node)
+                } else {
+                    info!("Heartbeat timeout, starting pre-vote");
+                    self.pre_vote(&node);
+                    node
+                }
+            }
+            RaftNode::Candidate(_) => {
+                info!("Election timeout, starting new election");
+                self.campaign(node)
+            }
+            RaftNode::Leader(_) => self.replicate(node, None),
+            // Learner does nothing on timeout
+            RaftNode::Learner(_) => node,
+        }
+    }
+
+    /// Ask the peers whether an election for the next term could succeed, our term stays untouched
+    fn pre_vote(&self, node: &RaftNode) {
+        let common = node.common();
+        let req = PreVoteRequest {
+            term: node.current_term() + 1,
+            candidate_id: common.endpoint.id().into(),
+            last_log_index: common.last_log_index(),
+            last_log_term: common.last_log_term(),
+            cluster_id: common.cluster_id(),
+        };
+        let (peers, quorum, timeout) = (common.peers(), common.quorum(), common.config.rpc_timeout());
+        let (clock, events) = (self.clock.clone(), self.events.clone());
+        tokio::spawn(async move {
+            let responses = broadcast(&clock, peers, req.clone(), timeout, |client, req| async move { client.pre_vote(req).await }).await;
+            let granted = 1 + responses.iter().filter(|(_, r)| r.vote_granted).count();
+            if granted < quorum {
+                debug!("Pre-vote for term {} got {}/{} votes", req.term, granted, quorum);
+            } else {
+                let _ = events.send(Event::PreVoteWon(req.term));
+            }
+        });
+    }
+
+    /// Become candidate of a new term and request the votes of the peers
+    fn campaign(&self, node: RaftNode) -> RaftNode {
+        let (node, started) = node.transition_candidate();
+        if let Err(e) = started {
+            error!("Failed to start an election: {}", e);
+            return node;
+        }
+        let RaftNode::Candidate(candidate) = &node else { return node };
+        let common = &candidate.common;
+        let req = RequestVoteRequest {
+            term: candidate.state.term,
+            candidate_id: common.endpoint.id().into(),
+            last_log_index: common.last_log_index(),
+            last_log_term: common.last_log_term(),
+            cluster_id: common.cluster_id(),
+        };
+        let (peers, timeout) = (common.peers(), common.config.rpc_timeout());
+        let (clock, events) = (self.clock.clone(), self.events.clone());
+        info!("Requesting votes for term {}", req.term);
+        tokio::spawn(async move {
+            let responses = broadcast(&clock, peers, req.clone(), timeout, |client, req| async move { client.request_vote(req).await }).await;
+            let _ = events.send(Event::Votes { term: req.term, responses });
+        });
+        node
+    }
+
+    fn on_votes(&self, mut node: RaftNode, term: u64, responses: Vec<(Endpoint, RequestVoteResponse)>) -> RaftNode {
+        if let Some(higher) = responses.iter().map(|(_, r)| r.term).filter(|t| *t > term).max() {
+            return logged(node.observe_term(higher), "step down");
+        }
+        let RaftNode::Candidate(candidate) = &mut node else { return node };
+        if candidate.state.term != term {
+            return node;
+        }
+
+        let voters: Vec<NodeId> = std::iter::once(candidate.common.endpoint.id())
+            .chain(responses.iter().filter(|(_, r)| r.vote_granted).map(|(peer, _)| peer.id()))
+            .collect();
+        let granted = voters.len();
+        let quorum = candidate.common.quorum();
+        candidate.state.votes_received = granted as u64;
+        if granted < quorum {
+            debug!("Election for term {} got {}/{} votes", term, granted, quorum);
+            return node;
+        }
+        let (node, won) = node.transition_leader(voters);
+        if let Err(e) = won {
+            error!("Failed to become leader of term {}: {}", term, e);
+            return node;
+        }
+        node.common().restart_timer();
+        self.replicate(node, None)
+    }
+
+    /// Send AppendEntries to every follower, doubling as the heartbeat when there is nothing new
+    ///
+    /// Answers come back as [`Event::Appended`], `done` is told once all of them are posted.
+    fn replicate(&self, mut node: RaftNode, done: Option<oneshot::Sender<()>>) -> RaftNode {
+        let RaftNode::Leader(leader) = &mut node else { return node };
+        // A single node cluster commits without any response
+        if let Err(e) = leader.advance_commit() {
+            error!("Failed to advance the commit index: {}", e);
+            return node;
+        }
+        let mut requests = Vec::new();
+        for (peer, client) in leader.common.peers() {
+            match leader.append_request(&peer) {
+                Some(req) => requests.push((req, peer, client)),
+                // The entries the peer needs are compacted, it gets the snapshot instead
+                None => {
+                    if let Some(offset) = leader.begin_snapshot_transfer(&peer) {
+                        self.send_snapshot(leader, (peer, client), offset);
+                    }
+                }
+            }
+        }
+
+        let (term, timeout) = (leader.state.term, leader.common.config.rpc_timeout());
+        debug!("Replicating to {} peers for term {}", requests.len(), term);
+        let (clock, events) = (self.clock.clone(), self.events.clone());
+        tokio::spawn(async move {
+            let mut tasks = JoinSet::new();
+            for (req, peer, client) in requests {
+                let clock = clock.clone();
+                tasks.spawn(async move {
+                    let resp = clock::timeout(clock.as_ref(), timeout, client.append_entries(req.clone())).await;
+                    (peer, req, resp)
+                });
+            }
+
+            while let Some(joined) = tasks.join_next().await {
+                match joined {
+                    Ok((peer, req, Some(Ok(resp)))) => {
+                        let _ = events.send(Event::Appended { term, peer, req, resp });
+                    }
+                    Ok((peer, _, Some(Err(e)))) => debug!("AppendEntries to {} failed: {}", peer, e),
+                    Ok((peer, _, None)) => debug!("AppendEntries to {} timed out", peer),
+                    Err(e) => error!("AppendEntries task failed: {}", e),
+                }
+            }
+            // Events are handled in order, so whatever the waiter sends next sees every answer applied
+            if let Some(done) = done {
+                let _ = done.send(());
+            }
+        });
+        node
+    }
+
+    /// Stream our snapshot to a follower whose next entry is compacted, resuming at `offset`
+    fn send_snapshot(&self, leader: &NodeData<Leader>, peer: Peer, offset: u64) {
+        let common = &leader.common;
+        let term = leader.state.term;
+        let header = InstallSnapshotChunk {
+            term,
+            leader_id: common.endpoint.id().into(),
+            cluster_id: common.cluster_id(),
+            ..Default::default()
+        };
+        let snapshots = common.snapshots.clone();
+        let (chunk_bytes, timeout) = (common.config.snapshot_chunk_bytes.max(1), snapshot_chunk_timeout(&common.config));
+        let (clock, events) = (self.clock.clone(), self.events.clone());
+        tokio::spawn(async move {
+            let result = stream_snapshot(clock, snapshots, header, chunk_bytes, timeout, &peer, offset).await;
+            let _ = events.send(Event::SnapshotSent { term, peer: peer.0, result });
+        });
+    }
+
+    /// Append a proposed entry and replicate it right away
+    fn propose(&self, mut node: RaftNode, entry_type: EntryType, data: Vec<u8>, reply: oneshot::Sender<std::result::Result<Admitted, CmdResp>>) -> RaftNode {
+        let proposal = node.propose(entry_type, data).map(|(index, commit_rx)| (index, commit_rx, submit_timeout(&node.common().config)));
+        let appended = proposal.is_ok();
+        let _ = reply.send(proposal);
+        if !appended {
+            return node;
+        }
+        if let Some(window) = node.common_mut().schedule_sync() {
+            let (clock, events) = (self.clock.clone(), self.events.clone());
+            tokio::spawn(async move {
+                clock.sleep(window).await;
+                let _ = events.send(Event::GroupCommit);
+            });
+        }
+        self.replicate(node, None)
+    }
+
+    /// Create clients for members that have none yet, connecting to their current address
+    ///
+    /// Clients of former members are dropped, a member that moved gets a new client.
+    /// Closing and connecting run in tasks of their own, new clients come back as [`Event::Connected`].
+    fn refresh_peers(&mut self, node: &RaftNode) {
+        let common = node.common();
+        let (moved, missing) = common.prune_peers();
+        for (endpoint, client) in moved {
+            info!("Reconnecting to member {} at its new address", endpoint.id());
+            tokio::spawn(async move {
+                if let Err(e) = client.close().await {
+                    warn!("Failed to close client of {}: {}", endpoint, e);
+                }
+            });
+        }
+
+        for endpoint in missing {
+            if !self.connecting.insert(endpoint.clone()) {
+                continue;
+            }
+            let (transport, events) = (common.transport.clone(), self.events.clone());
+            tokio::spawn(async move {
+                let client = transport.connect(&endpoint).await;
+                let _ = events.send(Event::Connected(endpoint, client));
+            });
+        }
+    }
+}
+
+/// Refuse an RPC of another cluster, otherwise let `step` handle it, then reply
+fn answer<R>(mut node: RaftNode, reply: Reply<R>, from: NodeId, cluster_id: &[u8], term: u64, adopt: bool, step: impl FnOnce(RaftNode) -> (RaftNode, Result<R>)) -> RaftNode {
+    let (node, resp) = match node.common_mut().check_cluster(from, cluster_id, term, adopt) {
+        Ok(()) => step(node),
+        Err(e) => (node, Err(e)),
+    };
+    let _ = reply.send(resp);
+    node
+}
+
+/// Keep the node a step hands back, logging why the step failed
+fn logged(step: (RaftNode, Result<()>), action: &str) -> RaftNode {
+    let (node, result) = step;
+    if let Err(e) = result {
+        error!("Failed to {}: {}", action, e);
+    }
+    node
+}
+
+/// Stop the timer and sync storage, returning our id, the peer clients to close and how syncing went
+///
+/// The node is dropped on return, which fails the proposals still waiting for their commit.
+/// A failed sync stops the node all the same.
+fn stop(node: RaftNode) -> (NodeId, Vec<Peer>, Result<()>) {
+    let mut common = node.into_common();
+    if let Some(timer) = common.timer.take() {
+        timer.stop();
+    }
+    let peers = common.peers();
+    common.remote_clients.clear();
+    let synced = common.sync_log().and_then(|_| common.meta.flush());
+    // Dropping the commit watcher wakes the proposals still waiting, they fail as shutting down
+    common.proposals.clear();
+    (common.endpoint.id(), peers, synced)
 }
 
-/// Wrapper to manage Node with proper locking
+/// Handle to a node running in a task of its own
+///
+/// The task owns the [`RaftNode`] and handles one [`Event`] at a time. Methods
+/// and RPC handlers send it events and wait for the answer, they never wait
+/// for network calls of other events.
 pub struct Node {
-    // Option allows taking ownership temporarily during state transitions
-    inner: Mutex<Option<RaftNode>>,
+    events: mpsc::UnboundedSender<Event>,
+    /// The node and its pending events until the first event spawns the task
+    idle: std::sync::Mutex<Option<(RaftNode, mpsc::UnboundedReceiver<Event>)>>,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+    /// Tells the RPC server to stop, and the server task to wait for once told
+    shutdown_tx: watch::Sender<bool>,
+    server: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Address the RPC server is bound to, once started
+    local_addr: std::sync::Mutex<Option<SocketAddr>>,
 }
 
 impl Node {
-    pub fn new(endpoint: Endpoint, config: Config) -> Result<Self> {
-        let node = RaftNode::new(endpoint, config)?;
-        Ok(Node { inner: Mutex::new(Some(node)) })
+    pub fn new(endpoint: Endpoint, config: Config, transport: Arc<dyn Transport>, clock: Arc<dyn Clock>, random: Arc<dyn Random>, sm: Box<dyn Sm>) -> Result<Self> {
+        let node = RaftNode::new(endpoint, config, transport, random.clone(), sm)?;
+        let (events, events_rx) = mpsc::unbounded_channel();
+        Ok(Node {
+            events,
+            idle: std::sync::Mutex::new(Some((node, events_rx))),
+            clock,
+            random,
+            shutdown_tx: watch::channel(false).0,
+            server: std::sync::Mutex::new(None),
+            local_addr: std::sync::Mutex::new(None),
+        })
     }
 
     pub async fn start(self: Arc<Self>) -> Result<()> {
-        // Initialize RPC clients
-        {
-            let guard = self.inner.lock().await;
-            if let Some(node) = guard.as_ref() {
-                node.init_rpc_clients().await?;
-            }
-        }
+        let (transport, endpoint, listen_addr, durability) = self
+            .with(|node| {
+                let common = node.common();
+                (common.transport.clone(), common.endpoint.clone(), common.config.listen_addr, common.config.durability)
+            })
+            .await?;
 
         // Start RPC server
-        let _server_handle = tokio::spawn(run_server(self.clone()));
+        let handler: Arc<dyn RaftRpcHandler> = self.clone();
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        let shutdown = Box::pin(async move {
+            let _ = shutdown_rx.wait_for(|stop| *stop).await;
+        });
+        let serving = transport.serve(endpoint.clone(), listen_addr, handler, shutdown).await?;
+        if let Some(addr) = serving.local_addr {
+            info!("Node {} listens on {}", endpoint.id(), addr);
+        }
+        *self.local_addr.lock().unwrap() = serving.local_addr;
+        let server = tokio::spawn(async move {
+            if let Err(e) = serving.run.await {
+                error!("Rpc server stopped: {}", e);
+            }
+        });
+        *self.server.lock().unwrap() = Some(server);
 
-        // Start timer for heartbeat/election
-        self.start_timer().await;
+        // Start timer for heartbeat/election, RPC handlers reset it through the node
+        let timer = self.start_timer();
+        self.with(move |node| node.common_mut().timer = Some(timer)).await?;
+
+        if let Durability::Interval(interval) = durability {
+            tokio::spawn(self.clone().sync_every(interval));
+        }
 
         Ok(())
     }
 
-    async fn start_timer(self: &Arc<Self>) {
+    fn start_timer(self: &Arc<Self>) -> RepeatTimerHandle {
         let node_for_delay = self.clone();
         let node_for_task = self.clone();
 
-        let timer = RepeatTimer::from_fns(
+        RepeatTimer::from_fns(
             "raft_timer".to_string(),
             move || {
                 let node = node_for_delay.clone();
                 Box::pin(async move {
-                    let guard = node.inner.lock().await;
-                    if let Some(raft_node) = guard.as_ref() {
-                        match raft_node {
-                            RaftNode::Candidate(_) => Duration::from_millis(rand::thread_rng().gen_range(150..300)),
-                            RaftNode::Follower(_) | RaftNode::Learner(_) => Duration::from_millis(raft_node.common().config.heartbeat_interval_millis + 50),
+                    let random = node.random.clone();
+                    let delay = node
+                        .with(move |raft_node| match raft_node {
+                            RaftNode::Follower(follower) if follower.common.timeout_now => Duration::ZERO,
+                            RaftNode::Follower(_) | RaftNode::Candidate(_) | RaftNode::Learner(_) => random_election_timeout(&raft_node.common().config, random.as_ref()),
                             RaftNode::Leader(_) => Duration::from_millis(raft_node.common().config.heartbeat_interval_millis),
-                        }
-                    } else {
-                        Duration::from_millis(1000)
-                    }
+                        })
+                        .await;
+                    delay.unwrap_or(Duration::from_millis(1000))
                 })
             },
             move || {
                 let node = node_for_task.clone();
+                // The node handles the tick in its own task, the timer goes on right away
                 Box::pin(async move {
-                    let mut guard = node.inner.lock().await;
-
-                    // Take ownership of the node for state transitions
-                    if let Some(current_node) = guard.take() {
-                        match current_node {
-                            RaftNode::Candidate(_) => {
-                                info!("Election timeout, starting new election");
-                                // TODO: Send RequestVote RPCs
-                                *guard = Some(current_node);
-                            }
-                            RaftNode::Follower(_) => {
-                                info!("Heartbeat timeout, becoming candidate");
-                                match current_node.transition_candidate() {
-                                    Ok(new_node) => {
-                                        *guard = Some(new_node);
-                                    }
-                                    Err(e) => {
-                                        error!("Failed to start election: {}", e);
-                                        // Can't restore current_node after move, create new follower
-                                        // This is unlikely to happen as start_election rarely fails
-                                        *guard = None;
-                                    }
-                                }
-                            }
-                            RaftNode::Leader(_) => {
-                                // Send heartbeat
-                                info!("Sending heartbeat");
-                                // TODO: Send AppendEntries RPCs
-                                *guard = Some(current_node);
-                            }
-                            RaftNode::Learner(_) => {
-                                // Learner does nothing on timeout
-                                *guard = Some(current_node);
-                            }
-                        }
-                    }
+                    let _ = node.send(Event::Timeout);
                 })
             },
         )
-        .spawn();
+        .with_clock(self.clock.clone())
+        .spawn()
+    }
 
-        // Store timer in the node
-        // Note: We need to store it somewhere accessible, for now just keep it alive
-        std::mem::forget(timer);
+    /// Ask for a log fsync every `interval` until the node stops
+    async fn sync_every(self: Arc<Self>, interval: Duration) {
+        loop {
+            self.clock.sleep(interval).await;
+            if self.send(Event::SyncLog).is_err() {
+                return;
+            }
+        }
     }
 
-    pub async fn update_members(&self, endpoints: Vec<Endpoint>) -> Result<()> {
-        let mut guard = self.inner.lock().await;
-        if let Some(node) = guard.as_mut() {
-            node.update_members(endpoints).await
-        } else {
-            Err(RuftError::InvalidState("Node is shutting down".into()))
+    /// Hand an event to the node's task, the first event spawns the task
+    fn send(&self, event: Event) -> Result<()> {
+        let idle = self.idle.lock().unwrap().take();
+        if let Some((node, events_rx)) = idle {
+            let actor = Actor {
+                clock: self.clock.clone(),
+                events: self.events.clone(),
+                connecting: HashSet::new(),
+                snapshotting: None,
+                snapshot_queued: Vec::new(),
+            };
+            tokio::spawn(actor.run(node, events_rx));
         }
+        self.events.send(event).map_err(|_| RuftError::InvalidState("Node is shutting down".into()))
+    }
+
+    /// Send the event `event` makes around a reply channel and wait for the answer
+    async fn call<R>(&self, event: impl FnOnce(Reply<R>) -> Event) -> Result<R> {
+        let (tx, rx) = oneshot::channel();
+        self.send(event(tx))?;
+        rx.await.map_err(|_| RuftError::InvalidState("Node is shutting down".into()))?
+    }
+
+    /// Run `f` on the node in its task and return what it returns
+    async fn with<R: Send + 'static>(&self, f: impl FnOnce(&mut RaftNode) -> R + Send + 'static) -> Result<R> {
+        self.call(|reply| {
+            Event::With(Box::new(move |node| {
+                let _ = reply.send(Ok(f(node)));
+            }))
+        })
+        .await
+    }
+
+    pub async fn update_members(&self, endpoints: Vec<Endpoint>) -> Result<()> {
+        self.call(|reply| Event::UpdateMembers(endpoints, reply)).await
     }
 
     pub async fn submit(&self, cmd: CmdReq) -> CmdResp {
-        let guard = self.inner.lock().await;
-        match guard.as_ref() {
-            Some(node) => node.submit(cmd).await,
-            None => CmdResp::Rejected {
-                code: crate::rpc::command::ErrorCode::Internal,
+        self.submit_entry(EntryType::Command, cmd.data.to_vec()).await
+    }
+
+    /// Move a member to the address of `endpoint` through the log, the member keeps its id
+    pub async fn update_address(&self, endpoint: Endpoint) -> CmdResp {
+        let id = endpoint.id();
+        let known = self.with(move |node| node.common().meta.members().iter().any(|m| m.id() == id)).await.unwrap_or(false);
+        if !known {
+            return CmdResp::Rejected {
+                code: ErrorCode::InvalidCommand,
+                message: format!("Node {} is not a member", endpoint.id()),
+            };
+        }
+        self.submit_entry(EntryType::Address, Member::from(&endpoint).encode_to_vec()).await
+    }
+
+    /// Propose an entry and wait until it is applied
+    async fn submit_entry(&self, entry_type: EntryType, data: Vec<u8>) -> CmdResp {
+        let (reply, admitted) = oneshot::channel();
+        let admitted = match self.send(Event::Propose { entry_type, data, reply }) {
+            Ok(()) => admitted.await.ok(),
+            Err(_) => None,
+        };
+        let (index, mut commit_rx, timeout) = match admitted {
+            Some(Ok(admitted)) => admitted,
+            Some(Err(resp)) => return resp,
+            None => {
+                return CmdResp::Rejected {
+                    code: ErrorCode::Internal,
+                    message: "Node is shutting down".into(),
+                };
+            }
+        };
+
+        // Drop the borrowed commit index right away, holding it across an await would make submit non-Send
+        let committed = clock::timeout(self.clock.as_ref(), timeout, async { commit_rx.wait_for(|commit| *commit >= index).await.map(|_| ()) }).await;
+
+        let Ok((proposal, leader)) = self.with(move |node| (node.common_mut().proposals.remove(&index), node.leader())).await else {
+            return CmdResp::Rejected {
+                code: ErrorCode::Internal,
                 message: "Node is shutting down".into(),
-            },
+            };
+        };
+        match committed {
+            Some(Ok(_)) => {}
+            Some(Err(_)) => {
+                return CmdResp::Rejected {
+                    code: ErrorCode::Internal,
+                    message: "Node is shutting down".into(),
+                };
+            }
+            None => {
+                return CmdResp::Rejected {
+                    code: ErrorCode::Timeout,
+                    message: format!("Entry {} was not committed in time", index),
+                };
+            }
+        }
+
+        // The index is committed, but it may hold an entry of a newer leader that replaced ours
+        match proposal {
+            Some(proposal) if proposal.applied => CmdResp::Success { data: proposal.result },
+            _ => CmdResp::NotLeader { leader },
+        }
+    }
+
+    /// Snapshot the state machine now and compact the log, returning the snapshot index
+    pub async fn snapshot(&self) -> Result<u64> {
+        self.call(Event::Snapshot).await
+    }
+
+    pub async fn metrics(&self) -> Result<Metrics> {
+        self.with(|node| node.metrics()).await
+    }
+
+    pub async fn commit_index(&self) -> u64 {
+        self.with(|n| n.common().commit_index()).await.unwrap_or(0)
+    }
+
+    pub async fn last_log_index(&self) -> u64 {
+        self.with(|n| n.common().last_log_index()).await.unwrap_or(0)
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.lock().unwrap()
+    }
+
+    pub async fn leader(&self) -> Option<Endpoint> {
+        self.with(|n| n.leader()).await.ok().flatten()
+    }
+
+    /// Wait until a leader is known, for at most `timeout`
+    pub async fn wait_for_leader(&self, timeout: Duration) -> Result<Endpoint> {
+        let mut leader_rx = self.with(|node| node.common().leader_tx.subscribe()).await?;
+        // Clone the leader out of the borrow, holding it across an await would make this non-Send
+        let leader = clock::timeout(self.clock.as_ref(), timeout, async { leader_rx.wait_for(Option::is_some).await.map(|leader| leader.clone()) }).await;
+        match leader {
+            Some(Ok(Some(leader))) => Ok(leader),
+            Some(_) => Err(RuftError::InvalidState("Node is shutting down".into())),
+            None => Err(RuftError::InvalidState(format!("No leader known after {:?}", timeout))),
+        }
+    }
+
+    /// Committed entries still in the local log, those covered by the snapshot are gone
+    #[cfg(test)]
+    pub(crate) async fn committed_entries(&self) -> Vec<LogEntry> {
+        self.with(|n| {
+            let log = &n.common().log;
+            log.entries_from(1, (n.common().commit_index() - log.snapshot_index()) as usize)
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Stop the node for good: timer, peer clients and RPC server, after syncing its storage
+    ///
+    /// A leader first hands leadership to its most up to date follower, unless
+    /// [`crate::ConfigBuilder::transfer_leadership_on_shutdown`] turned that off.
+    /// Proposals still waiting for their commit fail.
+    pub async fn shutdown(&self) -> Result<()> {
+        let transfer = self
+            .with(|node| node.common().config.transfer_leadership_on_shutdown && matches!(node, RaftNode::Leader(_)))
+            .await
+            .map_err(|_| RuftError::InvalidState("Node is already shut down".into()))?;
+        if transfer && let Err(e) = self.transfer_leadership().await {
+            warn!("Failed to hand over leadership: {}", e);
+        }
+
+        let (reply, stopped) = oneshot::channel();
+        self.send(Event::Shutdown(reply)).map_err(|_| RuftError::InvalidState("Node is already shut down".into()))?;
+        // The clients and the server go even if storage failed, the error is reported after
+        let synced = match stopped.await {
+            Ok((id, peers, synced)) => {
+                for (peer, client) in peers {
+                    if let Err(e) = client.close().await {
+                        warn!("Failed to close client of {}: {}", peer, e);
+                    }
+                }
+                synced.map(|_| id)
+            }
+            Err(_) => Err(RuftError::InvalidState("Node stopped without syncing its storage".into())),
+        };
+
+        self.shutdown_tx.send_replace(true);
+        let server = self.server.lock().unwrap().take();
+        if let Some(server) = server {
+            let _ = server.await;
+        }
+        let id = synced?;
+        info!("Node {} is shut down", id);
+        Ok(())
+    }
+
+    /// Bring the most up to date follower level with our log and let it start an election
+    async fn transfer_leadership(&self) -> Result<()> {
+        let leading = self
+            .with(|node| match node {
+                RaftNode::Leader(leader) => Some((leader.state.term, leader.common.endpoint.id(), leader.common.cluster_id(), election_timeout(&leader.common.config))),
+                _ => None,
+            })
+            .await?;
+        let Some((term, leader_id, cluster_id, timeout)) = leading else { return Ok(()) };
+        let caught_up = clock::timeout(self.clock.as_ref(), timeout, async {
+            loop {
+                // `None` if there is nobody to hand over to, the best follower once it has our whole log
+                let best = self
+                    .with(|node| {
+                        let RaftNode::Leader(leader) = node else { return None };
+                        let matched = |peer: &Endpoint| leader.state.match_index.get(&peer.id()).copied().unwrap_or(0);
+                        let best = leader.common.peers().into_iter().max_by_key(|(peer, _)| matched(peer))?;
+                        let ready = matched(&best.0) >= leader.common.last_log_index();
+                        Some(ready.then(|| (best, leader.common.config.rpc_timeout())))
+                    })
+                    .await?;
+                match best {
+                    None => return Ok(None),
+                    Some(Some(target)) => return Ok(Some(target)),
+                    Some(None) => {}
+                }
+                let (done, replicated) = oneshot::channel();
+                self.send(Event::Replicate(done))?;
+                // Dropped without an answer once we are no longer leader, the next look tells
+                let _ = replicated.await;
+            }
+        })
+        .await;
+        let ((peer, client), timeout) = match caught_up {
+            Some(Ok(Some(target))) => target,
+            Some(Ok(None)) => return Ok(()),
+            Some(Err(e)) => return Err(e),
+            None => return Err(RuftError::InvalidState(format!("No follower caught up with the log within {:?}", timeout))),
+        };
+
+        let req = TimeoutNowRequest {
+            term,
+            leader_id: leader_id.into(),
+            cluster_id,
+        };
+        match clock::timeout(self.clock.as_ref(), timeout, client.timeout_now(req)).await {
+            Some(Ok(resp)) if resp.accepted => {
+                info!("Handed leadership of term {} to {}", term, peer);
+                Ok(())
+            }
+            Some(Ok(_)) => Err(RuftError::InvalidState(format!("{} refused to take over leadership", peer))),
+            Some(Err(e)) => Err(e),
+            None => Err(RuftError::InvalidState(format!("{} did not answer the leadership handover", peer))),
+        }
+    }
+
+    /// Stop the node abruptly as if its process died, nothing is flushed or handed over
+    #[cfg(test)]
+    pub(crate) async fn halt(&self) {
+        let (done, halted) = oneshot::channel();
+        if self.send(Event::Halt(done)).is_ok() {
+            let _ = halted.await;
         }
     }
 
     pub async fn current_term(&self) -> u64 {
-        let guard = self.inner.lock().await;
-        guard.as_ref().map(|n| n.current_term()).unwrap_or(0)
+        self.with(|n| n.current_term()).await.unwrap_or(0)
     }
 
     pub async fn state_name(&self) -> String {
-        let guard = self.inner.lock().await;
-        guard.as_ref().map(|n| n.state_name().to_string()).unwrap_or_else(|| "Shutdown".to_string())
+        self.with(|n| n.state_name().to_string()).await.unwrap_or_else(|_| "Shutdown".to_string())
+    }
+}
+
+#[tonic::async_trait]
+impl RaftRpcHandler for Node {
+    async fn handle_pre_vote(&self, req: PreVoteRequest) -> Result<PreVoteResponse> {
+        self.call(|reply| Event::PreVote(req, reply)).await
+    }
+
+    async fn handle_request_vote(&self, req: RequestVoteRequest) -> Result<RequestVoteResponse> {
+        self.call(|reply| Event::RequestVote(req, reply)).await
+    }
+
+    async fn handle_append_entries(&self, req: AppendEntriesRequest) -> Result<AppendEntriesResponse> {
+        self.call(|reply| Event::AppendEntries(req, reply)).await
+    }
+
+    async fn handle_install_snapshot(&self, chunk: InstallSnapshotChunk) -> Result<InstallSnapshotResponse> {
+        self.call(|reply| Event::InstallSnapshot(chunk, reply)).await
+    }
+
+    async fn handle_timeout_now(&self, req: TimeoutNowRequest) -> Result<TimeoutNowResponse> {
+        self.call(|reply| Event::TimeoutNow(req, reply)).await
+    }
+}
+
+/// Random version 4 id for a new cluster, drawn from `random` so simulations stay reproducible
+fn random_uuid(random: &dyn Random) -> Uuid {
+    let (high, low) = (random.gen_range(0..u64::MAX), random.gen_range(0..u64::MAX));
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&high.to_le_bytes());
+    bytes[8..].copy_from_slice(&low.to_le_bytes());
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+/// How long a follower waits for the leader before it starts an election, also the time a leadership handover may take
+fn election_timeout(config: &Config) -> Duration {
+    Duration::from_millis(config.heartbeat_interval_millis + 50)
+}
+
+/// Election timeout of one round, drawn from `[T, 2T)` with `T` the [`election_timeout`], so nodes rarely time out together
+fn random_election_timeout(config: &Config, random: &dyn Random) -> Duration {
+    let base = election_timeout(config).as_millis() as u64;
+    Duration::from_millis(base + random.gen_range(0..base))
+}
+
+/// Upper bound for shipping one snapshot chunk, which is far larger than a heartbeat
+fn snapshot_chunk_timeout(config: &Config) -> Duration {
+    config.rpc_timeout() * 10
+}
+
+/// How long a proposal may wait for its entry to be committed
+fn submit_timeout(config: &Config) -> Duration {
+    Duration::from_millis(config.heartbeat_interval_millis * 10)
+}
+
+/// Send the same request to every peer concurrently and collect the successful responses by peer
+async fn broadcast<Req, Resp, F, Fut>(clock: &Arc<dyn Clock>, peers: Vec<(Endpoint, Arc<dyn RaftRpcClient>)>, req: Req, timeout: Duration, call: F) -> Vec<(Endpoint, Resp)>
+where
+    Req: Clone + Send + 'static,
+    Resp: Send + 'static,
+    F: Fn(Arc<dyn RaftRpcClient>, Req) -> Fut,
+    Fut: Future<Output = Result<Resp>> + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for (endpoint, client) in peers {
+        let fut = call(client, req.clone());
+        let clock = clock.clone();
+        tasks.spawn(async move { (endpoint, clock::timeout(clock.as_ref(), timeout, fut).await) });
+    }
+
+    let mut responses = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((endpoint, Some(Ok(resp)))) => responses.push((endpoint, resp)),
+            Ok((endpoint, Some(Err(e)))) => debug!("Rpc to {} failed: {}", endpoint, e),
+            Ok((endpoint, None)) => debug!("Rpc to {} timed out", endpoint),
+            Err(e) => error!("Rpc task failed: {}", e),
+        }
+    }
+    responses
+}
+
+/// Stream the newest snapshot to `peer` from `offset`, returning its index and the answer to the last chunk the follower handled
+///
+/// `header` carries the term, leader and cluster every chunk is sent with.
+async fn stream_snapshot(
+    clock: Arc<dyn Clock>,
+    snapshots: SnapshotStore,
+    header: InstallSnapshotChunk,
+    chunk_bytes: usize,
+    timeout: Duration,
+    (peer, client): &Peer,
+    offset: u64,
+) -> Result<(u64, InstallSnapshotResponse)> {
+    // The open file keeps this snapshot readable even if a newer one replaces it meanwhile
+    let mut reader = snapshots.reader().map_err(|e| RuftError::Storage(format!("Failed to open snapshot: {}", e)))?;
+    let manifest = reader.manifest().clone();
+    let index = manifest.index;
+    info!("Sending snapshot at index {} to {} from offset {}", index, peer, offset);
+
+    let (tx, rx) = mpsc::channel(1);
+    let chunk_clock = clock.clone();
+    let produce = async move {
+        let mut offset = offset.min(manifest.size);
+        loop {
+            let data = reader
+                .read_at(offset, chunk_bytes)
+                .map_err(|e| RuftError::Storage(format!("Failed to read snapshot at offset {}: {}", offset, e)))?;
+            let len = data.len() as u64;
+            let done = offset + len >= manifest.size;
+            let chunk = InstallSnapshotChunk {
+                last_included_index: manifest.index,
+                last_included_term: manifest.term,
+                offset,
+                checksum: crc32c(&data),
+                data,
+                done,
+                members: if done { manifest.members.iter().map(Member::from).collect() } else { vec![] },
+                ..header.clone()
+            };
+            // A chunk not taken in time means the follower or the link is stuck
+            match clock::timeout(chunk_clock.as_ref(), timeout, tx.send(chunk)).await {
+                Some(Ok(())) if !done => offset += len,
+                // All sent, or the stream stopped early and its response says why
+                Some(_) => return Ok(()),
+                None => return Err(RuftError::Rpc(tonic::Status::deadline_exceeded(format!("Snapshot chunk at offset {} was not taken in time", offset)))),
+            }
+        }
+    };
+
+    let transfer = client.install_snapshot(rx);
+    tokio::pin!(transfer);
+    tokio::select! {
+        resp = &mut transfer => return resp.map(|resp| (index, resp)),
+        produced = produce => produced?,
     }
+    // Every chunk is out, wait for the answer to the last one
+    let resp = clock::timeout(clock.as_ref(), timeout, transfer)
+        .await
+        .ok_or_else(|| RuftError::Rpc(tonic::Status::deadline_exceeded(format!("{} did not confirm the snapshot in time", peer))))??;
+    Ok((index, resp))
 }
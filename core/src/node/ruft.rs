@@ -1,8 +1,11 @@
-use crate::Config;
 use crate::node::node::Node;
-use crate::rpc::Endpoint;
 use crate::rpc::command::{CmdReq, CmdResp};
+use crate::rpc::{Endpoint, GrpcTransport, PeerTls, Transport};
+use crate::sm::NoopSm;
+use crate::{Clock, Config, Metrics, Random, Sm, ThreadRandom, TokioClock};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 /// Main entry point for Raft consensus
 ///
@@ -13,6 +16,8 @@ use std::sync::Arc;
 /// - Graceful shutdown
 pub struct Ruft {
     inner: Arc<Node>,
+    /// Certificates of the gRPC transport built from [`Config::tls`]
+    tls: Option<Arc<PeerTls>>,
 }
 
 impl Ruft {
@@ -22,8 +27,26 @@ impl Ruft {
     /// * `endpoint` - Network endpoint for this node
     /// * `config` - Configuration parameters
     pub fn new(endpoint: Endpoint, config: Config) -> crate::Result<Self> {
-        let node = Node::new(endpoint, config)?;
-        Ok(Ruft { inner: Arc::new(node) })
+        Self::builder(endpoint, config).build()
+    }
+
+    /// Create a new Raft node talking to its peers through a custom transport
+    ///
+    /// Use [`crate::rpc::LocalTransport`] to run a whole cluster inside one process.
+    pub fn with_transport(endpoint: Endpoint, config: Config, transport: Arc<dyn Transport>) -> crate::Result<Self> {
+        Self::builder(endpoint, config).transport(transport).build()
+    }
+
+    /// Create a builder to replace the transport, clock, randomness or state machine of a node
+    pub fn builder(endpoint: Endpoint, config: Config) -> RuftBuilder {
+        RuftBuilder {
+            endpoint,
+            config,
+            transport: None,
+            clock: Arc::new(TokioClock),
+            random: Arc::new(ThreadRandom),
+            sm: Box::new(NoopSm),
+        }
     }
 
     /// Start the Raft node
@@ -52,6 +75,15 @@ impl Ruft {
         self.inner.update_members(endpoints).await
     }
 
+    /// Move the member with the id of `endpoint` to its host and port
+    ///
+    /// The change goes through the log like a command, so it must be made on the
+    /// leader; elsewhere it returns NotLeader. Once committed every node connects
+    /// to the new address, the member keeps its id, votes and replication state.
+    pub async fn update_address(&self, endpoint: Endpoint) -> CmdResp {
+        self.inner.update_address(endpoint).await
+    }
+
     /// Get the current term
     pub async fn current_term(&self) -> u64 {
         self.inner.current_term().await
@@ -62,20 +94,147 @@ impl Ruft {
         self.inner.state_name().await
     }
 
+    /// Get the highest log index known to be committed
+    pub async fn commit_index(&self) -> u64 {
+        self.inner.commit_index().await
+    }
+
+    /// Get the index of the last entry in the local log
+    pub async fn last_log_index(&self) -> u64 {
+        self.inner.last_log_index().await
+    }
+
+    /// Snapshot the state machine and purge the log up to the last applied entry
+    ///
+    /// Returns the index covered by the snapshot. Snapshots are also taken
+    /// automatically once [`crate::ConfigBuilder::snapshot_entries`] or
+    /// [`crate::ConfigBuilder::snapshot_log_bytes`] is reached.
+    pub async fn snapshot(&self) -> crate::Result<u64> {
+        self.inner.snapshot().await
+    }
+
+    /// Get the state of the node, its log and the durability policy of the log
+    pub async fn metrics(&self) -> crate::Result<Metrics> {
+        self.inner.metrics().await
+    }
+
+    /// Get the address the RPC server is bound to, `None` before [`Ruft::start`] or for transports without sockets
+    ///
+    /// With port 0 in [`crate::ConfigBuilder::listen_addr`] this is the port the OS picked.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Get the leader as known by this node, `None` while no leader is known
+    ///
+    /// Unknown right after start and while an election is going on.
+    pub async fn get_leader(&self) -> Option<Endpoint> {
+        self.inner.leader().await
+    }
+
+    /// Wait until this node knows a leader, failing with [`crate::RuftError::InvalidState`] after `timeout`
+    pub async fn wait_for_leader(&self, timeout: Duration) -> crate::Result<Endpoint> {
+        self.inner.wait_for_leader(timeout).await
+    }
+
+    /// Read the TLS certificates of [`crate::ConfigBuilder::tls`] again without restarting
+    ///
+    /// Connections made from now on use the new certificates. Fails with
+    /// [`crate::RuftError::InvalidState`] if the node does not use TLS or the new files
+    /// are not valid, the previous certificates stay in use then.
+    pub fn reload_tls(&self) -> crate::Result<()> {
+        let tls = self.tls.as_ref().ok_or_else(|| crate::RuftError::InvalidState("Node is not configured for TLS".into()))?;
+        tls.reload()
+    }
+
     /// Check if this node is the leader
     pub async fn is_leader(&self) -> bool {
         self.state().await == "Leader"
     }
 
-    // TODO: Add these methods when needed:
-    // - pub async fn shutdown(&self) -> Result<()>
-    // - pub async fn snapshot(&self) -> Result<()>
-    // - pub async fn get_leader(&self) -> Option<Endpoint>
-    // - pub async fn metrics(&self) -> Metrics
+    #[cfg(test)]
+    pub(crate) fn node(&self) -> &Arc<Node> {
+        &self.inner
+    }
+
+    /// Stop the node gracefully
+    ///
+    /// A leader first hands leadership to its most up to date follower. Then the
+    /// timer, the peer clients and the RPC server stop, the log and meta are
+    /// synced and proposals still waiting for their commit fail.
+    pub async fn shutdown(&self) -> crate::Result<()> {
+        self.inner.shutdown().await
+    }
 }
 
 impl Clone for Ruft {
     fn clone(&self) -> Self {
-        Ruft { inner: self.inner.clone() }
+        Ruft {
+            inner: self.inner.clone(),
+            tls: self.tls.clone(),
+        }
+    }
+}
+
+/// Builder for Ruft with pluggable environment
+///
+/// Defaults to gRPC, over mutual TLS if the config asks for it, the tokio clock, thread-local randomness and a state
+/// machine that ignores commands. A simulation
+/// injects an in-process transport and a [`crate::SeededRandom`] so that a run
+/// can be replayed from its seed.
+pub struct RuftBuilder {
+    endpoint: Endpoint,
+    config: Config,
+    transport: Option<Arc<dyn Transport>>,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+    sm: Box<dyn Sm>,
+}
+
+impl RuftBuilder {
+    /// Set the transport used to talk to peers
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Set the clock driving timers and timeouts
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set the source of randomness for election jitter
+    pub fn random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Set the state machine committed commands are applied to
+    pub fn state_machine(mut self, sm: impl Sm) -> Self {
+        self.sm = Box::new(sm);
+        self
+    }
+
+    /// Build the Ruft node
+    pub fn build(self) -> crate::Result<Ruft> {
+        let (transport, tls): (Arc<dyn Transport>, _) = match (self.transport, &self.config.tls) {
+            (Some(transport), _) => (transport, None),
+            (None, files) => {
+                let tls = files.clone().map(PeerTls::load).transpose()?;
+                let grpc = match &tls {
+                    Some(tls) => GrpcTransport::with_tls(tls.clone()),
+                    None => GrpcTransport::default(),
+                };
+                let grpc = grpc
+                    .rpc_timeout(self.config.rpc_timeout())
+                    .reconnect_backoff(self.config.reconnect_backoff)
+                    .clock(self.clock.clone())
+                    .random(self.random.clone());
+                (Arc::new(grpc), tls)
+            }
+        };
+        let node = Node::new(self.endpoint, self.config, transport, self.clock, self.random, self.sm)?;
+        Ok(Ruft { inner: Arc::new(node), tls })
     }
 }
@@ -1,5 +1,7 @@
+use crate::clock::{Clock, TokioClock};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use tokio::time::Duration;
 
 /// Trait for tasks that need to be executed repeatedly
@@ -12,8 +14,10 @@ pub(crate) trait RepeatTask: Send + Sync {
 }
 
 pub(crate) struct RepeatTimer {
+    #[allow(dead_code)]
     name: String,
     task: Box<dyn RepeatTask>,
+    clock: Arc<dyn Clock>,
 }
 
 pub(crate) struct RepeatTimerHandle {
@@ -22,8 +26,13 @@ pub(crate) struct RepeatTimerHandle {
 }
 
 impl RepeatTimer {
+    #[allow(dead_code)]
     pub fn new(name: String, task: Box<dyn RepeatTask>) -> Self {
-        RepeatTimer { name, task }
+        RepeatTimer {
+            name,
+            task,
+            clock: Arc::new(TokioClock),
+        }
     }
 
     /// Create a timer from closures (for simple cases)
@@ -35,9 +44,16 @@ impl RepeatTimer {
         RepeatTimer {
             name,
             task: Box::new(FnTask { delay_fn, run_fn }),
+            clock: Arc::new(TokioClock),
         }
     }
 
+    /// Measure delays with `clock` instead of the tokio timer
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn spawn(self) -> RepeatTimerHandle {
         let (restart_tx, mut restart_rx) = tokio::sync::mpsc::unbounded_channel();
         let (stop_tx, mut stop_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -47,14 +63,16 @@ impl RepeatTimer {
                 let delay = self.task.delay().await;
 
                 tokio::select! {
-                    _ = tokio::time::sleep(delay) => {
-                        self.task.run().await;
+                    // Fixed polling order keeps simulations reproducible
+                    biased;
+                    Some(_) = stop_rx.recv() => {
+                        break;
                     }
                     Some(_) = restart_rx.recv() => {
                         continue;
                     }
-                    Some(_) = stop_rx.recv() => {
-                        break;
+                    _ = self.clock.sleep(delay) => {
+                        self.task.run().await;
                     }
                 }
             }
@@ -117,7 +135,7 @@ mod tests {
             },
         );
 
-        let handle = timer.spawn();
+        let _handle = timer.spawn();
 
         // 等待第一次执行
         tokio::time::sleep(Duration::from_millis(150)).await;
@@ -1,4 +1,5 @@
 use crate::role::state::RaftState;
+use crate::rpc::NodeId;
 use std::fmt::Display;
 
 /// Candidate state: requesting votes to become leader
@@ -6,7 +7,8 @@ use std::fmt::Display;
 pub struct Candidate {
     pub term: u64,
     pub votes_received: u64,
-    pub voted_for: u8,
+    #[allow(dead_code)]
+    pub voted_for: NodeId,
 }
 
 impl RaftState for Candidate {
@@ -1,13 +1,14 @@
 use crate::role::state::RaftState;
-use crate::rpc::Endpoint;
+use crate::rpc::{Endpoint, NodeId};
 use std::fmt::Display;
 
 /// Follower state: waiting for heartbeats from leader
 #[derive(Debug, Clone)]
 pub struct Follower {
     pub term: u64,
-    pub leader: Endpoint,
-    pub voted_for: Option<u64>,
+    /// `None` until a leader of this term is heard from
+    pub leader: Option<Endpoint>,
+    pub voted_for: Option<NodeId>,
 }
 
 impl RaftState for Follower {
@@ -22,6 +23,9 @@ impl RaftState for Follower {
 
 impl Display for Follower {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Following[term={}, leader={}]", self.term, self.leader)
+        match &self.leader {
+            Some(leader) => write!(f, "Following[term={}, leader={}]", self.term, leader),
+            None => write!(f, "Following[term={}, leader=unknown]", self.term),
+        }
     }
 }
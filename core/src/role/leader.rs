@@ -1,5 +1,5 @@
 use crate::role::state::RaftState;
-use crate::rpc::Endpoint;
+use crate::rpc::NodeId;
 use std::collections::HashMap;
 use std::fmt::Display;
 
@@ -8,10 +8,22 @@ use std::fmt::Display;
 #[derive(Debug, Clone)]
 pub struct Leader {
     pub term: u64,
-    /// For each server, index of the next log entry to send
-    pub next_index: HashMap<Endpoint, u64>,
-    /// For each server, index of highest log entry known to be replicated
-    pub match_index: HashMap<Endpoint, u64>,
+    /// For each server by id, index of the next log entry to send
+    pub next_index: HashMap<NodeId, u64>,
+    /// For each server by id, index of highest log entry known to be replicated
+    pub match_index: HashMap<NodeId, u64>,
+    /// Snapshot transfers to followers whose next entry is already compacted
+    pub snapshot_transfers: HashMap<NodeId, SnapshotTransfer>,
+}
+
+/// Progress of streaming a snapshot to one follower
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotTransfer {
+    /// Index of the snapshot being sent, `offset` only applies to it
+    pub index: u64,
+    /// Data offset the follower acknowledged last
+    pub offset: u64,
+    pub in_flight: bool,
 }
 
 impl RaftState for Leader {
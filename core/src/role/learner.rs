@@ -6,7 +6,8 @@ use std::fmt::Display;
 #[derive(Debug, Clone)]
 pub struct Learner {
     pub term: u64,
-    pub leader: Endpoint,
+    /// `None` until a leader of this term is heard from
+    pub leader: Option<Endpoint>,
 }
 
 impl RaftState for Learner {
@@ -21,6 +22,9 @@ impl RaftState for Learner {
 
 impl Display for Learner {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Learner[term={}, leader={}]", self.term, self.leader)
+        match &self.leader {
+            Some(leader) => write!(f, "Learner[term={}, leader={}]", self.term, leader),
+            None => write!(f, "Learner[term={}, leader=unknown]", self.term),
+        }
     }
 }
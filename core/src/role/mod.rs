@@ -6,6 +6,6 @@ mod state;
 
 pub(crate) use crate::role::candidate::Candidate;
 pub(crate) use crate::role::follower::Follower;
-pub(crate) use crate::role::leader::Leader;
+pub(crate) use crate::role::leader::{Leader, SnapshotTransfer};
 pub(crate) use crate::role::learner::Learner;
 pub(crate) use crate::role::state::RaftState;
@@ -2,5 +2,6 @@
 /// This enables the typestate pattern: RaftNode<S: RaftState>
 pub trait RaftState: Sized {
     fn term(&self) -> u64;
+    #[allow(dead_code)]
     fn state_name() -> &'static str;
 }
@@ -1,39 +1,401 @@
+use crate::clock;
 use crate::rpc::ruft_rpc_client::RuftRpcClient;
-use crate::rpc::{Endpoint, PreVoteRequest, PreVoteResponse};
-use std::error::Error;
+use crate::rpc::tls::{PeerTls, server_name};
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, Endpoint, InstallSnapshotChunk, InstallSnapshotResponse, PreVoteRequest, PreVoteResponse, RequestVoteRequest, RequestVoteResponse, TimeoutNowRequest,
+    TimeoutNowResponse,
+};
+use crate::{Clock, Random, Result, RuftError};
+use hyper_util::rt::TokioIo;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::Channel;
 use tonic::transport::Endpoint as TonicEndpoint;
+use tonic::transport::Uri;
+use tonic::{Code, Request, Response, Status};
+use tower::service_fn;
+use tracing::debug;
 
-pub async fn init_remote_client(endpoint: &Endpoint) -> Result<RemoteClient, Box<dyn Error + Send + Sync>> {
-    let channel = TonicEndpoint::from_shared(endpoint.url())?.connect().await?;
-    let client = RuftRpcClient::new(channel);
-    Ok(RemoteClient { client })
+/// Delay between attempts to reconnect to an unreachable peer
+///
+/// The delay doubles with every failed attempt from `initial` up to `max`, and
+/// each one is jittered down by up to half so peers that failed together do
+/// not retry in lockstep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
 }
 
-pub trait RaftRpcClient {
-    async fn close(&self) -> Result<(), Box<dyn Error>>;
-    async fn pre_vote(&mut self, term: u64, candidate_id: u64, last_log_id: u64, last_log_term: u64) -> Result<PreVoteResponse, Box<dyn std::error::Error>>;
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay after the `failures`th failed attempt in a row, jittered with `random`
+    pub(crate) fn delay(&self, failures: u32, random: &dyn Random) -> Duration {
+        let base = self.initial.saturating_mul(1 << failures.saturating_sub(1).min(20)).min(self.max);
+        let half = base / 2;
+        half + Duration::from_nanos(random.gen_range(0..half.as_nanos() as u64 + 1))
+    }
 }
 
+/// State of the connection to a peer as its client sees it, see [`crate::Metrics::peers`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerHealth {
+    /// Whether the last RPC or connection attempt succeeded, false before the first one
+    pub reachable: bool,
+    /// RPCs and connection attempts that failed since the last success
+    pub consecutive_failures: u32,
+    /// Error of the last failure, kept after the peer recovered
+    pub last_error: Option<String>,
+}
+
+/// Keeps the [`PeerHealth`] of a client up to date with the outcome of its calls
+#[derive(Default)]
+pub(crate) struct HealthTracker(std::sync::Mutex<PeerHealth>);
+
+impl HealthTracker {
+    pub(crate) fn record<T, E: Display>(&self, result: &std::result::Result<T, E>) {
+        let mut health = self.0.lock().unwrap();
+        match result {
+            Ok(_) => {
+                health.reachable = true;
+                health.consecutive_failures = 0;
+            }
+            Err(e) => {
+                health.reachable = false;
+                health.consecutive_failures += 1;
+                health.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub(crate) fn get(&self) -> PeerHealth {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Client side of the peer protocol
+///
+/// Every RPC a node sends to its peers goes through this trait, so the
+/// consensus code does not care whether messages travel over gRPC or
+/// through an in-process channel.
+#[tonic::async_trait]
+pub trait RaftRpcClient: Send + Sync {
+    async fn close(&self) -> Result<()>;
+    async fn pre_vote(&self, req: PreVoteRequest) -> Result<PreVoteResponse>;
+    async fn request_vote(&self, req: RequestVoteRequest) -> Result<RequestVoteResponse>;
+    async fn append_entries(&self, req: AppendEntriesRequest) -> Result<AppendEntriesResponse>;
+
+    /// Stream snapshot chunks as they arrive on `chunks`
+    ///
+    /// Returns the answer to the last chunk the peer handled: the stream stops
+    /// early once a chunk is rejected, `next_offset` then says where to resume.
+    async fn install_snapshot(&self, chunks: mpsc::Receiver<InstallSnapshotChunk>) -> Result<InstallSnapshotResponse>;
+
+    /// Ask the peer to start an election right away, used to hand over leadership
+    async fn timeout_now(&self, req: TimeoutNowRequest) -> Result<TimeoutNowResponse>;
+
+    /// How the calls to the peer went lately
+    fn health(&self) -> PeerHealth;
+}
+
+/// Whether a snapshot stream goes on after a chunk ending at `end` got `resp`
+pub(crate) fn snapshot_stream_continues(end: u64, done: bool, resp: &InstallSnapshotResponse) -> bool {
+    !done && !resp.installed && resp.next_offset == end
+}
+
+/// Used for connection attempts when no RPC deadline is configured
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// gRPC implementation of [`RaftRpcClient`]
+///
+/// Connects on the first call rather than when created, so peers may come up
+/// in any order. A connection that fails is dropped and made again on a later
+/// call, after a [`Backoff`] while attempts keep failing. Unary calls carry the
+/// RPC deadline to the server and are given up locally once it passes.
 pub struct RemoteClient {
-    client: RuftRpcClient<Channel>,
+    endpoint: Endpoint,
+    target: TonicEndpoint,
+    tls: Option<Arc<PeerTls>>,
+    rpc_timeout: Option<Duration>,
+    backoff: Backoff,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+    conn: Mutex<Connection>,
+    health: HealthTracker,
+}
+
+#[derive(Default)]
+struct Connection {
+    client: Option<RuftRpcClient<Channel>>,
+    /// Connection attempts that failed in a row
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl RemoteClient {
+    /// Create a client for `endpoint`, over mutual TLS when `tls` is given
+    ///
+    /// With TLS the peer has to prove it is the node of `endpoint`'s id, see [`crate::rpc::peer_name`].
+    /// Deadlines and backoffs are measured on `clock`, backoffs are jittered with `random`.
+    pub fn new(endpoint: &Endpoint, tls: Option<Arc<PeerTls>>, rpc_timeout: Option<Duration>, backoff: Backoff, clock: Arc<dyn Clock>, random: Arc<dyn Random>) -> Result<Self> {
+        let url = if tls.is_some() { endpoint.tls_url() } else { endpoint.url() };
+        let target = TonicEndpoint::from_shared(url)
+            .map_err(|e| RuftError::InvalidState(format!("Invalid endpoint {}: {}", endpoint, e)))?
+            .connect_timeout(rpc_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT));
+        Ok(RemoteClient {
+            endpoint: endpoint.clone(),
+            target,
+            tls,
+            rpc_timeout,
+            backoff,
+            clock,
+            random,
+            conn: Mutex::new(Connection::default()),
+            health: HealthTracker::default(),
+        })
+    }
+
+    async fn connect(&self) -> std::result::Result<Channel, tonic::transport::Error> {
+        let Some(tls) = self.tls.clone() else {
+            return self.target.connect().await;
+        };
+        let (host, port, name) = (self.endpoint.host().clone(), self.endpoint.port(), server_name(self.endpoint.id()));
+        // Reconnects handshake again, picking up reloaded certificates
+        let connector = service_fn(move |_: Uri| {
+            let (host, name, connector) = (host.clone(), name.clone(), tls.connector());
+            async move {
+                let stream = TcpStream::connect((host.as_str(), port)).await?;
+                stream.set_nodelay(true)?;
+                let stream = connector.connect(name, stream).await?;
+                Ok::<_, std::io::Error>(TokioIo::new(stream))
+            }
+        });
+        self.target.connect_with_connector(connector).await
+    }
+
+    /// The connection to the peer, made now unless an earlier attempt failed too recently
+    async fn client(&self) -> std::result::Result<RuftRpcClient<Channel>, Status> {
+        let mut conn = self.conn.lock().await;
+        if let Some(client) = &conn.client {
+            // Channel is cheap to clone, cloning lets concurrent calls share one connection
+            return Ok(client.clone());
+        }
+        let now = self.clock.now();
+        if let Some(retry_at) = conn.retry_at
+            && now < retry_at
+        {
+            return Err(Status::unavailable(format!("Waiting {:?} before reconnecting to {}", retry_at - now, self.endpoint)));
+        }
+        match self.connect().await {
+            Ok(channel) => {
+                debug!("Connected to {}", self.endpoint);
+                let client = RuftRpcClient::new(channel);
+                *conn = Connection {
+                    client: Some(client.clone()),
+                    ..Connection::default()
+                };
+                Ok(client)
+            }
+            Err(e) => {
+                conn.failures += 1;
+                let delay = self.backoff.delay(conn.failures, self.random.as_ref());
+                conn.retry_at = Some(self.clock.now() + delay);
+                debug!("Failed to connect to {} ({} in a row), retrying in {:?}: {}", self.endpoint, conn.failures, delay, e);
+                Err(Status::unavailable(format!("Failed to connect to {}: {}", self.endpoint, e)))
+            }
+        }
+    }
+
+    /// Run one RPC on the connection, dropping the connection if the peer turned out unreachable
+    async fn call<Req, Resp, F, Fut>(&self, req: Req, deadline: Option<Duration>, rpc: F) -> Result<Resp>
+    where
+        F: FnOnce(RuftRpcClient<Channel>, Request<Req>) -> Fut,
+        Fut: Future<Output = std::result::Result<Response<Resp>, Status>>,
+    {
+        let result = match self.client().await {
+            Ok(client) => {
+                let mut request = Request::new(req);
+                match deadline {
+                    Some(deadline) => {
+                        request.set_timeout(deadline);
+                        clock::timeout(self.clock.as_ref(), deadline, rpc(client, request))
+                            .await
+                            .unwrap_or_else(|| Err(Status::deadline_exceeded(format!("No answer from {} within {:?}", self.endpoint, deadline))))
+                    }
+                    None => rpc(client, request).await,
+                }
+            }
+            Err(status) => Err(status),
+        };
+        self.health.record(&result);
+        if let Err(status) = &result
+            && status.code() == Code::Unavailable
+        {
+            self.conn.lock().await.client = None;
+        }
+        Ok(result?.into_inner())
+    }
 }
 
+#[tonic::async_trait]
 impl RaftRpcClient for RemoteClient {
-    async fn close(&self) -> Result<(), Box<dyn Error>> {
+    async fn close(&self) -> Result<()> {
+        self.conn.lock().await.client = None;
         Ok(())
     }
 
-    async fn pre_vote(&mut self, term: u64, candidate_id: u64, last_log_id: u64, last_log_term: u64) -> Result<PreVoteResponse, Box<dyn std::error::Error>> {
-        let resp = self
-            .client
-            .pre_vote(PreVoteRequest {
-                term,
-                candidate_id,
-                last_log_index: last_log_id,
-                last_log_term,
-            })
-            .await?;
-        Ok(resp.into_inner())
+    async fn pre_vote(&self, req: PreVoteRequest) -> Result<PreVoteResponse> {
+        self.call(req, self.rpc_timeout, |mut client, req| async move { client.pre_vote(req).await }).await
+    }
+
+    async fn request_vote(&self, req: RequestVoteRequest) -> Result<RequestVoteResponse> {
+        self.call(req, self.rpc_timeout, |mut client, req| async move { client.request_vote(req).await }).await
+    }
+
+    async fn append_entries(&self, req: AppendEntriesRequest) -> Result<AppendEntriesResponse> {
+        self.call(req, self.rpc_timeout, |mut client, req| async move { client.append_entries(req).await }).await
+    }
+
+    async fn install_snapshot(&self, chunks: mpsc::Receiver<InstallSnapshotChunk>) -> Result<InstallSnapshotResponse> {
+        // A stream runs as long as the snapshot takes, the caller bounds each chunk instead
+        self.call(ReceiverStream::new(chunks), None, |mut client, req| async move { client.install_snapshot(req).await }).await
+    }
+
+    async fn timeout_now(&self, req: TimeoutNowRequest) -> Result<TimeoutNowResponse> {
+        self.call(req, self.rpc_timeout, |mut client, req| async move { client.timeout_now(req).await }).await
+    }
+
+    fn health(&self) -> PeerHealth {
+        self.health.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{GrpcTransport, NodeId, RaftRpcHandler, Transport};
+    use crate::{SeededRandom, ThreadRandom, TokioClock};
+    use std::net::SocketAddr;
+
+    /// A peer that takes every request but never answers in time
+    struct Stalled;
+
+    #[tonic::async_trait]
+    impl RaftRpcHandler for Stalled {
+        async fn handle_pre_vote(&self, _req: PreVoteRequest) -> Result<PreVoteResponse> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(PreVoteResponse::default())
+        }
+        async fn handle_request_vote(&self, _req: RequestVoteRequest) -> Result<RequestVoteResponse> {
+            Err(RuftError::InvalidState("unused".into()))
+        }
+        async fn handle_append_entries(&self, _req: AppendEntriesRequest) -> Result<AppendEntriesResponse> {
+            Err(RuftError::InvalidState("unused".into()))
+        }
+        async fn handle_install_snapshot(&self, _chunk: InstallSnapshotChunk) -> Result<InstallSnapshotResponse> {
+            Err(RuftError::InvalidState("unused".into()))
+        }
+        async fn handle_timeout_now(&self, _req: TimeoutNowRequest) -> Result<TimeoutNowResponse> {
+            Err(RuftError::InvalidState("unused".into()))
+        }
+    }
+
+    fn pre_vote() -> PreVoteRequest {
+        PreVoteRequest {
+            term: 0,
+            candidate_id: 9,
+            last_log_index: 0,
+            last_log_term: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max_with_jitter() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+        };
+        for (failures, base) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let delay = backoff.delay(failures, &SeededRandom::new(failures as u64));
+            assert!(delay >= Duration::from_millis(base / 2) && delay <= Duration::from_millis(base), "{} failures: {:?}", failures, delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnects_after_backoff() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), port);
+        let backoff = Backoff {
+            initial: Duration::from_millis(200),
+            max: Duration::from_millis(200),
+        };
+        let client = RemoteClient::new(&endpoint, None, Some(Duration::from_millis(500)), backoff, Arc::new(TokioClock), Arc::new(ThreadRandom)).unwrap();
+        assert_eq!(client.health(), PeerHealth::default());
+
+        // Nobody listens yet, and the next attempt waits for the backoff
+        assert!(client.pre_vote(pre_vote()).await.is_err());
+        match client.pre_vote(pre_vote()).await {
+            Err(RuftError::Rpc(status)) => assert!(status.message().starts_with("Waiting"), "{}", status.message()),
+            other => panic!("expected to wait for the backoff, got {:?}", other),
+        }
+        let health = client.health();
+        assert!(!health.reachable);
+        assert_eq!(health.consecutive_failures, 2);
+
+        let dir = "/tmp/raft/reconnect/node1";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = crate::Config::builder().members(vec![endpoint.clone()]).data_dir(dir).heartbeat_interval(50).build();
+        let node = crate::Ruft::new(endpoint, config).unwrap();
+        node.start().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        client.pre_vote(pre_vote()).await.unwrap();
+        let health = client.health();
+        assert!(health.reachable);
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.last_error.is_some());
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rpc_gives_up_at_deadline() {
+        let listen_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (_stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = Box::pin(async move {
+            let _ = stopped.await;
+        });
+        let serving = GrpcTransport::default()
+            .serve(Endpoint::new(NodeId::new(1), "127.0.0.1".into(), 0), Some(listen_addr), Arc::new(Stalled), shutdown)
+            .await
+            .unwrap();
+        let addr = serving.local_addr.unwrap();
+        tokio::spawn(serving.run);
+
+        let peer = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), addr.port());
+        let clock = Arc::new(TokioClock);
+        let client = GrpcTransport::default().rpc_timeout(Duration::from_millis(100)).clock(clock.clone()).connect(&peer).await.unwrap();
+        let started = clock.now();
+        match client.pre_vote(pre_vote()).await {
+            // Whichever side notices first, the server cancels the call once the deadline it got passes
+            Err(RuftError::Rpc(status)) => assert!(matches!(status.code(), Code::DeadlineExceeded | Code::Cancelled), "{:?}", status),
+            other => panic!("expected the deadline to pass, got {:?}", other),
+        }
+        assert!(clock.now() - started < Duration::from_secs(2));
+        assert_eq!(client.health().consecutive_failures, 1);
     }
 }
@@ -1,19 +1,58 @@
+use crate::rpc::Member;
+use crate::{Result, RuftError};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 
+/// Identifies a node within its cluster, the same width as the ids in the RPC messages
+///
+/// A type of its own so ids do not mix with terms and log indexes, the raw
+/// `u64` of the messages converts with `From` at the RPC boundary.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NodeId(u64);
+
+impl NodeId {
+    pub const fn new(id: u64) -> Self {
+        NodeId(id)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for NodeId {
+    fn from(id: u64) -> Self {
+        NodeId(id)
+    }
+}
+
+impl From<NodeId> for u64 {
+    fn from(id: NodeId) -> Self {
+        id.0
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Endpoint {
-    id: u8,
+    id: NodeId,
     host: String,
     port: u16,
 }
 
 impl Endpoint {
-    pub fn new(id: u8, host: String, port: u16) -> Self {
+    pub fn new(id: NodeId, host: String, port: u16) -> Self {
         Endpoint { id, host, port }
     }
 
-    pub fn id(&self) -> u8 {
+    pub fn id(&self) -> NodeId {
         self.id
     }
 
@@ -26,7 +65,21 @@ impl Endpoint {
     }
 
     pub fn url(&self) -> String {
-        format!("http://{}:{}", self.host, self.port)
+        self.url_with_scheme("http")
+    }
+
+    /// URL of the endpoint when peers talk over TLS
+    pub fn tls_url(&self) -> String {
+        self.url_with_scheme("https")
+    }
+
+    fn url_with_scheme(&self, scheme: &str) -> String {
+        // IPv6 literals are bracketed in URLs
+        if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            format!("{}://{}:{}", scheme, self.host, self.port)
+        }
     }
 }
 
@@ -37,3 +90,36 @@ impl Display for Endpoint {
         write!(f, "]")
     }
 }
+
+impl From<&Endpoint> for Member {
+    fn from(endpoint: &Endpoint) -> Self {
+        Member {
+            id: endpoint.id.into(),
+            host: endpoint.host.clone(),
+            port: endpoint.port as u32,
+        }
+    }
+}
+
+impl TryFrom<&Member> for Endpoint {
+    type Error = RuftError;
+
+    fn try_from(member: &Member) -> Result<Self> {
+        let port = u16::try_from(member.port).map_err(|_| RuftError::InvalidState(format!("Member {} has invalid port {}", member.id, member.port)))?;
+        Ok(Endpoint::new(member.id.into(), member.host.clone(), port))
+    }
+}
+
+/// Endpoint as persisted before node ids were widened to 64 bits, read by the file format migrations
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyEndpoint {
+    pub id: u8,
+    pub host: String,
+    pub port: u16,
+}
+
+impl From<LegacyEndpoint> for Endpoint {
+    fn from(legacy: LegacyEndpoint) -> Self {
+        Endpoint::new(NodeId::from(u64::from(legacy.id)), legacy.host, legacy.port)
+    }
+}
@@ -0,0 +1,478 @@
+use crate::rpc::client::{HealthTracker, PeerHealth, RaftRpcClient, snapshot_stream_continues};
+use crate::rpc::server::RaftRpcHandler;
+use crate::rpc::transport::{Serving, Shutdown, Transport};
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, Endpoint, InstallSnapshotChunk, InstallSnapshotResponse, PreVoteRequest, PreVoteResponse, RequestVoteRequest, RequestVoteResponse, TimeoutNowRequest,
+    TimeoutNowResponse,
+};
+use crate::{Result, RuftError};
+use dashmap::DashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// A message in flight between two in-process nodes, carrying its reply channel
+pub(crate) enum LocalMessage {
+    PreVote(PreVoteRequest, oneshot::Sender<Result<PreVoteResponse>>),
+    RequestVote(RequestVoteRequest, oneshot::Sender<Result<RequestVoteResponse>>),
+    AppendEntries(AppendEntriesRequest, oneshot::Sender<Result<AppendEntriesResponse>>),
+    InstallSnapshot(InstallSnapshotChunk, oneshot::Sender<Result<InstallSnapshotResponse>>),
+    TimeoutNow(TimeoutNowRequest, oneshot::Sender<Result<TimeoutNowResponse>>),
+}
+
+impl LocalMessage {
+    /// Run the message against a handler and send the result back to the caller
+    pub(crate) async fn dispatch(self, handler: &dyn RaftRpcHandler) {
+        match self {
+            LocalMessage::PreVote(req, reply) => {
+                let _ = reply.send(handler.handle_pre_vote(req).await);
+            }
+            LocalMessage::RequestVote(req, reply) => {
+                let _ = reply.send(handler.handle_request_vote(req).await);
+            }
+            LocalMessage::AppendEntries(req, reply) => {
+                let _ = reply.send(handler.handle_append_entries(req).await);
+            }
+            LocalMessage::InstallSnapshot(chunk, reply) => {
+                let _ = reply.send(handler.handle_install_snapshot(chunk).await);
+            }
+            LocalMessage::TimeoutNow(req, reply) => {
+                let _ = reply.send(handler.handle_timeout_now(req).await);
+            }
+        }
+    }
+}
+
+/// Hand every message arriving on `rx` to `handler` until `shutdown` resolves or all senders are gone
+pub(crate) async fn dispatch_until(mut rx: mpsc::UnboundedReceiver<LocalMessage>, handler: Arc<dyn RaftRpcHandler>, mut shutdown: Shutdown) {
+    loop {
+        let msg = tokio::select! {
+            biased;
+            _ = &mut shutdown => break,
+            msg = rx.recv() => msg,
+        };
+        let Some(msg) = msg else { break };
+        let handler = handler.clone();
+        tokio::spawn(async move { msg.dispatch(handler.as_ref()).await });
+    }
+}
+
+/// Ship a snapshot stream as one message per chunk, stopping like a gRPC stream would
+pub(crate) async fn send_chunks<F, Fut>(mut chunks: mpsc::Receiver<InstallSnapshotChunk>, mut send: F) -> Result<InstallSnapshotResponse>
+where
+    F: FnMut(InstallSnapshotChunk) -> Fut,
+    Fut: Future<Output = Result<InstallSnapshotResponse>>,
+{
+    let mut last = None;
+    while let Some(chunk) = chunks.recv().await {
+        let (end, done) = (chunk.offset + chunk.data.len() as u64, chunk.done);
+        let resp = send(chunk).await?;
+        if !snapshot_stream_continues(end, done, &resp) {
+            return Ok(resp);
+        }
+        last = Some(resp);
+    }
+    last.ok_or_else(|| RuftError::InvalidState("Empty snapshot stream".into()))
+}
+
+/// Registry of in-process nodes, shared by every [`LocalTransport`] of a cluster
+#[derive(Default)]
+pub struct LocalNetwork {
+    nodes: DashMap<Endpoint, mpsc::UnboundedSender<LocalMessage>>,
+}
+
+impl LocalNetwork {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn send(&self, target: &Endpoint, msg: LocalMessage) -> Result<()> {
+        let sender = self.nodes.get(target).ok_or_else(|| unreachable(target))?;
+        sender.send(msg).map_err(|_| unreachable(target))
+    }
+}
+
+fn unreachable(target: &Endpoint) -> RuftError {
+    RuftError::Rpc(tonic::Status::unavailable(format!("Node {} is not reachable", target)))
+}
+
+/// Channel based transport for running whole clusters inside one process
+#[derive(Clone)]
+pub struct LocalTransport {
+    network: Arc<LocalNetwork>,
+}
+
+impl LocalTransport {
+    pub fn new(network: Arc<LocalNetwork>) -> Self {
+        LocalTransport { network }
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for LocalTransport {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Arc<dyn RaftRpcClient>> {
+        // The target is resolved on every call, so peers may come up later
+        Ok(Arc::new(LocalClient {
+            target: endpoint.clone(),
+            network: self.network.clone(),
+            health: HealthTracker::default(),
+        }))
+    }
+
+    async fn serve(&self, endpoint: Endpoint, _listen_addr: Option<SocketAddr>, handler: Arc<dyn RaftRpcHandler>, shutdown: Shutdown) -> Result<Serving> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let ours = tx.downgrade();
+        self.network.nodes.insert(endpoint.clone(), tx);
+        let network = self.network.clone();
+        let run = async move {
+            dispatch_until(rx, handler, shutdown).await;
+            // Peers see the node as unreachable from now on, unless it was started again meanwhile
+            network.nodes.remove_if(&endpoint, |_, current| ours.upgrade().is_some_and(|tx| current.same_channel(&tx)));
+            Ok(())
+        };
+        Ok(Serving { local_addr: None, run: Box::pin(run) })
+    }
+}
+
+/// In-process implementation of [`RaftRpcClient`]
+pub struct LocalClient {
+    target: Endpoint,
+    network: Arc<LocalNetwork>,
+    health: HealthTracker,
+}
+
+impl LocalClient {
+    async fn call<T>(&self, make: impl FnOnce(oneshot::Sender<Result<T>>) -> LocalMessage) -> Result<T> {
+        let (tx, rx) = oneshot::channel();
+        let result = match self.network.send(&self.target, make(tx)) {
+            Ok(()) => rx.await.map_err(|_| unreachable(&self.target)).and_then(|resp| resp),
+            Err(e) => Err(e),
+        };
+        self.health.record(&result);
+        result
+    }
+}
+
+#[tonic::async_trait]
+impl RaftRpcClient for LocalClient {
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn pre_vote(&self, req: PreVoteRequest) -> Result<PreVoteResponse> {
+        self.call(|reply| LocalMessage::PreVote(req, reply)).await
+    }
+
+    async fn request_vote(&self, req: RequestVoteRequest) -> Result<RequestVoteResponse> {
+        self.call(|reply| LocalMessage::RequestVote(req, reply)).await
+    }
+
+    async fn append_entries(&self, req: AppendEntriesRequest) -> Result<AppendEntriesResponse> {
+        self.call(|reply| LocalMessage::AppendEntries(req, reply)).await
+    }
+
+    async fn install_snapshot(&self, chunks: mpsc::Receiver<InstallSnapshotChunk>) -> Result<InstallSnapshotResponse> {
+        send_chunks(chunks, |chunk| self.call(|reply| LocalMessage::InstallSnapshot(chunk, reply))).await
+    }
+
+    async fn timeout_now(&self, req: TimeoutNowRequest) -> Result<TimeoutNowResponse> {
+        self.call(|reply| LocalMessage::TimeoutNow(req, reply)).await
+    }
+
+    fn health(&self) -> PeerHealth {
+        self.health.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::command::{CmdReq, CmdResp, ErrorCode};
+    use crate::rpc::{LogEntry, NodeId};
+    use crate::storage::crc32c;
+    use crate::{Config, Ruft, Sm, SmView};
+    use bytes::Bytes;
+    use std::io::{self, Read, Write};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_local_cluster_elects_leader() {
+        let network = LocalNetwork::new();
+        let members: Vec<Endpoint> = (1..=3).map(|id| Endpoint::new(NodeId::new(id), "local".into(), 5000 + id as u16)).collect();
+
+        let mut nodes = vec![];
+        for member in &members {
+            let dir = format!("/tmp/raft/local_cluster/node{}", member.id());
+            let _ = std::fs::remove_dir_all(&dir);
+            let config = Config::builder().members(members.clone()).data_dir(dir).heartbeat_interval(50).build();
+            let ruft = Ruft::with_transport(member.clone(), config, Arc::new(LocalTransport::new(network.clone()))).unwrap();
+            ruft.start().await.unwrap();
+            nodes.push(ruft);
+        }
+
+        let mut leaders = vec![];
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            leaders.clear();
+            for node in &nodes {
+                if node.is_leader().await {
+                    leaders.push(node.current_term().await);
+                }
+            }
+            if leaders.len() == 1 {
+                break;
+            }
+        }
+        assert_eq!(leaders.len(), 1);
+
+        // Followers learn the leader's term from its heartbeats
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for node in &nodes {
+            assert_eq!(node.current_term().await, leaders[0]);
+        }
+
+        // A node that is shut down leaves the network
+        for node in &nodes {
+            node.shutdown().await.unwrap();
+        }
+        assert!(network.nodes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_member_moves_to_new_address() {
+        let network = LocalNetwork::new();
+        let members: Vec<Endpoint> = (1..=3).map(|id| Endpoint::new(NodeId::new(id), "local".into(), 6000 + id as u16)).collect();
+        let start = |endpoint: Endpoint| {
+            let dir = format!("/tmp/raft/local_move/node{}", endpoint.id());
+            let config = Config::builder().members(members.clone()).data_dir(dir).heartbeat_interval(50).build();
+            Ruft::with_transport(endpoint, config, Arc::new(LocalTransport::new(network.clone()))).unwrap()
+        };
+        let mut nodes = vec![];
+        for member in &members {
+            let _ = std::fs::remove_dir_all(format!("/tmp/raft/local_move/node{}", member.id()));
+            let node = start(member.clone());
+            node.start().await.unwrap();
+            nodes.push(node);
+        }
+        let leader = nodes[0].wait_for_leader(Duration::from_secs(5)).await.unwrap();
+        let moving = members.iter().position(|m| m.id() != leader.id()).unwrap();
+        let leader = nodes[leader.id().get() as usize - 1].clone();
+
+        // The node comes back on another port, still a voting member under its id
+        nodes[moving].shutdown().await.unwrap();
+        let moved = Endpoint::new(members[moving].id(), "local".into(), 7000);
+        nodes[moving] = start(moved.clone());
+        nodes[moving].start().await.unwrap();
+        assert_eq!(nodes[moving].state().await, "Follower");
+
+        assert!(matches!(leader.update_address(moved.clone()).await, CmdResp::Success { .. }));
+        let unknown = Endpoint::new(NodeId::new(9), "local".into(), 7009);
+        assert!(matches!(leader.update_address(unknown).await, CmdResp::Rejected { code: ErrorCode::InvalidCommand, .. }));
+        let cmd = CmdReq {
+            id: "after-move".into(),
+            data: Bytes::from_static(b"x"),
+        };
+        assert!(matches!(leader.submit(cmd).await, CmdResp::Success { .. }));
+
+        // The leader reconnects to the new address and catches the node up
+        let target = leader.commit_index().await;
+        for _ in 0..100 {
+            if nodes[moving].commit_index().await >= target {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(nodes[moving].commit_index().await >= target);
+        let peers = leader.metrics().await.unwrap().peers;
+        assert!(peers[&moved.id()].reachable, "{:?}", peers);
+
+        for node in &nodes {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_append_keeps_node() {
+        let network = LocalNetwork::new();
+        let members: Vec<Endpoint> = (1..=2).map(|id| Endpoint::new(NodeId::new(id), "local".into(), 8000 + id as u16)).collect();
+        let dir = "/tmp/raft/local_failed_append/node1";
+        let _ = std::fs::remove_dir_all(dir);
+        // Node 2 never runs, a long heartbeat keeps node 1 from campaigning meanwhile
+        let config = Config::builder().members(members.clone()).data_dir(dir).heartbeat_interval(5000).build();
+        let node = Ruft::with_transport(members[0].clone(), config, Arc::new(LocalTransport::new(network.clone()))).unwrap();
+        node.start().await.unwrap();
+
+        let append = |term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64| AppendEntriesRequest {
+            term,
+            leader_id: 2,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+            ..Default::default()
+        };
+        let entry = |index: u64, term: u64| LogEntry { index, term, ..Default::default() };
+        let handler = node.node().clone();
+        assert!(handler.handle_append_entries(append(1, 0, 0, vec![entry(1, 1)], 1)).await.unwrap().success);
+
+        // A leader of a newer term replacing the committed entry fails, the node stays a follower of that term
+        let overwrite = handler.handle_append_entries(append(2, 0, 0, vec![entry(1, 2)], 1)).await;
+        assert!(matches!(overwrite, Err(RuftError::InvalidState(_))), "{:?}", overwrite);
+        assert_eq!(node.state().await, "Follower");
+        assert_eq!(node.current_term().await, 2);
+        assert_eq!(node.commit_index().await, 1);
+
+        // And keeps handling RPCs
+        let heartbeat = handler.handle_append_entries(append(2, 1, 1, vec![entry(2, 2)], 2)).await.unwrap();
+        assert!(heartbeat.success);
+        assert_eq!(node.commit_index().await, 2);
+        node.shutdown().await.unwrap();
+    }
+
+    /// Counts the commands it applied and cannot restore a snapshot
+    struct Unrestorable(u64);
+
+    impl Sm for Unrestorable {
+        fn apply(&mut self, _index: u64, _command: &[u8]) -> Option<Bytes> {
+            self.0 += 1;
+            None
+        }
+
+        fn view(&self) -> Box<dyn SmView> {
+            Box::new(crate::sm::NoopSm)
+        }
+
+        fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+            Err(io::Error::other("restore failed"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_restore_keeps_log() {
+        let network = LocalNetwork::new();
+        let members: Vec<Endpoint> = (1..=2).map(|id| Endpoint::new(NodeId::new(id), "local".into(), 8200 + id as u16)).collect();
+        let dir = "/tmp/raft/local_failed_restore/node1";
+        let _ = std::fs::remove_dir_all(dir);
+        // Node 2 never runs, a long heartbeat keeps node 1 from campaigning meanwhile
+        let config = Config::builder().members(members.clone()).data_dir(dir).heartbeat_interval(5000).build();
+        let ruft = Ruft::builder(members[0].clone(), config)
+            .transport(Arc::new(LocalTransport::new(network)))
+            .state_machine(Unrestorable(0))
+            .build()
+            .unwrap();
+        ruft.start().await.unwrap();
+
+        let append = |prev_log_index: u64, entries: Vec<LogEntry>, leader_commit: u64| AppendEntriesRequest {
+            term: 1,
+            leader_id: 2,
+            prev_log_index,
+            prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+            entries,
+            leader_commit,
+            ..Default::default()
+        };
+        let entry = |index: u64| LogEntry { index, term: 1, ..Default::default() };
+        let handler = ruft.node().clone();
+        assert!(handler.handle_append_entries(append(0, (1..=3).map(entry).collect(), 3)).await.unwrap().success);
+
+        // A snapshot past our log that the state machine fails to take
+        let data = b"state".to_vec();
+        let chunk = InstallSnapshotChunk {
+            term: 1,
+            leader_id: 2,
+            last_included_index: 5,
+            last_included_term: 1,
+            checksum: crc32c(&data),
+            data,
+            done: true,
+            ..Default::default()
+        };
+        let installed = handler.handle_install_snapshot(chunk).await;
+        assert!(matches!(installed, Err(RuftError::Storage(_))), "{:?}", installed);
+
+        // The log still holds what the state machine has not applied, replication goes on from it
+        let metrics = ruft.metrics().await.unwrap();
+        assert_eq!((metrics.last_log_index, metrics.last_applied), (3, 3));
+        assert!(handler.handle_append_entries(append(3, vec![entry(4)], 4)).await.unwrap().success);
+        assert_eq!(ruft.metrics().await.unwrap().last_applied, 4);
+        ruft.shutdown().await.unwrap();
+    }
+
+    /// Counts the commands it applied, a view announces itself and writes its count once the gate lets it
+    struct Gated {
+        count: u64,
+        writing: mpsc::UnboundedSender<()>,
+        gate: Arc<Mutex<std::sync::mpsc::Receiver<()>>>,
+    }
+
+    impl Sm for Gated {
+        fn apply(&mut self, _index: u64, _command: &[u8]) -> Option<Bytes> {
+            self.count += 1;
+            None
+        }
+
+        fn view(&self) -> Box<dyn SmView> {
+            Box::new(Gated {
+                count: self.count,
+                writing: self.writing.clone(),
+                gate: self.gate.clone(),
+            })
+        }
+
+        fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+            let mut count = [0u8; 8];
+            r.read_exact(&mut count)?;
+            self.count = u64::from_le_bytes(count);
+            Ok(())
+        }
+    }
+
+    impl SmView for Gated {
+        fn snapshot(&self, w: &mut dyn Write) -> io::Result<()> {
+            let _ = self.writing.send(());
+            let _ = self.gate.lock().unwrap().recv();
+            w.write_all(&self.count.to_le_bytes())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_applies_while_snapshot_is_written() {
+        let network = LocalNetwork::new();
+        let member = Endpoint::new(NodeId::new(1), "local".into(), 8101);
+        let dir = "/tmp/raft/local_snapshot_in_background/node1";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config::builder().members(vec![member.clone()]).data_dir(dir).heartbeat_interval(50).build();
+        let (open, gate) = std::sync::mpsc::channel();
+        let (writing, mut started) = mpsc::unbounded_channel();
+        let sm = Gated {
+            count: 0,
+            writing,
+            gate: Arc::new(Mutex::new(gate)),
+        };
+        let ruft = Ruft::builder(member, config).transport(Arc::new(LocalTransport::new(network))).state_machine(sm).build().unwrap();
+        ruft.start().await.unwrap();
+        ruft.wait_for_leader(Duration::from_secs(5)).await.unwrap();
+
+        let cmd = |id: &str| CmdReq {
+            id: id.into(),
+            data: Bytes::from(id.to_string()),
+        };
+        assert!(matches!(ruft.submit(cmd("a")).await, CmdResp::Success { .. }));
+        let index = ruft.commit_index().await;
+        let node = ruft.node().clone();
+        let snapshot = tokio::spawn(async move { node.snapshot().await });
+        started.recv().await.unwrap();
+
+        // The snapshot is stuck at the gate, commands still commit
+        assert!(matches!(ruft.submit(cmd("b")).await, CmdResp::Success { .. }));
+        assert!(!snapshot.is_finished());
+
+        open.send(()).unwrap();
+        assert_eq!(snapshot.await.unwrap().unwrap(), index);
+        assert_eq!(ruft.metrics().await.unwrap().snapshot_index, index);
+        assert!(ruft.last_log_index().await > index);
+        ruft.shutdown().await.unwrap();
+    }
+}
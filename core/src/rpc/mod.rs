@@ -1,8 +1,17 @@
-pub(crate) mod client;
+pub mod client;
 pub mod command;
 mod endpoint;
-pub(crate) mod server;
+pub mod local;
+pub mod server;
+mod tls;
+mod transport;
 
-pub use crate::rpc::endpoint::Endpoint;
+pub use crate::rpc::client::{Backoff, PeerHealth, RaftRpcClient};
+pub(crate) use crate::rpc::endpoint::LegacyEndpoint;
+pub use crate::rpc::endpoint::{Endpoint, NodeId};
+pub use crate::rpc::local::{LocalNetwork, LocalTransport};
+pub use crate::rpc::server::RaftRpcHandler;
+pub use crate::rpc::tls::{PeerTls, peer_name};
+pub use crate::rpc::transport::{GrpcTransport, Serving, Shutdown, Transport};
 
 tonic::include_proto!("ruft");
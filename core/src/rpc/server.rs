@@ -1,30 +1,101 @@
-use crate::node::node::Node;
+use crate::rpc::client::snapshot_stream_continues;
 use crate::rpc::ruft_rpc_server::{RuftRpc, RuftRpcServer};
-use crate::rpc::{AppendEntriesRequest, AppendEntriesResponse, PreVoteRequest, PreVoteResponse, RequestVoteRequest, RequestVoteResponse};
+use crate::rpc::tls::{peer_certs, verify_sender};
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotChunk, InstallSnapshotResponse, PreVoteRequest, PreVoteResponse, RequestVoteRequest, RequestVoteResponse, TimeoutNowRequest,
+    TimeoutNowResponse,
+};
+use crate::{Result, RuftError};
 use std::error::Error;
 use std::sync::Arc;
-use tonic::{Request, Response, Status};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_stream::Stream;
+use tonic::transport::server::Connected;
+use tonic::{Request, Response, Status, Streaming};
 use tracing::info;
 
-pub async fn run_server(node: Arc<Node>) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let addr = "127.0.0.1:1218".parse()?;
+/// Server side of the peer protocol
+///
+/// Implemented by the node itself; transports only decode a message,
+/// hand it to the handler and ship the response back.
+#[tonic::async_trait]
+pub trait RaftRpcHandler: Send + Sync + 'static {
+    async fn handle_pre_vote(&self, req: PreVoteRequest) -> Result<PreVoteResponse>;
+    async fn handle_request_vote(&self, req: RequestVoteRequest) -> Result<RequestVoteResponse>;
+    async fn handle_append_entries(&self, req: AppendEntriesRequest) -> Result<AppendEntriesResponse>;
+    /// Handle one chunk of an InstallSnapshot stream
+    async fn handle_install_snapshot(&self, chunk: InstallSnapshotChunk) -> Result<InstallSnapshotResponse>;
+    async fn handle_timeout_now(&self, req: TimeoutNowRequest) -> Result<TimeoutNowResponse>;
+}
+
+/// Serve the peer protocol on accepted connections until `shutdown` completes, letting requests in flight finish
+///
+/// With `tls` every message has to come from the node its client certificate names.
+pub async fn run_server<I, IO, IE>(handler: Arc<dyn RaftRpcHandler>, incoming: I, tls: bool, shutdown: impl Future<Output = ()>) -> std::result::Result<(), Box<dyn Error + Send + Sync>>
+where
+    I: Stream<Item = std::result::Result<IO, IE>>,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IE: Into<Box<dyn Error + Send + Sync>>,
+{
     info!("Rpc server is starting");
-    tonic::transport::Server::builder().add_service(RuftRpcServer::from_arc(node)).serve(addr).await?;
-    info!("Rpc server is started");
+    tonic::transport::Server::builder()
+        .add_service(RuftRpcServer::new(GrpcService { handler, tls }))
+        .serve_with_incoming_shutdown(incoming, shutdown)
+        .await?;
+    info!("Rpc server is stopped");
     Ok(())
 }
 
+/// Adapts a [`RaftRpcHandler`] to the tonic generated service
+struct GrpcService {
+    handler: Arc<dyn RaftRpcHandler>,
+    tls: bool,
+}
+
+impl GrpcService {
+    fn authorize<T>(&self, request: &Request<T>, sender: u64) -> std::result::Result<(), Status> {
+        if self.tls { verify_sender(peer_certs(request), sender.into()) } else { Ok(()) }
+    }
+}
+
 #[tonic::async_trait]
-impl RuftRpc for Node {
-    async fn pre_vote(&self, request: Request<PreVoteRequest>) -> Result<Response<PreVoteResponse>, Status> {
-        todo!()
+impl RuftRpc for GrpcService {
+    async fn pre_vote(&self, request: Request<PreVoteRequest>) -> std::result::Result<Response<PreVoteResponse>, Status> {
+        self.authorize(&request, request.get_ref().candidate_id)?;
+        Ok(Response::new(self.handler.handle_pre_vote(request.into_inner()).await?))
+    }
+
+    async fn request_vote(&self, request: Request<RequestVoteRequest>) -> std::result::Result<Response<RequestVoteResponse>, Status> {
+        self.authorize(&request, request.get_ref().candidate_id)?;
+        Ok(Response::new(self.handler.handle_request_vote(request.into_inner()).await?))
+    }
+
+    async fn append_entries(&self, request: Request<AppendEntriesRequest>) -> std::result::Result<Response<AppendEntriesResponse>, Status> {
+        self.authorize(&request, request.get_ref().leader_id)?;
+        Ok(Response::new(self.handler.handle_append_entries(request.into_inner()).await?))
     }
 
-    async fn request_vote(&self, request: Request<RequestVoteRequest>) -> Result<Response<RequestVoteResponse>, Status> {
-        todo!()
+    async fn install_snapshot(&self, request: Request<Streaming<InstallSnapshotChunk>>) -> std::result::Result<Response<InstallSnapshotResponse>, Status> {
+        let certs = peer_certs(&request);
+        let mut chunks = request.into_inner();
+        let mut last = None;
+        while let Some(chunk) = chunks.message().await? {
+            if self.tls {
+                verify_sender(certs.clone(), chunk.leader_id.into())?;
+            }
+            let (end, done) = (chunk.offset + chunk.data.len() as u64, chunk.done);
+            let resp = self.handler.handle_install_snapshot(chunk).await?;
+            if !snapshot_stream_continues(end, done, &resp) {
+                return Ok(Response::new(resp));
+            }
+            last = Some(resp);
+        }
+        let resp = last.ok_or_else(|| RuftError::InvalidState("Empty snapshot stream".into()))?;
+        Ok(Response::new(resp))
     }
 
-    async fn append_entries(&self, request: Request<AppendEntriesRequest>) -> Result<Response<AppendEntriesResponse>, Status> {
-        todo!()
+    async fn timeout_now(&self, request: Request<TimeoutNowRequest>) -> std::result::Result<Response<TimeoutNowResponse>, Status> {
+        self.authorize(&request, request.get_ref().leader_id)?;
+        Ok(Response::new(self.handler.handle_timeout_now(request.into_inner()).await?))
     }
 }
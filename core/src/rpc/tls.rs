@@ -0,0 +1,308 @@
+use crate::rpc::NodeId;
+use crate::{Result, RuftError, TlsConfig};
+use rustls::client::verify_server_name;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ParsedCertificate, WebPkiClientVerifier};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tonic::transport::server::Connected;
+use tonic::{Request, Status};
+use tracing::info;
+
+/// Name a peer's certificate has to carry as DNS subject alternative name to act as node `id`
+pub fn peer_name(id: NodeId) -> String {
+    format!("node-{}", id)
+}
+
+/// Certificates of a node for mutual TLS, reloadable while the node runs
+///
+/// Every connection, incoming or outgoing, takes the configuration current at
+/// its handshake, so a [`PeerTls::reload`] applies to new connections while
+/// established ones keep going with the certificates they were opened with.
+pub struct PeerTls {
+    files: TlsConfig,
+    configs: RwLock<(Arc<ServerConfig>, Arc<ClientConfig>)>,
+}
+
+impl PeerTls {
+    pub fn load(files: TlsConfig) -> Result<Arc<Self>> {
+        let configs = RwLock::new(build_configs(&files)?);
+        Ok(Arc::new(PeerTls { files, configs }))
+    }
+
+    /// Read the files again, the previous certificates stay in use if they are not valid
+    pub fn reload(&self) -> Result<()> {
+        let configs = build_configs(&self.files)?;
+        *self.configs.write().unwrap() = configs;
+        info!("Reloaded TLS certificates from {}", self.files.cert_file.display());
+        Ok(())
+    }
+
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.configs.read().unwrap().0.clone())
+    }
+
+    pub(crate) fn connector(&self) -> TlsConnector {
+        TlsConnector::from(self.configs.read().unwrap().1.clone())
+    }
+}
+
+impl Debug for PeerTls {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerTls").field("files", &self.files).finish()
+    }
+}
+
+fn tls_error(path: &Path, e: impl std::fmt::Display) -> RuftError {
+    RuftError::InvalidState(format!("Invalid TLS file {}: {}", path.display(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| tls_error(path, e))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| tls_error(path, e))?;
+    if certs.is_empty() {
+        return Err(tls_error(path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn build_configs(files: &TlsConfig) -> Result<(Arc<ServerConfig>, Arc<ClientConfig>)> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    for ca in read_certs(&files.ca_file)? {
+        roots.add(ca).map_err(|e| tls_error(&files.ca_file, e))?;
+    }
+    let roots = Arc::new(roots);
+    let certs = read_certs(&files.cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&files.key_file).map_err(|e| tls_error(&files.key_file, e))?;
+
+    // Peers have to present a certificate of the cluster CA as well
+    let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone())
+        .build()
+        .map_err(|e| tls_error(&files.ca_file, e))?;
+    let mut server = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| tls_error(&files.cert_file, e))?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs.clone(), key.clone_key())
+        .map_err(|e| tls_error(&files.cert_file, e))?;
+    server.alpn_protocols = vec![b"h2".to_vec()];
+
+    let mut client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| tls_error(&files.cert_file, e))?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)
+        .map_err(|e| tls_error(&files.cert_file, e))?;
+    client.alpn_protocols = vec![b"h2".to_vec()];
+    Ok((Arc::new(server), Arc::new(client)))
+}
+
+/// Server name the certificate of node `id` is checked against when connecting to it
+pub(crate) fn server_name(id: NodeId) -> ServerName<'static> {
+    ServerName::try_from(peer_name(id)).expect("peer names are valid DNS names")
+}
+
+/// A server side TLS connection that tells requests which certificates the client presented
+pub(crate) struct PeerStream(pub(crate) TlsStream<TcpStream>);
+
+/// Connection info of a [`PeerStream`], found in the extensions of every request
+#[derive(Clone)]
+pub(crate) struct PeerCerts(Option<Arc<Vec<CertificateDer<'static>>>>);
+
+impl Connected for PeerStream {
+    type ConnectInfo = PeerCerts;
+
+    fn connect_info(&self) -> PeerCerts {
+        PeerCerts(self.0.get_ref().1.peer_certificates().map(|certs| Arc::new(certs.to_vec())))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Certificates the client presented in the TLS handshake of the request's connection
+pub(crate) fn peer_certs<T>(request: &Request<T>) -> Option<Arc<Vec<CertificateDer<'static>>>> {
+    request.extensions().get::<PeerCerts>().and_then(|info| info.0.clone())
+}
+
+/// Check that the client certificate of a TLS connection belongs to node `sender`
+///
+/// The chain itself was verified during the handshake, this only stops a peer
+/// of the cluster from sending messages in the name of another node.
+pub(crate) fn verify_sender(certs: Option<Arc<Vec<CertificateDer<'static>>>>, sender: NodeId) -> std::result::Result<(), Status> {
+    let certs = certs.ok_or_else(|| Status::unauthenticated("Peer presented no certificate"))?;
+    let cert = certs.first().ok_or_else(|| Status::unauthenticated("Peer presented no certificate"))?;
+    let name = server_name(sender);
+    ParsedCertificate::try_from(cert)
+        .and_then(|cert| verify_server_name(&cert, &name))
+        .map_err(|e| Status::permission_denied(format!("Certificate does not identify node {}: {}", sender, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{Endpoint, GrpcTransport, PreVoteRequest, Transport};
+    use crate::{Config, Ruft};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tonic::Code;
+
+    fn new_ca() -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+    }
+
+    /// Write the CA and a certificate naming node `id` to `dir`
+    fn write_certs(dir: &str, ca: &CertifiedIssuer<'static, KeyPair>, id: NodeId) -> TlsConfig {
+        std::fs::create_dir_all(dir).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![peer_name(id)]).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, ca).unwrap();
+        let files = TlsConfig::new(PathBuf::from(dir).join("ca.pem"), PathBuf::from(dir).join("node.pem"), PathBuf::from(dir).join("node.key"));
+        std::fs::write(&files.ca_file, ca.pem()).unwrap();
+        std::fs::write(&files.cert_file, cert.pem()).unwrap();
+        std::fs::write(&files.key_file, key.serialize_pem()).unwrap();
+        files
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn tls_node(test: &str, endpoint: &Endpoint, members: Vec<Endpoint>, tls: TlsConfig) -> Ruft {
+        let dir = format!("/tmp/raft/tls/{}/node{}", test, endpoint.id());
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config::builder().members(members).data_dir(dir).heartbeat_interval(50).tls(tls).build();
+        Ruft::new(endpoint.clone(), config).unwrap()
+    }
+
+    /// Whether node 2 gets an answer from `endpoint` through `transport`, on a fresh connection
+    async fn reaches(transport: &GrpcTransport, endpoint: &Endpoint) -> bool {
+        transport.connect(endpoint).await.unwrap().pre_vote(pre_vote(2)).await.is_ok()
+    }
+
+    fn pre_vote(candidate_id: u64) -> PreVoteRequest {
+        PreVoteRequest {
+            term: 0,
+            candidate_id,
+            last_log_index: 0,
+            last_log_term: 0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_cluster_elects_leader() {
+        let ca = new_ca();
+        let members: Vec<Endpoint> = (1..=3).map(|id| Endpoint::new(NodeId::new(id), "127.0.0.1".into(), free_port())).collect();
+        let mut nodes = vec![];
+        for member in &members {
+            let tls = write_certs(&format!("/tmp/raft/tls/cluster/certs{}", member.id()), &ca, member.id());
+            let node = tls_node("cluster", member, members.clone(), tls);
+            node.start().await.unwrap();
+            nodes.push(node);
+        }
+
+        let leader = nodes[0].wait_for_leader(std::time::Duration::from_secs(5)).await.unwrap();
+        for node in &nodes {
+            assert_eq!(node.wait_for_leader(std::time::Duration::from_secs(5)).await.unwrap(), leader);
+        }
+        for node in &nodes {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_identity_is_checked() {
+        let ca = new_ca();
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), free_port());
+        let node = tls_node("identity", &endpoint, vec![endpoint.clone()], write_certs("/tmp/raft/tls/identity/certs1", &ca, NodeId::new(1)));
+        node.start().await.unwrap();
+
+        // Node 2 may speak for itself but not for node 3
+        let peer = GrpcTransport::with_tls(PeerTls::load(write_certs("/tmp/raft/tls/identity/certs2", &ca, NodeId::new(2))).unwrap());
+        let client = peer.connect(&endpoint).await.unwrap();
+        client.pre_vote(pre_vote(2)).await.unwrap();
+        match client.pre_vote(pre_vote(3)).await {
+            Err(RuftError::Rpc(status)) => assert_eq!(status.code(), Code::PermissionDenied),
+            other => panic!("expected PermissionDenied, got {:?}", other),
+        }
+
+        // The server has to be the node the endpoint names
+        assert!(!reaches(&peer, &Endpoint::new(NodeId::new(5), "127.0.0.1".into(), endpoint.port())).await);
+        // Plaintext and certificates of another CA are refused
+        assert!(!reaches(&GrpcTransport::default(), &endpoint).await);
+        let stranger = GrpcTransport::with_tls(PeerTls::load(write_certs("/tmp/raft/tls/identity/stranger", &new_ca(), NodeId::new(2))).unwrap());
+        assert!(!reaches(&stranger, &endpoint).await);
+
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_certificates_reload_without_restart() {
+        let (old_ca, new_ca) = (new_ca(), new_ca());
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), 0);
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let dir = "/tmp/raft/tls/reload/node1";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config::builder()
+            .members(vec![endpoint.clone()])
+            .data_dir(dir)
+            .heartbeat_interval(50)
+            .listen_addr(any_port)
+            .tls(write_certs("/tmp/raft/tls/reload/certs1", &old_ca, NodeId::new(1)))
+            .build();
+        let node = Ruft::new(endpoint, config).unwrap();
+        node.start().await.unwrap();
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), node.local_addr().unwrap().port());
+
+        let old_peer = GrpcTransport::with_tls(PeerTls::load(write_certs("/tmp/raft/tls/reload/old2", &old_ca, NodeId::new(2))).unwrap());
+        let new_peer = GrpcTransport::with_tls(PeerTls::load(write_certs("/tmp/raft/tls/reload/new2", &new_ca, NodeId::new(2))).unwrap());
+        assert!(reaches(&old_peer, &endpoint).await);
+        assert!(!reaches(&new_peer, &endpoint).await);
+
+        // Broken files leave the running certificates in place
+        std::fs::write("/tmp/raft/tls/reload/certs1/node.pem", "garbage").unwrap();
+        assert!(node.reload_tls().is_err());
+        assert!(reaches(&old_peer, &endpoint).await);
+
+        write_certs("/tmp/raft/tls/reload/certs1", &new_ca, NodeId::new(1));
+        node.reload_tls().unwrap();
+        assert!(reaches(&new_peer, &endpoint).await);
+        assert!(!reaches(&old_peer, &endpoint).await);
+
+        node.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+use crate::rpc::Endpoint;
+use crate::rpc::client::{Backoff, RaftRpcClient, RemoteClient};
+use crate::rpc::server::{RaftRpcHandler, run_server};
+use crate::rpc::tls::{PeerStream, PeerTls};
+use crate::{Clock, Random, Result, RuftError, ThreadRandom, TokioClock};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::TcpIncoming;
+use tracing::{debug, warn};
+
+/// Resolves once a server has to stop accepting messages
+pub type Shutdown = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A server that is bound and receives messages once `run` is polled
+pub struct Serving {
+    /// Address the server listens on, `None` for transports without sockets
+    pub local_addr: Option<SocketAddr>,
+    /// Receives messages until the shutdown signal resolves or the server fails
+    pub run: Pin<Box<dyn Future<Output = Result<()>> + Send>>,
+}
+
+/// Pluggable network layer between Raft nodes
+///
+/// A transport knows how to reach a peer (`connect`) and how to deliver
+/// incoming messages to the local node (`serve`).
+#[tonic::async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Build a client for talking to `endpoint`
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Arc<dyn RaftRpcClient>>;
+
+    /// Bind a server for messages addressed to `endpoint`, on `listen_addr` if given
+    ///
+    /// Binding happens right away, so a taken address fails here and the bound
+    /// address is known before the first message arrives.
+    async fn serve(&self, endpoint: Endpoint, listen_addr: Option<SocketAddr>, handler: Arc<dyn RaftRpcHandler>, shutdown: Shutdown) -> Result<Serving>;
+}
+
+/// Transport over tonic gRPC, used by default
+///
+/// Plaintext unless built [`GrpcTransport::with_tls`], which is what
+/// [`crate::ConfigBuilder::tls`] does.
+#[derive(Clone)]
+pub struct GrpcTransport {
+    tls: Option<Arc<PeerTls>>,
+    rpc_timeout: Option<Duration>,
+    backoff: Backoff,
+    clock: Arc<dyn Clock>,
+    random: Arc<dyn Random>,
+}
+
+impl Default for GrpcTransport {
+    fn default() -> Self {
+        GrpcTransport {
+            tls: None,
+            rpc_timeout: None,
+            backoff: Backoff::default(),
+            clock: Arc::new(TokioClock),
+            random: Arc::new(ThreadRandom),
+        }
+    }
+}
+
+impl std::fmt::Debug for GrpcTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GrpcTransport")
+            .field("tls", &self.tls)
+            .field("rpc_timeout", &self.rpc_timeout)
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GrpcTransport {
+    /// Talk to peers over mutual TLS with the certificates of `tls`
+    pub fn with_tls(tls: Arc<PeerTls>) -> Self {
+        GrpcTransport { tls: Some(tls), ..Self::default() }
+    }
+
+    /// Give up on a unary RPC after `timeout`, and tell the server so; no deadline by default
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long to wait between attempts to reconnect to an unreachable peer
+    pub fn reconnect_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Measure RPC deadlines and reconnect backoffs with `clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Jitter reconnect backoffs with `random`
+    pub fn random(mut self, random: Arc<dyn Random>) -> Self {
+        self.random = random;
+        self
+    }
+}
+
+/// Longest a connecting peer may take for its TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept connections on `listener` and hand them on once their TLS handshake succeeds
+///
+/// Handshakes run in their own tasks so a slow peer does not hold up others,
+/// accepting stops once the server drops `incoming`.
+async fn accept_tls(listener: TcpListener, tls: Arc<PeerTls>, incoming: mpsc::Sender<std::io::Result<PeerStream>>) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = incoming.closed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        let _ = stream.set_nodelay(true);
+        let acceptor = tls.acceptor();
+        let incoming = incoming.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = incoming.send(Ok(PeerStream(stream))).await;
+                }
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                Err(_) => debug!("TLS handshake with {} timed out", peer),
+            }
+        });
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for GrpcTransport {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Arc<dyn RaftRpcClient>> {
+        // Connects on first use, so this succeeds even while the peer is down
+        let client = RemoteClient::new(endpoint, self.tls.clone(), self.rpc_timeout, self.backoff, self.clock.clone(), self.random.clone())?;
+        Ok(Arc::new(client))
+    }
+
+    async fn serve(&self, endpoint: Endpoint, listen_addr: Option<SocketAddr>, handler: Arc<dyn RaftRpcHandler>, shutdown: Shutdown) -> Result<Serving> {
+        let addr = match listen_addr {
+            Some(addr) => addr,
+            None => tokio::net::lookup_host((endpoint.host().as_str(), endpoint.port()))
+                .await?
+                .next()
+                .ok_or_else(|| RuftError::InvalidState(format!("Endpoint {} does not resolve to an address", endpoint)))?,
+        };
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let tls = self.tls.clone();
+        let run = async move {
+            let served = match tls {
+                Some(tls) => {
+                    let (tx, rx) = mpsc::channel(16);
+                    tokio::spawn(accept_tls(listener, tls, tx));
+                    run_server(handler, ReceiverStream::new(rx), true, shutdown).await
+                }
+                None => run_server(handler, TcpIncoming::from(listener).with_nodelay(Some(true)), false, shutdown).await,
+            };
+            served.map_err(|e| RuftError::Rpc(tonic::Status::internal(format!("Rpc server failed: {}", e))))
+        };
+        Ok(Serving {
+            local_addr: Some(local_addr),
+            run: Box::pin(run),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{AppendEntriesRequest, NodeId, PreVoteRequest, RequestVoteRequest};
+    use crate::{Config, Ruft, RuftError, Uuid};
+
+    fn grpc_node(id: NodeId, listen_addr: SocketAddr) -> Ruft {
+        let endpoint = Endpoint::new(id, "127.0.0.1".into(), 0);
+        let dir = format!("/tmp/raft/grpc_bind/node{}", id);
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config::builder().members(vec![endpoint.clone()]).data_dir(dir).heartbeat_interval(50).listen_addr(listen_addr).build();
+        Ruft::new(endpoint, config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_grpc_servers_bind_ephemeral_ports() {
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let first = grpc_node(NodeId::new(1), any_port);
+        let second = grpc_node(NodeId::new(2), any_port);
+        assert_eq!(first.local_addr(), None);
+        first.start().await.unwrap();
+        second.start().await.unwrap();
+
+        let first_addr = first.local_addr().unwrap();
+        let second_addr = second.local_addr().unwrap();
+        assert_ne!(first_addr.port(), 0);
+        assert_ne!(first_addr.port(), second_addr.port());
+
+        // The reported address is the one peers reach the node on
+        let peer = Endpoint::new(NodeId::new(9), first_addr.ip().to_string(), first_addr.port());
+        let client = GrpcTransport::default().connect(&peer).await.unwrap();
+        let resp = client
+            .pre_vote(PreVoteRequest {
+                term: 0,
+                candidate_id: 9,
+                last_log_index: 0,
+                last_log_term: 0,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(!resp.vote_granted);
+
+        // A taken address fails the start instead of a background task
+        let third = grpc_node(NodeId::new(3), first_addr);
+        assert!(third.start().await.is_err());
+
+        first.shutdown().await.unwrap();
+        second.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_grpc_cluster_forms_in_any_order() {
+        let members: Vec<Endpoint> = (1..=3).map(|id| Endpoint::new(NodeId::new(id), "127.0.0.1".into(), free_port())).collect();
+        let mut nodes = vec![];
+        // Each node starts before the peers after it are up
+        for member in &members {
+            let dir = format!("/tmp/raft/grpc_order/node{}", member.id());
+            let _ = std::fs::remove_dir_all(&dir);
+            let config = Config::builder().members(members.clone()).data_dir(dir).heartbeat_interval(50).build();
+            let node = Ruft::new(member.clone(), config).unwrap();
+            node.start().await.unwrap();
+            nodes.push(node);
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        }
+
+        let leader = nodes[0].wait_for_leader(std::time::Duration::from_secs(5)).await.unwrap();
+        for node in &nodes {
+            assert_eq!(node.wait_for_leader(std::time::Duration::from_secs(5)).await.unwrap(), leader);
+        }
+        let leader = &nodes[leader.id().get() as usize - 1];
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let peers = leader.metrics().await.unwrap().peers;
+        assert_eq!(peers.len(), 2);
+        assert!(peers.values().all(|health| health.reachable), "{:?}", peers);
+
+        for node in &nodes {
+            node.shutdown().await.unwrap();
+        }
+    }
+
+    /// Cluster id of a node, once the no-op entry of its first leader is committed
+    async fn committed_cluster_id(node: &Ruft) -> Uuid {
+        for _ in 0..100 {
+            if let Some(cluster_id) = node.metrics().await.unwrap().cluster_id {
+                return cluster_id;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no cluster id was committed");
+    }
+
+    #[tokio::test]
+    async fn test_recreated_cluster_gets_new_id() {
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), 0);
+        let mut ids = vec![];
+        for dir in ["/tmp/raft/grpc_cluster_id/first", "/tmp/raft/grpc_cluster_id/second"] {
+            let _ = std::fs::remove_dir_all(dir);
+            let config = Config::builder().members(vec![endpoint.clone()]).data_dir(dir).heartbeat_interval(50).listen_addr(any_port).build();
+            let node = Ruft::new(endpoint.clone(), config.clone()).unwrap();
+            node.start().await.unwrap();
+            ids.push(committed_cluster_id(&node).await);
+            node.shutdown().await.unwrap();
+
+            // A restart keeps the id it committed
+            let node = Ruft::new(endpoint.clone(), config).unwrap();
+            assert_eq!(node.metrics().await.unwrap().cluster_id, ids.last().copied());
+        }
+        // Same members at the same address, yet a cluster of its own
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn test_other_cluster_is_refused() {
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), 0);
+        let dir = "/tmp/raft/grpc_cluster_id/node1";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config::builder().members(vec![endpoint.clone()]).data_dir(dir).heartbeat_interval(50).listen_addr(any_port).build();
+        let node = Ruft::new(endpoint.clone(), config.clone()).unwrap();
+        node.start().await.unwrap();
+        let cluster_id = committed_cluster_id(&node).await;
+
+        let addr = node.local_addr().unwrap();
+        let client = GrpcTransport::default().connect(&Endpoint::new(NodeId::new(9), addr.ip().to_string(), addr.port())).await.unwrap();
+        let pre_vote = |cluster_id: Option<Uuid>| PreVoteRequest {
+            candidate_id: 9,
+            cluster_id: cluster_id.map(|id| id.as_bytes().to_vec()).unwrap_or_default(),
+            ..Default::default()
+        };
+        // Peers that do not know their cluster yet are still heard
+        assert!(client.pre_vote(pre_vote(None)).await.is_ok());
+        assert!(client.pre_vote(pre_vote(Some(cluster_id))).await.is_ok());
+        match client.pre_vote(pre_vote(Some(Uuid::from_u128(1)))).await {
+            Err(RuftError::ClusterMismatch(_)) => {}
+            other => panic!("expected a cluster mismatch, got {:?}", other),
+        }
+        node.shutdown().await.unwrap();
+
+        // The data directory stays bound to its cluster
+        let mut other = config.clone();
+        other.cluster_id = Some(Uuid::from_u128(1));
+        assert!(matches!(Ruft::new(endpoint.clone(), other), Err(RuftError::InvalidState(_))));
+        let mut same = config;
+        same.cluster_id = Some(cluster_id);
+        assert!(Ruft::new(endpoint, same).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stray_node_cannot_disrupt_running_cluster() {
+        let endpoint = Endpoint::new(NodeId::new(1), "127.0.0.1".into(), free_port());
+        let dir = "/tmp/raft/grpc_cluster_id/running";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config::builder().members(vec![endpoint.clone()]).data_dir(dir).heartbeat_interval(50).build();
+        let node = Ruft::new(endpoint.clone(), config).unwrap();
+        node.start().await.unwrap();
+        committed_cluster_id(&node).await;
+        let term = node.metrics().await.unwrap().term;
+
+        // A fresh node pointed at the running one by mistake does not know any cluster yet
+        let stray_endpoint = Endpoint::new(NodeId::new(9), "127.0.0.1".into(), free_port());
+        let dir = "/tmp/raft/grpc_cluster_id/stray";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config::builder().members(vec![endpoint.clone(), stray_endpoint.clone()]).data_dir(dir).heartbeat_interval(50).build();
+        let stray = Ruft::new(stray_endpoint, config).unwrap();
+        stray.start().await.unwrap();
+
+        let client = GrpcTransport::default().connect(&endpoint).await.unwrap();
+        let vote = RequestVoteRequest {
+            term: term + 5,
+            candidate_id: 9,
+            ..Default::default()
+        };
+        assert!(matches!(client.request_vote(vote).await, Err(RuftError::ClusterMismatch(_))));
+        let heartbeat = AppendEntriesRequest {
+            term: term + 5,
+            leader_id: 9,
+            ..Default::default()
+        };
+        assert!(matches!(client.append_entries(heartbeat).await, Err(RuftError::ClusterMismatch(_))));
+
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let metrics = node.metrics().await.unwrap();
+        assert_eq!((metrics.state.as_str(), metrics.term), ("Leader", term));
+
+        stray.shutdown().await.unwrap();
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_node_without_members_joins_cluster_of_leader() {
+        let endpoint = Endpoint::new(NodeId::new(2), "127.0.0.1".into(), 0);
+        let dir = "/tmp/raft/grpc_cluster_id/node2";
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config::builder().data_dir(dir).heartbeat_interval(50).listen_addr("127.0.0.1:0".parse().unwrap()).build();
+        let node = Ruft::new(endpoint, config).unwrap();
+        node.start().await.unwrap();
+        assert_eq!(node.metrics().await.unwrap().cluster_id, None);
+
+        let addr = node.local_addr().unwrap();
+        let client = GrpcTransport::default().connect(&Endpoint::new(NodeId::new(1), addr.ip().to_string(), addr.port())).await.unwrap();
+        let cluster_id = Uuid::from_u128(7);
+        let heartbeat = |cluster_id: Uuid| AppendEntriesRequest {
+            term: 1,
+            leader_id: 1,
+            cluster_id: cluster_id.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        assert!(client.append_entries(heartbeat(cluster_id)).await.unwrap().success);
+        assert_eq!(node.metrics().await.unwrap().cluster_id, Some(cluster_id));
+        assert!(matches!(client.append_entries(heartbeat(Uuid::from_u128(8))).await, Err(RuftError::ClusterMismatch(_))));
+
+        node.shutdown().await.unwrap();
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_ipv6_endpoint_url_is_bracketed() {
+        assert_eq!(Endpoint::new(NodeId::new(1), "::1".into(), 5000).url(), "http://[::1]:5000");
+        assert_eq!(Endpoint::new(NodeId::new(1), "127.0.0.1".into(), 5000).url(), "http://127.0.0.1:5000");
+    }
+}
@@ -0,0 +1,738 @@
+use crate::rpc::command::{CmdReq, CmdResp};
+use crate::rpc::{Endpoint, LogEntry, NodeId};
+use crate::sim::SimConfig;
+use crate::sim::network::{SimNetwork, SimTransport};
+use crate::{Clock, Config, Durability, Ruft, RuftError, SeededRandom, Sm, SmView, TokioClock};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// State machine of a simulated node: the commands it applied, in order
+///
+/// The harness keeps a handle to the state of every running node. Snapshots
+/// are the commands joined by newlines.
+#[derive(Clone, Default)]
+pub struct Applied(Arc<Mutex<Vec<String>>>);
+
+impl Applied {
+    pub fn commands(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Sm for Applied {
+    fn apply(&mut self, _index: u64, command: &[u8]) -> Option<Bytes> {
+        let mut commands = self.0.lock().unwrap();
+        commands.push(String::from_utf8_lossy(command).into_owned());
+        Some(Bytes::from(commands.len().to_string()))
+    }
+
+    fn view(&self) -> Box<dyn SmView> {
+        Box::new(self.commands())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let mut text = String::new();
+        r.read_to_string(&mut text)?;
+        *self.0.lock().unwrap() = text.split('\n').filter(|c| !c.is_empty()).map(String::from).collect();
+        Ok(())
+    }
+}
+
+impl SmView for Vec<String> {
+    fn snapshot(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(self.join("\n").as_bytes())
+    }
+}
+
+/// Test harness running N [`Ruft`] nodes on a [`SimNetwork`]
+///
+/// Nodes are addressed by their position `0..n`, node `i` has endpoint id `i + 1`.
+/// Network and election jitter derive from one seed; run the harness on a
+/// current-thread runtime with paused time and a failing seed replays exactly.
+/// Every observation of the cluster also checks election safety: no two nodes
+/// may ever be leader in the same term. All nodes trace into one file that
+/// [`Cluster::check_trace`] validates against the TLA+ spec.
+pub struct Cluster {
+    pub network: Arc<SimNetwork>,
+    /// Time of the network, the nodes and the waits of the harness
+    clock: Arc<dyn Clock>,
+    seed: u64,
+    members: Vec<Endpoint>,
+    /// Running nodes, `None` while crashed; behind a lock so faults can be injected while clients run
+    nodes: Mutex<Vec<Option<Ruft>>>,
+    /// State machines of the running nodes
+    applied: Mutex<Vec<Applied>>,
+    data_dir: String,
+    snapshot_entries: Option<u64>,
+    durability: Durability,
+    heartbeat_millis: u64,
+    leaders_by_term: Mutex<HashMap<u64, usize>>,
+}
+
+impl Cluster {
+    /// Start a cluster in a fresh data directory under `/tmp/raft/sim/<name>`
+    pub async fn start(name: &str, size: usize, seed: u64, sim: SimConfig) -> Self {
+        Self::start_with_snapshots(name, size, seed, sim, None).await
+    }
+
+    /// Start a cluster whose nodes snapshot every `snapshot_entries` applied entries
+    pub async fn start_with_snapshots(name: &str, size: usize, seed: u64, sim: SimConfig, snapshot_entries: Option<u64>) -> Self {
+        Self::start_with_options(name, size, seed, sim, snapshot_entries, Durability::EveryWrite).await
+    }
+
+    /// Start a cluster whose nodes make their logs durable with `durability`
+    pub async fn start_with_durability(name: &str, size: usize, seed: u64, sim: SimConfig, durability: Durability) -> Self {
+        Self::start_with_options(name, size, seed, sim, None, durability).await
+    }
+
+    async fn start_with_options(name: &str, size: usize, seed: u64, sim: SimConfig, snapshot_entries: Option<u64>, durability: Durability) -> Self {
+        let data_dir = format!("/tmp/raft/sim/{}", name);
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        let clock: Arc<dyn Clock> = Arc::new(TokioClock);
+        let cluster = Cluster {
+            network: SimNetwork::new(seed, sim, clock.clone()),
+            clock,
+            seed,
+            members: (1..=size as u64).map(|id| Endpoint::new(NodeId::new(id), "sim".into(), 7000 + id as u16)).collect(),
+            nodes: Mutex::new((0..size).map(|_| None).collect()),
+            applied: Mutex::new(vec![Applied::default(); size]),
+            data_dir,
+            snapshot_entries,
+            durability,
+            heartbeat_millis: 50,
+            leaders_by_term: Mutex::new(HashMap::new()),
+        };
+        for i in 0..size {
+            cluster.launch(i).await;
+        }
+        cluster
+    }
+
+    async fn launch(&self, i: usize) {
+        let endpoint = self.members[i].clone();
+        let mut config = Config::builder()
+            .members(self.members.clone())
+            .data_dir(format!("{}/node{}", self.data_dir, endpoint.id()))
+            .heartbeat_interval(self.heartbeat_millis)
+            .durability(self.durability)
+            .trace_file(self.trace_file());
+        if let Some(entries) = self.snapshot_entries {
+            // Tiny chunks, so that even small snapshots are streamed in many pieces
+            config = config.snapshot_entries(entries).snapshot_chunk_bytes(16);
+        }
+        let config = config.build();
+        let transport = Arc::new(SimTransport::new(self.network.clone(), endpoint.clone()));
+        let random = Arc::new(SeededRandom::new(self.seed.wrapping_add(endpoint.id().get())));
+        // A restarted node rebuilds its state machine from the snapshot and the log
+        let applied = Applied::default();
+        self.applied.lock().unwrap()[i] = applied.clone();
+        let ruft = Ruft::builder(endpoint, config)
+            .transport(transport)
+            .clock(self.clock.clone())
+            .random(random)
+            .state_machine(applied)
+            .build()
+            .unwrap();
+        ruft.start().await.unwrap();
+        self.nodes.lock().unwrap()[i] = Some(ruft);
+    }
+
+    fn trace_file(&self) -> String {
+        format!("{}/trace.ndjson", self.data_dir)
+    }
+
+    /// Replay the trace of every node so far against the spec, returning the number of events
+    pub fn check_trace(&self) -> usize {
+        let members: Vec<NodeId> = self.members.iter().map(|m| m.id()).collect();
+        crate::trace::check_file(self.trace_file().as_ref(), &members).unwrap_or_else(|e| panic!("trace diverges from the spec: {}", e))
+    }
+
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn id(&self, i: usize) -> NodeId {
+        self.members[i].id()
+    }
+
+    pub fn node(&self, i: usize) -> Ruft {
+        self.try_node(i).unwrap_or_else(|| panic!("node {} is crashed", i))
+    }
+
+    /// The node at position `i`, `None` while it is crashed
+    pub fn try_node(&self, i: usize) -> Option<Ruft> {
+        self.nodes.lock().unwrap()[i].clone()
+    }
+
+    /// Positions of the nodes that are currently running
+    pub fn alive(&self) -> Vec<usize> {
+        (0..self.size()).filter(|i| self.try_node(*i).is_some()).collect()
+    }
+
+    /// Crash a node: it stops immediately and its messages are lost, the data dir is kept
+    pub async fn crash(&self, i: usize) {
+        self.network.crash(self.id(i));
+        let ruft = self.nodes.lock().unwrap()[i].take();
+        if let Some(ruft) = ruft {
+            ruft.node().halt().await;
+        }
+    }
+
+    /// Shut a node down gracefully, a leader hands over leadership first
+    pub async fn shutdown(&self, i: usize) {
+        let ruft = self.nodes.lock().unwrap()[i].take();
+        if let Some(ruft) = ruft {
+            ruft.shutdown().await.unwrap();
+        }
+    }
+
+    /// Start a crashed node again from its data dir
+    pub async fn restart(&self, i: usize) {
+        self.network.restart(self.id(i));
+        self.launch(i).await;
+    }
+
+    /// Cut the network into groups of node positions
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let groups: Vec<Vec<NodeId>> = groups.iter().map(|g| g.iter().map(|i| self.id(*i)).collect()).collect();
+        self.network.partition(&groups);
+    }
+
+    /// Cut the link from node `from` to node `to` only
+    pub fn block(&self, from: usize, to: usize) {
+        self.network.block(self.id(from), self.id(to));
+    }
+
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
+    /// Current leaders among `among` as (position, term), recording them for the election safety check
+    pub async fn leaders(&self, among: &[usize]) -> Vec<(usize, u64)> {
+        let mut leaders = vec![];
+        for &i in among {
+            let Some(node) = self.try_node(i) else { continue };
+            if node.is_leader().await {
+                leaders.push((i, node.current_term().await));
+            }
+        }
+
+        let mut seen = self.leaders_by_term.lock().unwrap();
+        for &(i, term) in &leaders {
+            let first = *seen.entry(term).or_insert(i);
+            assert_eq!(first, i, "nodes {} and {} were both leader in term {}", first, i, term);
+        }
+        leaders
+    }
+
+    /// Wait until exactly one node among `among` is leader and return its position
+    pub async fn wait_for_leader(&self, among: &[usize], timeout: Duration) -> usize {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            let leaders = self.leaders(among).await;
+            if let [(leader, _)] = leaders.as_slice() {
+                return *leader;
+            }
+            assert!(self.clock.now() < deadline, "no single leader among {:?}, got {:?}", among, leaders);
+            self.clock.sleep(Duration::from_millis(self.heartbeat_millis)).await;
+        }
+    }
+
+    /// Submit through whichever node in `among` is leader, retrying until the command commits
+    pub async fn submit(&self, among: &[usize], data: &str, timeout: Duration) -> CmdResp {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            let leader = self.wait_for_leader(among, deadline.saturating_duration_since(self.clock.now())).await;
+            let cmd = CmdReq {
+                id: data.to_string(),
+                data: Bytes::from(data.to_string()),
+            };
+            let resp = self.node(leader).submit(cmd).await;
+            if matches!(resp, CmdResp::Success { .. }) || self.clock.now() >= deadline {
+                return resp;
+            }
+        }
+    }
+
+    /// Wait until every node in `among` has committed at least `index`
+    pub async fn wait_for_commit(&self, among: &[usize], index: u64, timeout: Duration) {
+        let deadline = self.clock.now() + timeout;
+        for &i in among {
+            while self.node(i).commit_index().await < index {
+                assert!(self.clock.now() < deadline, "node {} did not commit index {}", i, index);
+                self.clock.sleep(Duration::from_millis(self.heartbeat_millis)).await;
+            }
+        }
+    }
+
+    /// Committed entries of a node that are not compacted yet
+    pub async fn committed(&self, i: usize) -> Vec<LogEntry> {
+        self.node(i).node().committed_entries().await
+    }
+
+    /// Commands applied by the state machine of a node
+    pub fn applied(&self, i: usize) -> Vec<String> {
+        self.applied.lock().unwrap()[i].commands()
+    }
+
+    /// Log matching: committed entries of the given nodes must agree at every index both still hold
+    pub async fn check_logs(&self, among: &[usize]) {
+        let mut logs = vec![];
+        for &i in among {
+            let log: HashMap<u64, LogEntry> = self.committed(i).await.into_iter().map(|e| (e.index, e)).collect();
+            logs.push((i, log));
+        }
+        for (i, log) in &logs {
+            for (j, other) in &logs {
+                for (index, entry) in log {
+                    if let Some(theirs) = other.get(index) {
+                        assert_eq!(entry, theirs, "committed logs of nodes {} and {} diverge at {}", i, j, index);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::EntryType;
+    use crate::rpc::command::ErrorCode;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashSet;
+    use tokio::task::JoinSet;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn commands(entries: &[LogEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .filter(|e| e.entry_type == EntryType::Command as i32)
+            .map(|e| String::from_utf8(e.command.clone()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replication_on_lossy_network() {
+        let cluster = Cluster::start("lossy", 5, 1, SimConfig::lossy()).await;
+        let all = cluster.alive();
+
+        for i in 0..10 {
+            let resp = cluster.submit(&all, &format!("cmd{}", i), TIMEOUT).await;
+            assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+        }
+
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        cluster.check_logs(&all).await;
+
+        let expected: Vec<String> = (0..10).map(|i| format!("cmd{}", i)).collect();
+        assert_eq!(commands(&cluster.committed(leader).await), expected);
+
+        // Whichever leader named the cluster, every node took the committed id
+        let mut ids = HashSet::new();
+        for &i in &all {
+            ids.insert(cluster.node(i).metrics().await.unwrap().cluster_id);
+        }
+        assert_eq!(ids.len(), 1, "{:?}", ids);
+        assert!(ids.iter().all(Option::is_some));
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_isolated_leader_is_replaced() {
+        let cluster = Cluster::start("isolated_leader", 5, 2, SimConfig::default()).await;
+        let all = cluster.alive();
+        let old_leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let old_term = cluster.node(old_leader).current_term().await;
+
+        let majority: Vec<usize> = all.iter().copied().filter(|i| *i != old_leader).collect();
+        cluster.partition(&[&[old_leader], &majority]);
+
+        // The old leader still believes it leads but can't commit anything
+        let stale = cluster
+            .node(old_leader)
+            .submit(CmdReq {
+                id: "stale".into(),
+                data: Bytes::from("stale"),
+            })
+            .await;
+        assert!(!matches!(stale, CmdResp::Success { .. }));
+
+        let resp = cluster.submit(&majority, "fresh", TIMEOUT).await;
+        assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+        let new_leader = cluster.wait_for_leader(&majority, TIMEOUT).await;
+        assert!(cluster.node(new_leader).current_term().await > old_term);
+
+        // After healing, the old leader steps down and drops its uncommitted entry
+        cluster.heal();
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        cluster.check_logs(&all).await;
+        assert_eq!(commands(&cluster.committed(old_leader).await), vec!["fresh".to_string()]);
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_one_way_partition() {
+        let cluster = Cluster::start("one_way", 3, 3, SimConfig::default()).await;
+        let all = cluster.alive();
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let follower = (leader + 1) % 3;
+
+        // The follower still reaches the leader but never hears from it
+        cluster.block(leader, follower);
+        for i in 0..5 {
+            let resp = cluster.submit(&all, &format!("cmd{}", i), TIMEOUT).await;
+            assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+        }
+
+        cluster.heal();
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        cluster.check_logs(&all).await;
+        assert_eq!(commands(&cluster.committed(follower).await).len(), 5);
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_leader_is_unknown_until_elected() {
+        let cluster = Cluster::start("unknown_leader", 3, 13, SimConfig::default()).await;
+        let all = cluster.alive();
+
+        // A fresh node does not point clients at itself
+        assert_eq!(cluster.node(0).get_leader().await, None);
+        let cmd = CmdReq {
+            id: "early".into(),
+            data: Bytes::from("early"),
+        };
+        assert!(matches!(cluster.node(0).submit(cmd).await, CmdResp::NotLeader { leader: None }));
+
+        let leader = cluster.node(0).wait_for_leader(TIMEOUT).await.unwrap();
+        assert_eq!(leader, cluster.members[cluster.wait_for_leader(&all, TIMEOUT).await]);
+        for i in all {
+            assert_eq!(cluster.node(i).wait_for_leader(TIMEOUT).await.unwrap(), leader);
+            assert_eq!(cluster.node(i).get_leader().await, Some(leader.clone()));
+        }
+
+        // Alone, the last node stops hearing from the leader and cannot elect a new one
+        let survivor = cluster.members.iter().position(|m| *m != leader).unwrap();
+        for i in (0..cluster.size()).filter(|i| *i != survivor) {
+            cluster.crash(i).await;
+        }
+        let node = cluster.node(survivor);
+        let deadline = cluster.clock.now() + TIMEOUT;
+        while node.get_leader().await.is_some() {
+            assert!(cluster.clock.now() < deadline, "node {} still follows the crashed leader", survivor);
+            cluster.clock.sleep(Duration::from_millis(cluster.heartbeat_millis)).await;
+        }
+        assert!(matches!(node.wait_for_leader(Duration::from_secs(1)).await, Err(RuftError::InvalidState(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_hands_over_leadership() {
+        let cluster = Cluster::start("shutdown", 3, 14, SimConfig::default()).await;
+        let all = cluster.alive();
+        cluster.submit(&all, "before", TIMEOUT).await;
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let term = cluster.node(leader).current_term().await;
+
+        let node = cluster.node(leader);
+        cluster.shutdown(leader).await;
+        assert!(matches!(node.shutdown().await, Err(RuftError::InvalidState(_))));
+
+        // The follower it picked wins the very next term without waiting for an election timeout
+        let survivors = cluster.alive();
+        let new_leader = cluster.wait_for_leader(&survivors, Duration::from_millis(cluster.heartbeat_millis)).await;
+        assert_eq!(cluster.node(new_leader).current_term().await, term + 1);
+        let resp = cluster.submit(&survivors, "after", TIMEOUT).await;
+        assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+
+        // Restarting from the synced data dir, it catches up as a follower
+        cluster.restart(leader).await;
+        let index = cluster.node(new_leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        assert_eq!(cluster.applied(leader), vec!["before", "after"]);
+        cluster.check_logs(&all).await;
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_fails_waiting_proposals() {
+        let cluster = Cluster::start("shutdown_proposals", 3, 15, SimConfig::default()).await;
+        let all = cluster.alive();
+        cluster.submit(&all, "before", TIMEOUT).await;
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+
+        // Cut off from its followers, the leader can neither commit nor hand over leadership
+        let others: Vec<usize> = all.iter().copied().filter(|i| *i != leader).collect();
+        cluster.partition(&[&[leader], &others]);
+        let node = cluster.node(leader);
+        let proposal = tokio::spawn(async move {
+            let cmd = CmdReq {
+                id: "stuck".into(),
+                data: Bytes::from("stuck"),
+            };
+            node.submit(cmd).await
+        });
+        cluster.clock.sleep(Duration::from_millis(10)).await;
+        cluster.shutdown(leader).await;
+
+        match proposal.await.unwrap() {
+            CmdResp::Rejected { code, .. } => assert_eq!(code, ErrorCode::Internal),
+            resp => panic!("expected a rejection, got {:?}", resp),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crash_and_restart_keeps_data() {
+        let cluster = Cluster::start("crash_restart", 3, 4, SimConfig::default()).await;
+        let all = cluster.alive();
+
+        for i in 0..3 {
+            cluster.submit(&all, &format!("before{}", i), TIMEOUT).await;
+        }
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+
+        // Crash the leader, the survivors elect a new one and keep committing
+        let term = cluster.node(leader).current_term().await;
+        cluster.crash(leader).await;
+        let survivors = cluster.alive();
+        for i in 0..3 {
+            let resp = cluster.submit(&survivors, &format!("after{}", i), TIMEOUT).await;
+            assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+        }
+
+        // The restarted node recovers term and log from disk, then catches up
+        cluster.restart(leader).await;
+        assert!(cluster.node(leader).current_term().await >= term);
+        assert!(cluster.node(leader).last_log_index().await >= index);
+
+        let new_leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(new_leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        cluster.check_logs(&all).await;
+        assert_eq!(commands(&cluster.committed(new_leader).await).len(), 6);
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crashes_at_random_points_recover() {
+        let cluster = Cluster::start_with_snapshots("random_crashes", 3, 11, SimConfig::default(), Some(4)).await;
+        let all = cluster.alive();
+        let mut rng = StdRng::seed_from_u64(11);
+
+        for round in 0..8 {
+            // Kill a node while commands are in flight, anywhere between proposing and applying
+            let victim = rng.gen_range(0..all.len());
+            let delay = Duration::from_millis(rng.gen_range(0..3 * cluster.heartbeat_millis));
+            let submit = async {
+                for i in 0..3 {
+                    cluster.submit(&all, &format!("round{}-{}", round, i), TIMEOUT).await;
+                }
+            };
+            let crash = async {
+                cluster.clock.sleep(delay).await;
+                cluster.crash(victim).await;
+            };
+            tokio::join!(submit, crash);
+            cluster.restart(victim).await;
+        }
+
+        // Every node rebuilt the same state machine from its snapshot and log
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        cluster.submit(&all, "last", TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        cluster.check_logs(&all).await;
+        let applied = cluster.applied(leader);
+        assert_eq!(applied.last().map(String::as_str), Some("last"));
+        for i in all {
+            assert_eq!(cluster.applied(i), applied, "node {}", i);
+        }
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_removed_member_restarts_as_learner() {
+        let cluster = Cluster::start("learner_restart", 3, 12, SimConfig::default()).await;
+        let all = cluster.alive();
+        cluster.submit(&all, "before", TIMEOUT).await;
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+
+        // Its persisted membership no longer has it, so it comes back without a vote
+        let removed = (leader + 1) % all.len();
+        let members = cluster.members.iter().filter(|m| m.id() != cluster.id(removed)).cloned().collect();
+        cluster.node(removed).update_members(members).await.unwrap();
+        cluster.crash(removed).await;
+        cluster.restart(removed).await;
+        assert_eq!(cluster.node(removed).state().await, "Learner");
+
+        // It still receives the log from the leader
+        cluster.submit(&all, "after", TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&[removed], index, TIMEOUT).await;
+        assert_eq!(cluster.applied(removed), vec!["before", "after"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshots_compact_the_log() {
+        let cluster = Cluster::start_with_snapshots("snapshots", 3, 5, SimConfig::default(), Some(4)).await;
+        let all = cluster.alive();
+
+        let expected: Vec<String> = (0..10).map(|i| format!("cmd{}", i)).collect();
+        for (i, cmd) in expected.iter().enumerate() {
+            // The proposer gets the state machine's answer: the number of commands applied so far
+            let resp = cluster.submit(&all, cmd, TIMEOUT).await;
+            let CmdResp::Success { data } = resp else { panic!("{} failed: {:?}", cmd, resp) };
+            assert_eq!(data, Some(Bytes::from((i + 1).to_string())));
+        }
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        for &i in &all {
+            assert_eq!(cluster.applied(i), expected);
+            assert!(cluster.committed(i).await.len() < 4, "node {} kept {} committed entries", i, cluster.committed(i).await.len());
+            // Only the newest snapshots are retained
+            let snapshots = std::fs::read_dir(format!("{}/node{}/snapshots", cluster.data_dir, cluster.id(i))).unwrap().count();
+            assert_eq!(snapshots, 2);
+        }
+
+        // A restarted node restores the snapshot and replays the entries after it
+        cluster.crash(leader).await;
+        cluster.restart(leader).await;
+        assert_eq!(cluster.applied(leader), expected);
+        cluster.submit(&all, "after", TIMEOUT).await;
+        cluster.check_logs(&all).await;
+        cluster.check_trace();
+    }
+
+    /// A follower that was down while the others compacted their logs catches up from the leader's snapshot
+    async fn catch_up_from_snapshot(name: &str, seed: u64, sim: SimConfig) -> Cluster {
+        let cluster = Cluster::start_with_snapshots(name, 3, seed, sim, Some(4)).await;
+        let all = cluster.alive();
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let lagging = (leader + 1) % 3;
+        cluster.crash(lagging).await;
+
+        let survivors = cluster.alive();
+        for i in 0..12 {
+            cluster.submit(&survivors, &format!("cmd{}", i), TIMEOUT).await;
+        }
+        let leader = cluster.wait_for_leader(&survivors, TIMEOUT).await;
+        assert!(cluster.committed(leader).await.len() < 4);
+
+        cluster.restart(lagging).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        assert_eq!(cluster.applied(lagging), cluster.applied(leader));
+        assert!(cluster.applied(lagging).len() >= 12);
+
+        // Replication goes on with the log after the snapshot
+        let resp = cluster.submit(&all, "after", TIMEOUT).await;
+        assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        cluster.wait_for_commit(&all, index, TIMEOUT).await;
+        assert!(cluster.applied(lagging).contains(&"after".to_string()));
+        cluster.check_logs(&all).await;
+        cluster.check_trace();
+        cluster
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lagging_follower_installs_snapshot() {
+        catch_up_from_snapshot("install_snapshot", 7, SimConfig::default()).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_snapshot_transfer_resumes_on_lossy_network() {
+        let cluster = catch_up_from_snapshot("install_snapshot_lossy", 8, SimConfig::lossy()).await;
+        let lost = cluster.network.trace().iter().filter(|line| line.contains("InstallSnapshot") && line.ends_with("Drop")).count();
+        assert!(lost > 0, "no snapshot chunk was lost, the transfer never had to resume");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_group_commit_shares_fsyncs() {
+        let durability = Durability::GroupCommit {
+            window: Duration::from_millis(5),
+            max_bytes: 1 << 20,
+        };
+        let cluster = Cluster::start_with_durability("group_commit", 3, 9, SimConfig::default(), durability).await;
+        let all = cluster.alive();
+        cluster.submit(&all, "first", TIMEOUT).await;
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let before = cluster.node(leader).metrics().await.unwrap();
+        assert_eq!(before.durability, durability);
+
+        // Concurrent proposals land in the same window and share one fsync on the leader
+        let mut proposals = JoinSet::new();
+        for i in 0..10 {
+            let node = cluster.node(leader);
+            let cmd = CmdReq {
+                id: format!("cmd{}", i),
+                data: Bytes::from(format!("cmd{}", i)),
+            };
+            proposals.spawn(async move { node.submit(cmd).await });
+        }
+        while let Some(resp) = proposals.join_next().await {
+            let resp = resp.unwrap();
+            assert!(matches!(resp, CmdResp::Success { .. }), "{:?}", resp);
+        }
+        let after = cluster.node(leader).metrics().await.unwrap();
+        assert_eq!(after.synced_index, after.last_log_index);
+        assert!(after.log_syncs - before.log_syncs < 10, "{} fsyncs for 10 proposals", after.log_syncs - before.log_syncs);
+        cluster.check_logs(&all).await;
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_manual_snapshot() {
+        let cluster = Cluster::start("manual_snapshot", 3, 6, SimConfig::default()).await;
+        let all = cluster.alive();
+        for i in 0..3 {
+            cluster.submit(&all, &format!("cmd{}", i), TIMEOUT).await;
+        }
+        let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+        let index = cluster.node(leader).commit_index().await;
+        assert_eq!(cluster.node(leader).snapshot().await.unwrap(), index);
+        assert!(cluster.committed(leader).await.is_empty());
+
+        cluster.crash(leader).await;
+        cluster.restart(leader).await;
+        assert_eq!(cluster.applied(leader), vec!["cmd0", "cmd1", "cmd2"]);
+        cluster.check_trace();
+    }
+
+    fn replay(seed: u64) -> (Vec<String>, Vec<LogEntry>) {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+        runtime.block_on(async {
+            let cluster = Cluster::start("replay", 3, seed, SimConfig::lossy()).await;
+            let all = cluster.alive();
+            for i in 0..5 {
+                cluster.submit(&all, &format!("cmd{}", i), TIMEOUT).await;
+            }
+            let leader = cluster.wait_for_leader(&all, TIMEOUT).await;
+            (cluster.network.trace(), cluster.committed(leader).await)
+        })
+    }
+
+    #[test]
+    fn test_same_seed_replays_exactly() {
+        let first = replay(7);
+        assert!(!first.0.is_empty());
+        assert_eq!(first, replay(7));
+        assert_ne!(first.0, replay(8).0);
+    }
+}
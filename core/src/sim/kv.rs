@@ -0,0 +1,261 @@
+use crate::rpc::command::{CmdReq, CmdResp};
+use crate::rpc::{EntryType, LogEntry};
+use crate::sim::cluster::Cluster;
+use crate::sim::linearizability::{History, Model, Operation};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Operation on the key-value store replicated by the test clients
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KvInput {
+    Put { key: String, value: String },
+    Get { key: String },
+}
+
+impl KvInput {
+    fn key(&self) -> &str {
+        match self {
+            KvInput::Put { key, .. } | KvInput::Get { key } => key,
+        }
+    }
+}
+
+/// Answer of the store, `None` for a put and the current value for a get
+pub type KvOutput = Option<String>;
+
+pub type KvHistory = History<KvInput, KvOutput>;
+
+/// Sequential key-value store, checked key by key
+pub struct Kv;
+
+impl Model for Kv {
+    type State = BTreeMap<String, String>;
+    type Input = KvInput;
+    type Output = KvOutput;
+
+    fn init(&self) -> Self::State {
+        BTreeMap::new()
+    }
+
+    fn step(&self, state: &Self::State, input: &KvInput, output: Option<&KvOutput>) -> Option<Self::State> {
+        match input {
+            KvInput::Put { key, value } => {
+                let mut next = state.clone();
+                next.insert(key.clone(), value.clone());
+                Some(next)
+            }
+            KvInput::Get { key } => match output {
+                Some(read) if read.as_ref() != state.get(key) => None,
+                _ => Some(state.clone()),
+            },
+        }
+    }
+
+    fn partition(&self, ops: Vec<Operation<KvInput, KvOutput>>) -> Vec<Vec<Operation<KvInput, KvOutput>>> {
+        let mut by_key: BTreeMap<String, Vec<_>> = BTreeMap::new();
+        for op in ops {
+            by_key.entry(op.input.key().to_string()).or_default().push(op);
+        }
+        by_key.into_values().collect()
+    }
+}
+
+/// Log encoding of a command: `put <key> <value>` or `get <key> <tag>`
+///
+/// Reads go through the log too, the tag makes every read a distinct entry so
+/// its position in the committed log, and therefore its answer, can be found.
+fn encode(input: &KvInput, tag: &str) -> String {
+    match input {
+        KvInput::Put { key, value } => format!("put {} {}", key, value),
+        KvInput::Get { key } => format!("get {} {}", key, tag),
+    }
+}
+
+/// Replay the committed log up to the entry `command` and answer it
+fn answer(entries: &[LogEntry], command: &str) -> Option<KvOutput> {
+    let mut store = BTreeMap::new();
+    for entry in entries.iter().filter(|e| e.entry_type == EntryType::Command as i32) {
+        let text = String::from_utf8_lossy(&entry.command);
+        let mut parts = text.splitn(3, ' ');
+        let (op, key, arg) = (parts.next()?, parts.next()?, parts.next()?);
+        if text == command {
+            return Some(if op == "get" { store.get(key).cloned() } else { None });
+        }
+        if op == "put" {
+            store.insert(key.to_string(), arg.to_string());
+        }
+    }
+    None
+}
+
+/// Attempts per operation while no node accepts it
+const ATTEMPTS: usize = 20;
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// A simulated client: submits one command at a time and records it in the history
+///
+/// An operation is only retried after a definite rejection. Once its outcome
+/// is ambiguous it stays ambiguous in the history instead of being submitted
+/// again and possibly applied twice.
+pub struct KvClient<'a> {
+    id: usize,
+    cluster: &'a Cluster,
+    history: &'a KvHistory,
+    sent: usize,
+}
+
+impl<'a> KvClient<'a> {
+    pub fn new(id: usize, cluster: &'a Cluster, history: &'a KvHistory) -> Self {
+        KvClient { id, cluster, history, sent: 0 }
+    }
+
+    pub async fn put(&mut self, key: &str, value: &str) {
+        let input = KvInput::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        self.run(input).await;
+    }
+
+    pub async fn get(&mut self, key: &str) {
+        self.run(KvInput::Get { key: key.to_string() }).await;
+    }
+
+    async fn run(&mut self, input: KvInput) {
+        let op = self.history.invoke(self.id, input.clone());
+        for _ in 0..ATTEMPTS {
+            self.sent += 1;
+            let command = encode(&input, &format!("c{}-{}", self.id, self.sent));
+
+            // Ask whoever currently claims to lead, any live node otherwise
+            let alive = self.cluster.alive();
+            let target = match self.cluster.leaders(&alive).await.first() {
+                Some((leader, _)) => *leader,
+                None => alive[self.sent % alive.len()],
+            };
+            let Some(node) = self.cluster.try_node(target) else { continue };
+
+            let cmd = CmdReq {
+                id: command.clone(),
+                data: Bytes::from(command.clone()),
+            };
+            match node.submit(cmd).await {
+                CmdResp::Success { .. } => match input {
+                    KvInput::Put { .. } => return self.history.ok(op, None),
+                    KvInput::Get { .. } => match answer(&node.node().committed_entries().await, &command) {
+                        Some(value) => return self.history.ok(op, value),
+                        // The node crashed right after answering, a lost read changes nothing
+                        None => return self.history.fail(op),
+                    },
+                },
+                // Rejected before proposing, or the entry was overwritten by another leader: safe to try again
+                CmdResp::NotLeader { .. } => tokio::time::sleep(RETRY_DELAY).await,
+                // Timed out or shut down while replicating: the command may still commit, leave it open
+                CmdResp::Rejected { .. } | CmdResp::Pending { .. } => return,
+            }
+        }
+        self.history.fail(op);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimConfig;
+    use crate::sim::linearizability::check;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const KEYS: [&str; 2] = ["x", "y"];
+
+    /// A client issuing `ops` random puts and gets with short random pauses
+    async fn workload(cluster: &Cluster, history: &KvHistory, id: usize, seed: u64, ops: usize) {
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(id as u64));
+        let mut client = KvClient::new(id, cluster, history);
+        for n in 0..ops {
+            let key = KEYS[rng.gen_range(0..KEYS.len())];
+            if rng.gen_bool(0.5) {
+                client.put(key, &format!("{}-{}", id, n)).await;
+            } else {
+                client.get(key).await;
+            }
+            tokio::time::sleep(Duration::from_millis(rng.gen_range(0..30))).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_kv_is_linearizable_under_partitions() {
+        let seed = 11;
+        let cluster = Cluster::start("kv_partitions", 5, seed, SimConfig::lossy()).await;
+        let history = KvHistory::default();
+        let all = cluster.alive();
+
+        let nemesis = async {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                // Isolate the current leader together with one follower
+                if let Some((leader, _)) = cluster.leaders(&all).await.first().copied() {
+                    let minority = [leader, (leader + 1) % 5];
+                    let majority: Vec<usize> = all.iter().copied().filter(|i| !minority.contains(i)).collect();
+                    cluster.partition(&[&minority, &majority]);
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                cluster.heal();
+            }
+        };
+        tokio::join!(
+            nemesis,
+            workload(&cluster, &history, 0, seed, 25),
+            workload(&cluster, &history, 1, seed, 25),
+            workload(&cluster, &history, 2, seed, 25),
+            workload(&cluster, &history, 3, seed, 25),
+        );
+
+        let ops = history.operations();
+        assert!(ops.iter().any(|op| op.output.is_some()), "no operation succeeded");
+        check(&Kv, ops).unwrap();
+        cluster.check_trace();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_kv_is_linearizable_across_crashes() {
+        let seed = 12;
+        let cluster = Cluster::start("kv_crashes", 3, seed, SimConfig::default()).await;
+        let history = KvHistory::default();
+        let all = cluster.alive();
+
+        let nemesis = async {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                if let Some((leader, _)) = cluster.leaders(&all).await.first().copied() {
+                    cluster.crash(leader).await;
+                    tokio::time::sleep(Duration::from_millis(400)).await;
+                    cluster.restart(leader).await;
+                }
+            }
+        };
+        tokio::join!(
+            nemesis,
+            workload(&cluster, &history, 0, seed, 25),
+            workload(&cluster, &history, 1, seed, 25),
+            workload(&cluster, &history, 2, seed, 25),
+        );
+
+        let ops = history.operations();
+        assert!(ops.iter().any(|op| op.output.is_some()), "no operation succeeded");
+        check(&Kv, ops).unwrap();
+        cluster.check_trace();
+    }
+
+    #[test]
+    fn test_lost_write_is_detected() {
+        // A put acknowledged before a read started must be visible to it
+        let history = KvHistory::default();
+        let put = history.invoke(0, KvInput::Put { key: "x".into(), value: "1".into() });
+        history.ok(put, None);
+        let get = history.invoke(1, KvInput::Get { key: "x".into() });
+        history.ok(get, None);
+        assert!(check(&Kv, history.operations()).is_err());
+    }
+}
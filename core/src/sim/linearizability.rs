@@ -0,0 +1,233 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// Sequential specification of the object a history is checked against
+pub trait Model {
+    type State: Clone + Eq + Hash + Debug;
+    type Input: Clone + Debug;
+    type Output: Clone + Debug;
+
+    fn init(&self) -> Self::State;
+
+    /// Apply `input` to `state`, `None` if the object could not have answered `output`.
+    /// An unknown output (the client never heard back) matches anything.
+    fn step(&self, state: &Self::State, input: &Self::Input, output: Option<&Self::Output>) -> Option<Self::State>;
+
+    /// Split a history into independent sub-histories, e.g. one per key, each checked on its own
+    fn partition(&self, ops: Vec<Operation<Self::Input, Self::Output>>) -> Vec<Vec<Operation<Self::Input, Self::Output>>> {
+        vec![ops]
+    }
+}
+
+/// One client operation of a history
+///
+/// Times are logical: every invocation and completion takes the next tick of
+/// the [`History`], so `a.ret < b.call` means `a` finished before `b` started.
+#[derive(Clone, Debug)]
+pub struct Operation<I, O> {
+    pub client: usize,
+    pub input: I,
+    pub call: u64,
+    /// `None` if the outcome is unknown: the operation may take effect at any point after `call`, or never
+    pub ret: Option<u64>,
+    pub output: Option<O>,
+}
+
+struct Recorder<I, O> {
+    clock: u64,
+    ops: Vec<Option<Operation<I, O>>>,
+}
+
+/// Concurrent history of client operations, recorded as they are invoked and complete
+pub struct History<I, O> {
+    inner: Mutex<Recorder<I, O>>,
+}
+
+impl<I: Clone, O: Clone> Default for History<I, O> {
+    fn default() -> Self {
+        History {
+            inner: Mutex::new(Recorder { clock: 0, ops: Vec::new() }),
+        }
+    }
+}
+
+impl<I: Clone, O: Clone> History<I, O> {
+    /// Record the invocation of an operation, returning its id for the completion
+    pub fn invoke(&self, client: usize, input: I) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let call = inner.clock;
+        inner.ops.push(Some(Operation {
+            client,
+            input,
+            call,
+            ret: None,
+            output: None,
+        }));
+        inner.ops.len() - 1
+    }
+
+    /// The operation took effect and answered `output`
+    pub fn ok(&self, id: usize, output: O) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let ret = inner.clock;
+        if let Some(op) = inner.ops[id].as_mut() {
+            op.ret = Some(ret);
+            op.output = Some(output);
+        }
+    }
+
+    /// The operation certainly did not take effect, it is left out of the history
+    pub fn fail(&self, id: usize) {
+        self.inner.lock().unwrap().ops[id] = None;
+    }
+
+    /// Operations recorded so far; those never completed keep an unknown outcome
+    pub fn operations(&self) -> Vec<Operation<I, O>> {
+        self.inner.lock().unwrap().ops.iter().flatten().cloned().collect()
+    }
+}
+
+/// Check that a history is linearizable with respect to `model`
+///
+/// A depth-first search in the style of Wing & Gong with Lowe's memoization,
+/// as used by Porcupine: repeatedly pick an operation that may take effect
+/// first (no pending operation returned before it was called), apply it to
+/// the model and backtrack on mismatch. Returns the offending sub-history on
+/// failure.
+pub fn check<M: Model>(model: &M, ops: Vec<Operation<M::Input, M::Output>>) -> Result<(), String> {
+    for part in model.partition(ops) {
+        let mut search = Search {
+            model,
+            ops: &part,
+            done: vec![false; part.len()],
+            seen: HashSet::new(),
+        };
+        let completed = part.iter().filter(|op| op.ret.is_some()).count();
+        if !search.run(model.init(), completed) {
+            let mut part = part;
+            part.sort_by_key(|op| op.call);
+            let lines: Vec<String> = part
+                .iter()
+                .map(|op| format!("client {} {:?} [{}, {:?}] -> {:?}", op.client, op.input, op.call, op.ret, op.output))
+                .collect();
+            return Err(format!("history is not linearizable:\n{}", lines.join("\n")));
+        }
+    }
+    Ok(())
+}
+
+struct Search<'a, M: Model> {
+    model: &'a M,
+    ops: &'a [Operation<M::Input, M::Output>],
+    done: Vec<bool>,
+    /// (linearized set, state) pairs already explored
+    seen: HashSet<(Vec<bool>, M::State)>,
+}
+
+impl<M: Model> Search<'_, M> {
+    /// `completed` counts the completed operations not linearized yet. Once it
+    /// is zero the rest have unknown outcomes and can be assumed to never happen.
+    fn run(&mut self, state: M::State, completed: usize) -> bool {
+        if completed == 0 {
+            return true;
+        }
+
+        let horizon = (0..self.ops.len()).filter(|i| !self.done[*i]).filter_map(|i| self.ops[i].ret).min().unwrap_or(u64::MAX);
+        for i in 0..self.ops.len() {
+            let op = &self.ops[i];
+            if self.done[i] || op.call > horizon {
+                continue;
+            }
+            let Some(next) = self.model.step(&state, &op.input, op.output.as_ref()) else {
+                continue;
+            };
+
+            self.done[i] = true;
+            let remaining = completed - op.ret.is_some() as usize;
+            if self.seen.insert((self.done.clone(), next.clone())) && self.run(next, remaining) {
+                return true;
+            }
+            self.done[i] = false;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single register, the input is `Some(value)` for a write and `None` for a read
+    struct Register;
+
+    impl Model for Register {
+        type State = u32;
+        type Input = Option<u32>;
+        type Output = u32;
+
+        fn init(&self) -> u32 {
+            0
+        }
+
+        fn step(&self, state: &u32, input: &Option<u32>, output: Option<&u32>) -> Option<u32> {
+            match (input, output) {
+                (Some(value), _) => Some(*value),
+                (None, Some(read)) if read != state => None,
+                (None, _) => Some(*state),
+            }
+        }
+    }
+
+    fn op(input: Option<u32>, call: u64, ret: Option<u64>, output: Option<u32>) -> Operation<Option<u32>, u32> {
+        Operation { client: 0, input, call, ret, output }
+    }
+
+    #[test]
+    fn test_concurrent_operations_may_take_effect_in_any_order() {
+        // The read overlaps the write, so it may see either value
+        let ops = vec![op(Some(1), 1, Some(4), Some(0)), op(None, 2, Some(3), Some(0))];
+        assert!(check(&Register, ops).is_ok());
+        let ops = vec![op(Some(1), 1, Some(4), Some(0)), op(None, 2, Some(3), Some(1))];
+        assert!(check(&Register, ops).is_ok());
+    }
+
+    #[test]
+    fn test_stale_read_is_detected() {
+        // Write 1, then write 2, then a read that still sees 1
+        let ops = vec![op(Some(1), 1, Some(2), Some(0)), op(Some(2), 3, Some(4), Some(0)), op(None, 5, Some(6), Some(1))];
+        assert!(check(&Register, ops).is_err());
+    }
+
+    #[test]
+    fn test_unknown_outcome_may_or_may_not_happen() {
+        // A write that timed out may show up in a later read, or never
+        let ops = vec![op(Some(1), 1, None, None), op(None, 2, Some(3), Some(1)), op(None, 4, Some(5), Some(1))];
+        assert!(check(&Register, ops).is_ok());
+        let ops = vec![op(Some(1), 1, None, None), op(None, 2, Some(3), Some(0))];
+        assert!(check(&Register, ops).is_ok());
+        // But once observed it can't be undone
+        let ops = vec![op(Some(1), 1, None, None), op(None, 2, Some(3), Some(1)), op(None, 4, Some(5), Some(0))];
+        assert!(check(&Register, ops).is_err());
+    }
+
+    #[test]
+    fn test_history_records_logical_times() {
+        let history: History<Option<u32>, u32> = History::default();
+        let write = history.invoke(0, Some(1));
+        let read = history.invoke(1, None);
+        let lost = history.invoke(2, Some(2));
+        history.ok(read, 0);
+        history.ok(write, 0);
+        history.fail(lost);
+
+        let ops = history.operations();
+        assert_eq!(ops.len(), 2);
+        assert_eq!((ops[0].call, ops[0].ret), (1, Some(5)));
+        assert_eq!((ops[1].call, ops[1].ret), (2, Some(4)));
+        assert!(check(&Register, ops).is_ok());
+    }
+}
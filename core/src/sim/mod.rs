@@ -0,0 +1,8 @@
+//! Deterministic network simulation for multi-node tests
+
+mod cluster;
+mod kv;
+mod linearizability;
+mod network;
+
+pub use crate::sim::network::SimConfig;
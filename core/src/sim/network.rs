@@ -0,0 +1,299 @@
+use crate::clock::Clock;
+use crate::rpc::client::HealthTracker;
+use crate::rpc::local::{LocalMessage, dispatch_until, send_chunks};
+use crate::rpc::{
+    AppendEntriesRequest, AppendEntriesResponse, Endpoint, InstallSnapshotChunk, InstallSnapshotResponse, NodeId, PeerHealth, PreVoteRequest, PreVoteResponse, RaftRpcClient, RaftRpcHandler,
+    RequestVoteRequest, RequestVoteResponse, Serving, Shutdown, TimeoutNowRequest, TimeoutNowResponse, Transport,
+};
+use crate::{Result, RuftError};
+use dashmap::DashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Behaviour of every simulated link, probabilities are in `[0, 1]`
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+        }
+    }
+}
+
+impl SimConfig {
+    /// A network that delays, drops, duplicates and therefore reorders messages
+    pub fn lossy() -> Self {
+        SimConfig {
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+        }
+    }
+}
+
+/// What happens to a single message
+#[derive(Debug)]
+enum Fate {
+    Drop,
+    Deliver { delay: Duration, duplicate: Option<Duration> },
+}
+
+struct SimState {
+    rng: StdRng,
+    config: SimConfig,
+    /// One-way links that are cut, as (from, to)
+    blocked: HashSet<(NodeId, NodeId)>,
+    crashed: HashSet<NodeId>,
+    /// Every decision taken, in order, stamped with the time since the network was created
+    trace: Vec<String>,
+    started: Instant,
+}
+
+impl SimState {
+    fn delay(&mut self) -> Duration {
+        let (min, max) = (self.config.min_delay, self.config.max_delay);
+        if max <= min { min } else { self.rng.gen_range(min..=max) }
+    }
+
+    fn link_up(&self, from: NodeId, to: NodeId) -> bool {
+        !self.blocked.contains(&(from, to)) && !self.crashed.contains(&from) && !self.crashed.contains(&to)
+    }
+}
+
+/// Simulated network between in-process nodes
+///
+/// All decisions (delay, loss, duplication) come from one seeded RNG.
+/// Reordering falls out of the random delays: a later message may overtake
+/// an earlier one. On a single-threaded runtime with paused time the same
+/// seed produces the same [`SimNetwork::trace`]. Delays are waited out on
+/// `clock`, the same clock the attached nodes run their timers on.
+pub struct SimNetwork {
+    nodes: DashMap<NodeId, mpsc::UnboundedSender<LocalMessage>>,
+    state: Mutex<SimState>,
+    clock: Arc<dyn Clock>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, config: SimConfig, clock: Arc<dyn Clock>) -> Arc<Self> {
+        Arc::new(SimNetwork {
+            nodes: DashMap::new(),
+            state: Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                config,
+                blocked: HashSet::new(),
+                crashed: HashSet::new(),
+                trace: Vec::new(),
+                started: clock.now(),
+            }),
+            clock,
+        })
+    }
+
+    /// Cut the link from `from` to `to`, the opposite direction keeps working
+    pub fn block(&self, from: NodeId, to: NodeId) {
+        self.state.lock().unwrap().blocked.insert((from, to));
+    }
+
+    /// Split the nodes into groups that can only talk among themselves
+    pub fn partition(&self, groups: &[Vec<NodeId>]) {
+        let mut state = self.state.lock().unwrap();
+        for (i, group) in groups.iter().enumerate() {
+            for other in groups.iter().skip(i + 1) {
+                for &a in group {
+                    for &b in other {
+                        state.blocked.insert((a, b));
+                        state.blocked.insert((b, a));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Restore every cut link
+    pub fn heal(&self) {
+        self.state.lock().unwrap().blocked.clear();
+    }
+
+    /// Messages from and to a crashed node are lost
+    pub fn crash(&self, id: NodeId) {
+        self.state.lock().unwrap().crashed.insert(id);
+    }
+
+    pub fn restart(&self, id: NodeId) {
+        self.state.lock().unwrap().crashed.remove(&id);
+    }
+
+    /// Messages decided so far, for comparing two runs of the same seed
+    pub fn trace(&self) -> Vec<String> {
+        self.state.lock().unwrap().trace.clone()
+    }
+
+    fn fate(&self, kind: &str, from: NodeId, to: NodeId, allow_duplicate: bool) -> Fate {
+        let mut state = self.state.lock().unwrap();
+        let (drop_rate, duplicate_rate) = (state.config.drop_rate, state.config.duplicate_rate);
+        let fate = if !state.link_up(from, to) || state.rng.gen_bool(drop_rate) {
+            Fate::Drop
+        } else {
+            let delay = state.delay();
+            let duplicate = (allow_duplicate && state.rng.gen_bool(duplicate_rate)).then(|| state.delay());
+            Fate::Deliver { delay, duplicate }
+        };
+
+        let elapsed = self.clock.now() - state.started;
+        state.trace.push(format!("{:?} {} {}->{} {:?}", elapsed, kind, from, to, fate));
+        fate
+    }
+
+    fn link_up(&self, from: NodeId, to: NodeId) -> bool {
+        self.state.lock().unwrap().link_up(from, to)
+    }
+
+    fn deliver(&self, to: NodeId, msg: LocalMessage) {
+        if let Some(sender) = self.nodes.get(&to) {
+            let _ = sender.send(msg);
+        }
+    }
+}
+
+/// [`Transport`] of one node attached to a [`SimNetwork`]
+pub struct SimTransport {
+    network: Arc<SimNetwork>,
+    local: Endpoint,
+}
+
+impl SimTransport {
+    pub fn new(network: Arc<SimNetwork>, local: Endpoint) -> Self {
+        SimTransport { network, local }
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for SimTransport {
+    async fn connect(&self, endpoint: &Endpoint) -> Result<Arc<dyn RaftRpcClient>> {
+        Ok(Arc::new(SimClient {
+            network: self.network.clone(),
+            from: self.local.id(),
+            to: endpoint.id(),
+            health: HealthTracker::default(),
+        }))
+    }
+
+    async fn serve(&self, endpoint: Endpoint, _listen_addr: Option<SocketAddr>, handler: Arc<dyn RaftRpcHandler>, shutdown: Shutdown) -> Result<Serving> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        // A restarted node replaces the sender of its previous incarnation
+        let ours = tx.downgrade();
+        self.network.nodes.insert(endpoint.id(), tx);
+        let network = self.network.clone();
+        let run = async move {
+            dispatch_until(rx, handler, shutdown).await;
+            network.nodes.remove_if(&endpoint.id(), |_, current| ours.upgrade().is_some_and(|tx| current.same_channel(&tx)));
+            Ok(())
+        };
+        Ok(Serving { local_addr: None, run: Box::pin(run) })
+    }
+}
+
+struct SimClient {
+    network: Arc<SimNetwork>,
+    from: NodeId,
+    to: NodeId,
+    health: HealthTracker,
+}
+
+impl SimClient {
+    async fn call<Req, Resp>(&self, kind: &str, req: Req, wrap: fn(Req, oneshot::Sender<Result<Resp>>) -> LocalMessage, streamed: bool) -> Result<Resp>
+    where
+        Req: Clone + Send + 'static,
+        Resp: Send + 'static,
+    {
+        let result = self.exchange(kind, req, wrap, streamed).await;
+        self.health.record(&result);
+        result
+    }
+
+    /// Ship a request through the simulated link; a lost request or response never completes,
+    /// exactly like a real network, so callers rely on their own timeouts. Messages of a stream
+    /// are never duplicated, the stream's connection would discard them.
+    async fn exchange<Req, Resp>(&self, kind: &str, req: Req, wrap: fn(Req, oneshot::Sender<Result<Resp>>) -> LocalMessage, streamed: bool) -> Result<Resp>
+    where
+        Req: Clone + Send + 'static,
+        Resp: Send + 'static,
+    {
+        let Fate::Deliver { delay, duplicate } = self.network.fate(kind, self.from, self.to, !streamed) else {
+            return std::future::pending().await;
+        };
+
+        if let Some(duplicate_delay) = duplicate {
+            let network = self.network.clone();
+            let (to, req) = (self.to, req.clone());
+            tokio::spawn(async move {
+                network.clock.sleep(duplicate_delay).await;
+                let (reply, _) = oneshot::channel();
+                network.deliver(to, wrap(req, reply));
+            });
+        }
+
+        self.network.clock.sleep(delay).await;
+        // The target may have crashed while the message was in flight
+        if !self.network.link_up(self.from, self.to) {
+            return std::future::pending().await;
+        }
+        let (reply, rx) = oneshot::channel();
+        self.network.deliver(self.to, wrap(req, reply));
+        let resp = rx.await.map_err(|_| RuftError::Rpc(tonic::Status::unavailable(format!("Node {} dropped the request", self.to))))?;
+
+        let Fate::Deliver { delay, .. } = self.network.fate(kind, self.to, self.from, false) else {
+            return std::future::pending().await;
+        };
+        self.network.clock.sleep(delay).await;
+        resp
+    }
+}
+
+#[tonic::async_trait]
+impl RaftRpcClient for SimClient {
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn pre_vote(&self, req: PreVoteRequest) -> Result<PreVoteResponse> {
+        self.call("PreVote", req, LocalMessage::PreVote, false).await
+    }
+
+    async fn request_vote(&self, req: RequestVoteRequest) -> Result<RequestVoteResponse> {
+        self.call("RequestVote", req, LocalMessage::RequestVote, false).await
+    }
+
+    async fn append_entries(&self, req: AppendEntriesRequest) -> Result<AppendEntriesResponse> {
+        self.call("AppendEntries", req, LocalMessage::AppendEntries, false).await
+    }
+
+    async fn install_snapshot(&self, chunks: mpsc::Receiver<InstallSnapshotChunk>) -> Result<InstallSnapshotResponse> {
+        send_chunks(chunks, |chunk| self.call("InstallSnapshot", chunk, LocalMessage::InstallSnapshot, true)).await
+    }
+
+    async fn timeout_now(&self, req: TimeoutNowRequest) -> Result<TimeoutNowResponse> {
+        self.call("TimeoutNow", req, LocalMessage::TimeoutNow, false).await
+    }
+
+    fn health(&self) -> PeerHealth {
+        self.health.get()
+    }
+}
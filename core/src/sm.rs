@@ -1,3 +1,50 @@
-pub trait Sm {
-    fn consume();
+use bytes::Bytes;
+use std::io::{self, Read, Write};
+
+/// Replicated state machine fed with committed commands
+///
+/// Commands are applied in log order. After a restart the node restores the
+/// latest snapshot and applies the committed entries that follow it again,
+/// so the state machine itself does not need to be durable.
+pub trait Sm: Send + 'static {
+    /// Apply a committed command, the result is handed to the proposer of the command
+    fn apply(&mut self, index: u64, command: &[u8]) -> Option<Bytes>;
+
+    /// Freeze the current state for a snapshot
+    ///
+    /// Called between two applies. The view is written out on a blocking task
+    /// while the node goes on applying commands, so it must not see them.
+    fn view(&self) -> Box<dyn SmView>;
+
+    /// Replace the whole state with a snapshot read from `r`
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()>;
+}
+
+/// State of a [`Sm`] at one point in time, see [`Sm::view`]
+pub trait SmView: Send + 'static {
+    /// Serialize the whole state into `w`
+    fn snapshot(&self, w: &mut dyn Write) -> io::Result<()>;
+}
+
+/// State machine of a node that only orders commands
+pub(crate) struct NoopSm;
+
+impl Sm for NoopSm {
+    fn apply(&mut self, _index: u64, _command: &[u8]) -> Option<Bytes> {
+        None
+    }
+
+    fn view(&self) -> Box<dyn SmView> {
+        Box::new(NoopSm)
+    }
+
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SmView for NoopSm {
+    fn snapshot(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,92 @@
+use std::io;
+use tracing::info;
+
+/// Magic (4 bytes), version (u16 LE) and two reserved bytes
+pub const HEADER_SIZE: usize = 8;
+
+/// Upgrades the body of a file by one format version
+pub type Migration = fn(Vec<u8>) -> io::Result<Vec<u8>>;
+
+/// On-disk format of one kind of file
+///
+/// Every file starts with a header naming its kind and format version. Files
+/// written before headers existed are version 0. Older versions are upgraded
+/// one step at a time with `migrations[v]` turning version `v` into `v + 1`,
+/// files from a newer version are refused so a downgrade never misreads them.
+pub struct Format {
+    pub name: &'static str,
+    pub magic: [u8; 4],
+    pub migrations: &'static [Migration],
+}
+
+impl Format {
+    /// The version written by this build
+    pub fn version(&self) -> u16 {
+        self.migrations.len() as u16
+    }
+
+    pub fn header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&self.magic);
+        header[4..6].copy_from_slice(&self.version().to_le_bytes());
+        header
+    }
+
+    /// Header followed by `body`
+    pub fn encode(&self, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(&self.header());
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// Version and body of a file, a file without the magic is version 0 and all body
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] for a version newer than this build.
+    pub fn read<'a>(&self, bytes: &'a [u8]) -> io::Result<(u16, &'a [u8])> {
+        if bytes.len() < HEADER_SIZE || bytes[..4] != self.magic {
+            return Ok((0, bytes));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version > self.version() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} format version {} is newer than the supported version {}", self.name, version, self.version()),
+            ));
+        }
+        Ok((version, &bytes[HEADER_SIZE..]))
+    }
+
+    /// Bring a body of `version` up to the current version
+    pub fn upgrade(&self, version: u16, mut body: Vec<u8>) -> io::Result<Vec<u8>> {
+        for (from, migration) in self.migrations.iter().enumerate().skip(version as usize) {
+            info!("Migrating {} from format version {} to {}", self.name, from, from + 1);
+            body = migration(body)?;
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST: Format = Format {
+        name: "test",
+        magic: *b"TEST",
+        migrations: &[|body| Ok([b"v1:".as_slice(), &body].concat()), |body| Ok([b"v2:".as_slice(), &body].concat())],
+    };
+
+    #[test]
+    fn test_read_and_upgrade() {
+        let bytes = TEST.encode(b"body");
+        assert_eq!(TEST.read(&bytes).unwrap(), (2, b"body".as_slice()));
+        assert_eq!(TEST.read(b"legacy").unwrap(), (0, b"legacy".as_slice()));
+        assert_eq!(TEST.upgrade(0, b"legacy".to_vec()).unwrap(), b"v2:v1:legacy");
+        assert_eq!(TEST.upgrade(1, b"old".to_vec()).unwrap(), b"v2:old");
+
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        assert_eq!(TEST.read(&newer).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
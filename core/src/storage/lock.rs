@@ -0,0 +1,96 @@
+use crate::{Result, RuftError};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// Exclusive hold on a data directory
+///
+/// An advisory lock on `LOCK` in the directory, held until dropped. The file
+/// names the holder by process id and host so a refused opener can tell who
+/// has the directory. The operating system drops the lock with the process,
+/// so a file left behind by a dead process is stale and simply taken over.
+pub struct DirLock {
+    file: File,
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+impl DirLock {
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path: PathBuf = dir.join(LOCK_FILE);
+        let storage_error = |e: std::io::Error| RuftError::Storage(format!("Failed to lock data directory {}: {}", dir.display(), e));
+        std::fs::create_dir_all(dir).map_err(storage_error)?;
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(storage_error)?;
+
+        let mut holder = String::new();
+        file.read_to_string(&mut holder).map_err(storage_error)?;
+        let holder = holder.trim();
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(RuftError::InvalidState(format!("Data directory {} is in use by {}", dir.display(), holder)));
+            }
+            Err(TryLockError::Error(e)) => return Err(storage_error(e)),
+        }
+        if !holder.is_empty() {
+            info!("Taking over stale lock of data directory {} left by {}", dir.display(), holder);
+        }
+
+        let owner = format!("pid {} on {}\n", std::process::id(), hostname());
+        file.set_len(0).map_err(storage_error)?;
+        file.rewind().map_err(storage_error)?;
+        file.write_all(owner.as_bytes()).map_err(storage_error)?;
+        file.sync_data().map_err(storage_error)?;
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // Empty the file so the next opener does not report a stale holder, the lock itself goes with the file handle
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_opener_is_refused() {
+        let dir = PathBuf::from("/tmp/raft/lock_test/exclusive");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let lock = DirLock::acquire(&dir).unwrap();
+        let holder = std::fs::read_to_string(dir.join(LOCK_FILE)).unwrap();
+        assert!(holder.starts_with(&format!("pid {} on ", std::process::id())));
+        match DirLock::acquire(&dir) {
+            Err(RuftError::InvalidState(msg)) => assert!(msg.contains(holder.trim())),
+            other => panic!("expected InvalidState, got {:?}", other.map(|_| ())),
+        }
+
+        drop(lock);
+        DirLock::acquire(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_lock_is_taken_over() {
+        let dir = PathBuf::from("/tmp/raft/lock_test/stale");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // Left by a process that died without unlocking
+        std::fs::write(dir.join(LOCK_FILE), "pid 999999 on elsewhere\n").unwrap();
+
+        let _lock = DirLock::acquire(&dir).unwrap();
+        let holder = std::fs::read_to_string(dir.join(LOCK_FILE)).unwrap();
+        assert_eq!(holder, format!("pid {} on {}\n", std::process::id(), hostname()));
+    }
+}
@@ -0,0 +1,512 @@
+use crate::rpc::LogEntry;
+use crate::storage::{Format, HEADER_SIZE, crc32c, write_atomic};
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Size of the length prefix in front of every record
+const LEN_SIZE: usize = 4;
+/// Length and CRC32C of the payload in front of every record
+const RECORD_HEADER_SIZE: usize = LEN_SIZE + 4;
+/// The durable prefix length stored after the format header, records follow it
+const SYNCED_LEN_OFFSET: u64 = HEADER_SIZE as u64;
+const RECORDS_START: usize = HEADER_SIZE + 8;
+
+/// Version 1 put a header in front of the records, version 2 added record checksums
+pub(crate) const LOG_FORMAT: Format = Format {
+    name: "log",
+    magic: *b"RLOG",
+    migrations: &[Ok, add_checksums],
+};
+
+/// Append-only Raft log stored in a single file
+///
+/// The file starts with a [`LOG_FORMAT`] header and the length of a prefix known
+/// to be on disk. Every record then is a little-endian u32 length and the CRC32C
+/// of a prost encoded [`LogEntry`], followed by the entry. All entries are also
+/// kept in memory, the file is only read when the log is opened. Log indexes
+/// start at 1, index 0 is the empty log.
+///
+/// An invalid record past the durable prefix is a write torn by a crash and
+/// is cut off together with everything after it. An invalid record inside the
+/// prefix is corruption and fails opening the log.
+///
+/// Entries up to the snapshot index have been compacted away, the log then
+/// starts right after the snapshot.
+///
+/// Appends are only written, [`RaftLog::sync`] makes them durable so that
+/// several appends can share one fsync.
+pub struct RaftLog {
+    path: PathBuf,
+    file: File,
+    entries: Vec<LogEntry>,
+    /// File offset of each record, parallel to `entries`
+    offsets: Vec<u64>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// Last index that was fsynced, and the bytes written after it
+    synced_index: u64,
+    pending_bytes: u64,
+    /// File length after the last fsync
+    synced_len: u64,
+}
+
+impl RaftLog {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        if buf.is_empty() {
+            buf = empty_file();
+            file.write_all(&buf)?;
+            file.sync_all()?;
+        }
+        let (version, body) = LOG_FORMAT.read(&buf)?;
+        if version < LOG_FORMAT.version() {
+            buf = LOG_FORMAT.encode(&LOG_FORMAT.upgrade(version, body.to_vec())?);
+            file = write_atomic(&path, &buf)?;
+        }
+        let durable = match buf.get(RECORDS_START - 8..RECORDS_START) {
+            Some(len) => u64::from_le_bytes(len.try_into().unwrap()) as usize,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("log {} is shorter than its header", path.display()))),
+        };
+
+        let (entries, offsets, pos) = decode_records(&buf);
+        if pos < buf.len() {
+            if pos < durable {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("log {} is corrupt at offset {}, inside the {} bytes that were synced", path.display(), pos, durable),
+                ));
+            }
+            // Drop a torn tail so new records start on a record boundary
+            warn!("Truncating torn tail of log {} at offset {}, {} bytes dropped", path.display(), pos, buf.len() - pos);
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        // The term of the entry before a compacted log is only known from the snapshot, see `compact`
+        let snapshot_index = entries.first().map(|e| e.index - 1).unwrap_or(0);
+        let synced_index = snapshot_index + entries.len() as u64;
+        Ok(RaftLog {
+            path,
+            file,
+            entries,
+            offsets,
+            snapshot_index,
+            snapshot_term: 0,
+            synced_index,
+            pending_bytes: 0,
+            synced_len: pos as u64,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Index of the last entry covered by the snapshot, 0 if nothing was compacted
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn snapshot_term(&self) -> u64 {
+        self.snapshot_term
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map(|e| e.term).unwrap_or(self.snapshot_term)
+    }
+
+    /// Last index that is on disk, entries after it were written but not fsynced yet
+    pub fn synced_index(&self) -> u64 {
+        self.synced_index.min(self.last_index())
+    }
+
+    /// Bytes appended since the last fsync
+    pub fn pending_bytes(&self) -> u64 {
+        self.pending_bytes
+    }
+
+    /// Fsync the appended entries, returns false if there was nothing to sync
+    pub fn sync(&mut self) -> io::Result<bool> {
+        if self.synced_index() == self.last_index() && self.pending_bytes == 0 {
+            return Ok(false);
+        }
+        // Records first, the prefix may only cover what is on disk already
+        self.file.sync_data()?;
+        self.synced_len = self.size();
+        self.file.write_all_at(&self.synced_len.to_le_bytes(), SYNCED_LEN_OFFSET)?;
+        self.file.sync_data()?;
+        self.synced_index = self.last_index();
+        self.pending_bytes = 0;
+        Ok(true)
+    }
+
+    /// Size of the log file in bytes
+    pub fn size(&self) -> u64 {
+        self.offsets
+            .last()
+            .zip(self.entries.last())
+            .map(|(offset, e)| offset + (RECORD_HEADER_SIZE + e.encoded_len()) as u64)
+            .unwrap_or(RECORDS_START as u64)
+    }
+
+    /// Term of the entry at `index`, the snapshot term at the snapshot index and `None` for
+    /// compacted indexes or past the end of the log
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index) as usize - 1)
+    }
+
+    /// Term of every entry after the snapshot, in index order
+    pub fn terms(&self) -> Vec<u64> {
+        self.entries.iter().map(|e| e.term).collect()
+    }
+
+    /// Up to `max` entries starting at `index`, compacted entries are skipped
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = index.saturating_sub(self.snapshot_index + 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// Append entries at the end of the log, their indexes must follow `last_index`
+    ///
+    /// The entries are not durable before the next [`RaftLog::sync`].
+    pub fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut offset = self.file.stream_position()?;
+        let mut buf = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.index != self.last_index() + 1 + i as u64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("log entry {} is not contiguous", entry.index)));
+            }
+            let start = buf.len();
+            encode_record(entry, &mut buf);
+            self.offsets.push(offset);
+            offset += (buf.len() - start) as u64;
+        }
+
+        self.file.write_all(&buf)?;
+        self.pending_bytes += buf.len() as u64;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// Remove the entry at `index` and everything after it
+    pub fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        if index > self.last_index() {
+            return Ok(());
+        }
+        if index <= self.snapshot_index {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("log entry {} is already compacted", index)));
+        }
+
+        let keep = (index - self.snapshot_index) as usize - 1;
+        let offset = self.offsets[keep];
+        // Records appended later must not land inside the durable prefix
+        self.synced_len = self.synced_len.min(offset);
+        self.file.write_all_at(&self.synced_len.to_le_bytes(), SYNCED_LEN_OFFSET)?;
+        self.file.set_len(offset)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.sync_data()?;
+        self.entries.truncate(keep);
+        self.offsets.truncate(keep);
+        self.synced_index = self.last_index();
+        self.pending_bytes = 0;
+        Ok(())
+    }
+
+    /// Drop every entry up to `index`, which a snapshot with `term` now covers
+    ///
+    /// The remaining entries are written to a new file that replaces the old
+    /// one atomically. A snapshot past the end of the log empties it.
+    pub fn compact(&mut self, index: u64, term: u64) -> io::Result<()> {
+        if index < self.snapshot_index {
+            return Ok(());
+        }
+        if let Some(first) = self.entries.first()
+            && first.index > index + 1
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("log starts at {} but the snapshot ends at {}", first.index, index)));
+        }
+
+        let keep: Vec<LogEntry> = self.entries.iter().filter(|e| e.index > index).cloned().collect();
+        if keep.len() != self.entries.len() {
+            let mut buf = empty_file();
+            for entry in &keep {
+                encode_record(entry, &mut buf);
+            }
+            let len = buf.len() as u64;
+            buf[RECORDS_START - 8..RECORDS_START].copy_from_slice(&len.to_le_bytes());
+            let mut file = write_atomic(&self.path, &buf)?;
+            file.seek(SeekFrom::End(0))?;
+
+            let (entries, offsets, _) = decode_records(&buf);
+            self.file = file;
+            self.entries = entries;
+            self.offsets = offsets;
+            self.pending_bytes = 0;
+            self.synced_len = len;
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        // What the snapshot covers is durable, and so is a rewritten file
+        self.synced_index = if self.pending_bytes == 0 { self.last_index() } else { self.synced_index.max(index) };
+        Ok(())
+    }
+
+    /// Drop the whole log, it now continues after a snapshot ending at `index` with `term`
+    pub fn reset(&mut self, index: u64, term: u64) -> io::Result<()> {
+        self.synced_len = RECORDS_START as u64;
+        self.file.write_all_at(&self.synced_len.to_le_bytes(), SYNCED_LEN_OFFSET)?;
+        self.file.set_len(self.synced_len)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.sync_all()?;
+        self.entries.clear();
+        self.offsets.clear();
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.synced_index = index;
+        self.pending_bytes = 0;
+        Ok(())
+    }
+}
+
+/// Header and durable prefix of a log without records
+fn empty_file() -> Vec<u8> {
+    LOG_FORMAT.encode(&(RECORDS_START as u64).to_le_bytes())
+}
+
+fn frame(payload: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32c(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+fn encode_record(entry: &LogEntry, buf: &mut Vec<u8>) {
+    frame(&entry.encode_to_vec(), buf);
+}
+
+/// Decode the records of a file up to the first one that is incomplete or fails its checksum
+///
+/// Returns the entries, their offsets and the end of the last valid record.
+fn decode_records(buf: &[u8]) -> (Vec<LogEntry>, Vec<u64>, usize) {
+    let mut entries = Vec::new();
+    let mut offsets = Vec::new();
+    let mut pos = RECORDS_START;
+    while pos + RECORD_HEADER_SIZE <= buf.len() {
+        let len = u32::from_le_bytes(buf[pos..pos + LEN_SIZE].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(buf[pos + LEN_SIZE..pos + RECORD_HEADER_SIZE].try_into().unwrap());
+        let Some(payload) = buf.get(pos + RECORD_HEADER_SIZE..pos + RECORD_HEADER_SIZE + len) else {
+            break;
+        };
+        if crc32c(payload) != checksum {
+            break;
+        }
+        let Ok(entry) = LogEntry::decode(payload) else {
+            break;
+        };
+        offsets.push(pos as u64);
+        entries.push(entry);
+        pos += RECORD_HEADER_SIZE + len;
+    }
+    (entries, offsets, pos)
+}
+
+/// Version 1 to 2: frame every record with a checksum and record the whole file as synced
+fn add_checksums(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos + LEN_SIZE <= body.len() {
+        let len = u32::from_le_bytes(body[pos..pos + LEN_SIZE].try_into().unwrap()) as usize;
+        let Some(payload) = body.get(pos + LEN_SIZE..pos + LEN_SIZE + len) else {
+            break;
+        };
+        frame(payload, &mut records);
+        pos += LEN_SIZE + len;
+    }
+    let mut migrated = ((RECORDS_START + records.len()) as u64).to_le_bytes().to_vec();
+    migrated.extend_from_slice(&records);
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(index: u64, term: u64) -> LogEntry {
+        LogEntry {
+            index,
+            term,
+            command: format!("cmd{}", index).into_bytes(),
+            entry_type: 0,
+        }
+    }
+
+    #[test]
+    fn test_append_truncate_reopen() {
+        let path = PathBuf::from("/tmp/raft/log_test.bin");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut log = RaftLog::open(path.clone()).unwrap();
+        log.append(&[entry(1, 1), entry(2, 1), entry(3, 2)]).unwrap();
+        assert_eq!(log.last_index(), 3);
+        assert_eq!(log.last_term(), 2);
+        assert_eq!(log.term_at(2), Some(1));
+        assert!(log.append(&[entry(5, 2)]).is_err());
+        assert_eq!((log.synced_index(), log.pending_bytes() > 0), (0, true));
+        assert!(log.sync().unwrap());
+        assert_eq!((log.synced_index(), log.pending_bytes()), (3, 0));
+        assert!(!log.sync().unwrap());
+
+        log.truncate_from(3).unwrap();
+        log.append(&[entry(3, 3)]).unwrap();
+
+        let log = RaftLog::open(path).unwrap();
+        assert_eq!(log.last_index(), 3);
+        assert_eq!(log.term_at(3), Some(3));
+        assert_eq!(log.entries_from(2, 10), vec![entry(2, 1), entry(3, 3)]);
+    }
+
+    #[test]
+    fn test_torn_tail_is_dropped() {
+        let path = PathBuf::from("/tmp/raft/log_torn_test.bin");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut log = RaftLog::open(path.clone()).unwrap();
+        log.append(&[entry(1, 1), entry(2, 1)]).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let mut log = RaftLog::open(path).unwrap();
+        assert_eq!(log.last_index(), 1);
+        log.append(&[entry(2, 2)]).unwrap();
+        assert_eq!(log.term_at(2), Some(2));
+    }
+
+    #[test]
+    fn test_corruption_inside_synced_prefix() {
+        let path = PathBuf::from("/tmp/raft/log_corrupt_test.bin");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut log = RaftLog::open(path.clone()).unwrap();
+        log.append(&[entry(1, 1), entry(2, 1)]).unwrap();
+        log.sync().unwrap();
+        log.append(&[entry(3, 1)]).unwrap();
+        log.sync().unwrap();
+        log.append(&[entry(4, 1)]).unwrap();
+        let third = log.offsets[2] as usize;
+        let fourth = log.offsets[3] as usize;
+        drop(log);
+        let flip = |offset: usize| {
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[offset + RECORD_HEADER_SIZE] ^= 0xff;
+            std::fs::write(&path, bytes).unwrap();
+        };
+
+        // The last record was never synced, it may be torn and is dropped
+        flip(fourth);
+        let log = RaftLog::open(path.clone()).unwrap();
+        assert_eq!(log.last_index(), 3);
+        drop(log);
+
+        // The last synced record is inside the synced prefix, a bad one is corruption, not a crash
+        flip(third);
+        let err = RaftLog::open(path.clone()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("{} is corrupt at offset {}", path.display(), third)), "{}", err);
+    }
+
+    #[test]
+    fn test_migrate_headerless_log() {
+        let path = PathBuf::from("/tmp/raft/log_migrate_test.bin");
+        // Records of the first releases: a length and the entry, without header or checksum
+        let mut legacy = Vec::new();
+        for e in [entry(1, 1), entry(2, 1)] {
+            legacy.extend_from_slice(&(e.encoded_len() as u32).to_le_bytes());
+            legacy.extend_from_slice(&e.encode_to_vec());
+        }
+        std::fs::write(&path, &legacy).unwrap();
+
+        let mut log = RaftLog::open(path.clone()).unwrap();
+        assert_eq!(log.entries_from(1, 10), vec![entry(1, 1), entry(2, 1)]);
+        log.append(&[entry(3, 2)]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(LOG_FORMAT.read(&bytes).unwrap().0, LOG_FORMAT.version());
+        assert_eq!(RaftLog::open(path.clone()).unwrap().last_index(), 3);
+
+        // A log written by a newer release is left alone
+        let mut newer = bytes;
+        newer[4] += 1;
+        std::fs::write(&path, &newer).unwrap();
+        assert_eq!(RaftLog::open(path).err().unwrap().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = PathBuf::from("/tmp/raft/log_compact_test.bin");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut log = RaftLog::open(path.clone()).unwrap();
+        log.append(&[entry(1, 1), entry(2, 1), entry(3, 2), entry(4, 2)]).unwrap();
+        let size = log.size();
+        log.compact(2, 1).unwrap();
+        assert!(log.size() < size);
+        assert_eq!((log.snapshot_index(), log.last_index()), (2, 4));
+        assert_eq!(log.term_at(2), Some(1));
+        assert_eq!(log.term_at(1), None);
+        assert_eq!(log.entries_from(1, 10), vec![entry(3, 2), entry(4, 2)]);
+        assert!(log.truncate_from(2).is_err());
+        log.append(&[entry(5, 3)]).unwrap();
+
+        // Reopened, the log starts after the snapshot; its term comes back from the snapshot
+        let mut log = RaftLog::open(path).unwrap();
+        assert_eq!((log.snapshot_index(), log.last_index()), (2, 5));
+        log.compact(2, 1).unwrap();
+        assert_eq!(log.last_term(), 3);
+        log.compact(1, 1).unwrap();
+        assert_eq!(log.snapshot_index(), 2);
+
+        // A snapshot beyond the log empties it
+        log.reset(7, 3).unwrap();
+        assert_eq!((log.snapshot_index(), log.last_index(), log.entries_from(1, 10)), (7, 7, vec![]));
+        log.compact(9, 4).unwrap();
+        assert_eq!((log.last_index(), log.last_term()), (9, 4));
+        log.append(&[entry(10, 4)]).unwrap();
+        assert_eq!(log.entries_from(10, 10), vec![entry(10, 4)]);
+    }
+}
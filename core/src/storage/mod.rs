@@ -1,9 +1,40 @@
+mod crc;
+mod format;
+mod lock;
+mod log;
+mod snapshot;
+
+pub(crate) use crate::storage::crc::{crc32c, crc32c_append};
+pub(crate) use crate::storage::format::{Format, HEADER_SIZE};
+pub(crate) use crate::storage::lock::DirLock;
+pub use crate::storage::log::RaftLog;
+pub use crate::storage::snapshot::{SnapshotStore, SnapshotWriter};
+
 use memmap2::MmapMut;
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
 use std::mem::align_of;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Make a rename in the directory of `path` durable
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Replace the file at `path` with `bytes` through a synced temporary file and a rename
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    sync_dir(path)?;
+    Ok(file)
+}
 
 /// Marker trait for types safe to use with direct memory mapping.
 ///
@@ -29,52 +60,110 @@ unsafe impl Pod for i64 {}
 unsafe impl Pod for f32 {}
 unsafe impl Pod for f64 {}
 
+/// A file mapped into memory
+///
+/// Writes past the end grow the file, at least doubling it, and remap it.
+/// Storage opened with [`MmapStorage::open_fixed`] keeps its size instead and
+/// fails such writes, which suits preallocated segment files.
 pub struct MmapStorage {
+    file: File,
     mmap: MmapMut,
+    growable: bool,
 }
 
 impl MmapStorage {
-    fn create(path: PathBuf, size: u64) -> std::io::Result<Self> {
-        let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
-        file.set_len(size)?;
+    fn create(path: PathBuf, size: u64, growable: bool) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        file.set_len(size.max(1))?;
         let mmap = unsafe { MmapMut::map_mut(&file)? };
-        Ok(Self { mmap })
+        Ok(Self { file, mmap, growable })
     }
 
-    fn open(path: &std::path::Path) -> std::io::Result<Self> {
+    fn open(path: &std::path::Path, growable: bool) -> std::io::Result<Self> {
         let file = OpenOptions::new().read(true).write(true).open(path)?;
         let mmap = unsafe { MmapMut::map_mut(&file)? };
-        Ok(Self { mmap })
+        Ok(Self { file, mmap, growable })
     }
 
+    /// Open the file at `path`, creating it with `size` bytes if it does not exist
     pub fn open_or_create(path: PathBuf, size: u64) -> std::io::Result<Self> {
-        if path.exists() {
-            Self::open(&path)
-        } else {
-            if let Some(parent) = path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            Self::create(path, size)
+        if path.exists() { Self::open(&path, true) } else { Self::create(path, size, true) }
+    }
+
+    /// Open or create a file that never grows beyond its `size`
+    #[allow(dead_code)]
+    pub fn open_fixed(path: PathBuf, size: u64) -> std::io::Result<Self> {
+        if path.exists() { Self::open(&path, false) } else { Self::create(path, size, false) }
+    }
+
+    /// Number of bytes mapped, the length of the file
+    pub fn capacity(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Grow or shrink the file to `len` bytes and remap it
+    ///
+    /// Shrinking drops the data past `len`.
+    pub fn resize(&mut self, len: usize) -> io::Result<()> {
+        if len == self.capacity() {
+            return Ok(());
+        }
+        self.mmap.flush()?;
+        self.file.set_len(len.max(1) as u64)?;
+        self.mmap = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
+
+    /// Make room for `len` bytes, doubling the capacity as often as needed
+    fn reserve(&mut self, len: usize) -> io::Result<()> {
+        if len <= self.capacity() {
+            return Ok(());
+        }
+        if !self.growable {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("{} bytes do not fit into fixed storage of {} bytes", len, self.capacity()),
+            ));
         }
+        let mut capacity = self.capacity();
+        while capacity < len {
+            capacity *= 2;
+        }
+        self.resize(capacity)
     }
 
     /// Write serializable data to storage
+    #[allow(dead_code)]
     pub fn write_serialized<T: Serialize>(&mut self, data: &T) -> io::Result<()> {
         let bytes = bincode::serialize(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-        if bytes.len() > self.mmap.len() {
-            return Err(io::Error::new(io::ErrorKind::WriteZero, "data too large for mmap"));
-        }
-
+        self.reserve(bytes.len())?;
         self.mmap[..bytes.len()].copy_from_slice(&bytes);
         self.mmap.flush()
     }
 
     /// Read serializable data from storage
+    #[allow(dead_code)]
     pub fn read_serialized<T: for<'de> Deserialize<'de>>(&self) -> io::Result<T> {
         bincode::deserialize(&self.mmap).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
+    /// Bytes `offset..offset + len` of the mapping
+    pub fn read_at(&self, offset: usize, len: usize) -> io::Result<&[u8]> {
+        self.mmap
+            .get(offset..offset + len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "read beyond the end of the mmap"))
+    }
+
+    /// Copy `bytes` to `offset` and flush only the touched range
+    pub fn write_at(&mut self, offset: usize, bytes: &[u8]) -> io::Result<()> {
+        self.reserve(offset + bytes.len())?;
+        self.mmap[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.mmap.flush_range(offset, bytes.len())
+    }
+
     /// Direct memory access for POD types
     ///
     /// # Safety
@@ -85,33 +174,43 @@ impl MmapStorage {
     /// - No pointers
     ///
     /// The compiler will reject non-Pod types at compile time!
+    #[allow(dead_code)]
     pub fn with_mut<T: Pod>(&mut self, f: impl FnOnce(&mut T)) -> io::Result<()> {
-        // Safety checks
-        if std::mem::size_of::<T>() > self.mmap.len() {
+        self.with_mut_at(0, f)
+    }
+
+    /// Direct memory access for POD types (read-only)
+    #[allow(dead_code)]
+    pub fn with_ref<T: Pod, R>(&self, f: impl FnOnce(&T) -> R) -> io::Result<R> {
+        self.with_ref_at(0, f)
+    }
+
+    /// Check that a `T` fits at `offset` and is aligned there
+    fn check_pod<T: Pod>(&self, offset: usize) -> io::Result<()> {
+        if offset + std::mem::size_of::<T>() > self.mmap.len() {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "type too large"));
         }
-        if (self.mmap.as_ptr() as usize) % align_of::<T>() != 0 {
+        if !(self.mmap.as_ptr() as usize + offset).is_multiple_of(align_of::<T>()) {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "misaligned pointer"));
         }
+        Ok(())
+    }
 
+    /// Direct memory access for a POD type at `offset`, flushing only its bytes
+    pub fn with_mut_at<T: Pod>(&mut self, offset: usize, f: impl FnOnce(&mut T)) -> io::Result<()> {
+        self.check_pod::<T>(offset)?;
         unsafe {
-            let ptr = self.mmap.as_mut_ptr() as *mut T;
+            let ptr = self.mmap.as_mut_ptr().add(offset) as *mut T;
             f(&mut *ptr);
         }
-        self.mmap.flush()
+        self.mmap.flush_range(offset, std::mem::size_of::<T>())
     }
 
-    /// Direct memory access for POD types (read-only)
-    pub fn with_ref<T: Pod, R>(&self, f: impl FnOnce(&T) -> R) -> io::Result<R> {
-        if std::mem::size_of::<T>() > self.mmap.len() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "type too large"));
-        }
-        if (self.mmap.as_ptr() as usize) % align_of::<T>() != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "misaligned pointer"));
-        }
-
+    /// Direct memory access for a POD type at `offset` (read-only)
+    pub fn with_ref_at<T: Pod, R>(&self, offset: usize, f: impl FnOnce(&T) -> R) -> io::Result<R> {
+        self.check_pod::<T>(offset)?;
         unsafe {
-            let ptr = self.mmap.as_ptr() as *const T;
+            let ptr = self.mmap.as_ptr().add(offset) as *const T;
             Ok(f(&*ptr))
         }
     }
@@ -168,6 +267,41 @@ mod tests {
         assert_eq!(loaded2, meta2);
     }
 
+    #[test]
+    fn test_grow_and_shrink() {
+        let path = PathBuf::from("/tmp/raft/mmap_grow_test.bin");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut store = MmapStorage::open_or_create(path.clone(), 64).unwrap();
+        let long = vec!["a very long host name".to_string(); 10];
+        store.write_serialized(&long).unwrap();
+        assert_eq!(store.capacity(), 512);
+        store.write_at(1000, b"tail").unwrap();
+        assert_eq!(store.capacity(), 1024);
+
+        let mut store = MmapStorage::open_or_create(path.clone(), 64).unwrap();
+        assert_eq!(store.read_serialized::<Vec<String>>().unwrap(), long);
+        assert_eq!(store.read_at(1000, 4).unwrap(), b"tail");
+        store.resize(100).unwrap();
+        assert_eq!((store.capacity(), std::fs::metadata(&path).unwrap().len()), (100, 100));
+        assert!(store.read_at(1000, 4).is_err());
+    }
+
+    #[test]
+    fn test_fixed_size_segment() {
+        let path = PathBuf::from("/tmp/raft/mmap_segment_test.bin");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let mut segment = MmapStorage::open_fixed(path, 128).unwrap();
+        segment.write_at(120, b"fits").unwrap();
+        assert_eq!(segment.write_at(126, b"full").unwrap_err().kind(), io::ErrorKind::WriteZero);
+        assert_eq!(segment.capacity(), 128);
+    }
+
     // Test that Pod trait prevents unsafe usage
     #[test]
     fn test_pod_safety() {
@@ -0,0 +1,422 @@
+use crate::rpc::{Endpoint, LegacyEndpoint};
+use crate::storage::{Format, crc32c_append, sync_dir, write_atomic};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Prefix of snapshot directories still being written, they never survive a restart
+const TMP_PREFIX: &str = "tmp-";
+const DATA_FILE: &str = "data";
+const MANIFEST_FILE: &str = "manifest";
+
+/// Version 1 put a header in front of the manifest, version 2 widened node ids to 64 bits.
+/// The data file is the state machine's own format.
+pub(crate) const MANIFEST_FORMAT: Format = Format {
+    name: "snapshot manifest",
+    magic: *b"RSNP",
+    migrations: &[Ok, widen_node_ids],
+};
+
+/// Description of a stored snapshot, kept next to its data
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    /// Last log index and term the snapshot covers
+    pub index: u64,
+    pub term: u64,
+    /// Cluster members when the snapshot was taken
+    pub members: Vec<Endpoint>,
+    /// Length and CRC32C of the state machine data
+    pub size: u64,
+    pub checksum: u32,
+}
+
+/// Manifest of version 1
+#[derive(Serialize, Deserialize)]
+struct ManifestV1 {
+    index: u64,
+    term: u64,
+    members: Vec<LegacyEndpoint>,
+    size: u64,
+    checksum: u32,
+}
+
+/// Version 1 to 2: widen the ids of the members from 8 to 64 bits
+fn widen_node_ids(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let v1: ManifestV1 = bincode::deserialize(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let manifest = SnapshotManifest {
+        index: v1.index,
+        term: v1.term,
+        members: v1.members.into_iter().map(Endpoint::from).collect(),
+        size: v1.size,
+        checksum: v1.checksum,
+    };
+    bincode::serialize(&manifest).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// State machine state up to and including `manifest.index`
+///
+/// The data was checked against the manifest and is read from the start.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub manifest: SnapshotManifest,
+    pub data: BufReader<File>,
+}
+
+/// Directory of snapshots, one sub-directory per snapshot
+///
+/// A snapshot is written to a `tmp-` directory, synced and renamed into place,
+/// so a finished directory is always complete. Directory names are the zero
+/// padded index and term, which sorts them from oldest to newest. Only the
+/// newest `retain` snapshots are kept.
+#[derive(Clone, Debug)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    retain: usize,
+}
+
+impl SnapshotStore {
+    /// Open the store, removing whatever a crash left half written
+    pub fn open(dir: PathBuf, retain: usize) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                warn!("Removing partially written snapshot {}", entry.path().display());
+                std::fs::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(SnapshotStore { dir, retain: retain.max(1) })
+    }
+
+    /// Finished snapshots as (index, term, directory), newest first
+    fn list(&self) -> io::Result<Vec<(u64, u64, PathBuf)>> {
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some((index, term)) = name.split_once('-')
+                && let (Ok(index), Ok(term)) = (index.parse(), term.parse())
+            {
+                snapshots.push((index, term, entry.path()));
+            }
+        }
+        snapshots.sort_by_key(|(index, term, _)| std::cmp::Reverse((*index, *term)));
+        Ok(snapshots)
+    }
+
+    fn name(index: u64, term: u64) -> String {
+        format!("{:020}-{:020}", index, term)
+    }
+
+    /// Store a snapshot taken locally, `write` streams the data into the store
+    pub fn save(&self, index: u64, term: u64, members: Vec<Endpoint>, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<SnapshotManifest> {
+        let mut writer = self.writer(index, term)?;
+        write(&mut writer)?;
+        self.finish(writer, members)
+    }
+
+    /// Start writing a snapshot piece by piece
+    pub fn writer(&self, index: u64, term: u64) -> io::Result<SnapshotWriter> {
+        let dir = self.dir.join(format!("{}{}", TMP_PREFIX, Self::name(index, term)));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new().write(true).create_new(true).open(dir.join(DATA_FILE))?;
+        Ok(SnapshotWriter {
+            dir,
+            file,
+            index,
+            term,
+            len: 0,
+            checksum: 0,
+        })
+    }
+
+    /// Write the manifest, move the snapshot into place and drop the snapshots beyond the retention
+    pub fn finish(&self, writer: SnapshotWriter, members: Vec<Endpoint>) -> io::Result<SnapshotManifest> {
+        writer.file.sync_all()?;
+        let manifest = SnapshotManifest {
+            index: writer.index,
+            term: writer.term,
+            members,
+            size: writer.len,
+            checksum: writer.checksum,
+        };
+        let bytes = bincode::serialize(&manifest).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(writer.dir.join(MANIFEST_FILE))?;
+        file.write_all(&MANIFEST_FORMAT.encode(&bytes))?;
+        file.sync_all()?;
+        File::open(&writer.dir)?.sync_all()?;
+
+        let target = self.dir.join(Self::name(manifest.index, manifest.term));
+        if target.exists() {
+            std::fs::remove_dir_all(&target)?;
+        }
+        std::fs::rename(&writer.dir, &target)?;
+        sync_dir(&target)?;
+        self.prune()?;
+        Ok(manifest)
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        for (_, _, dir) in self.list()?.into_iter().skip(self.retain) {
+            std::fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Read a manifest, upgrading it in place when it was written in an older format
+    fn read_manifest(dir: &Path) -> io::Result<SnapshotManifest> {
+        let path = dir.join(MANIFEST_FILE);
+        let bytes = std::fs::read(&path)?;
+        let (version, body) = MANIFEST_FORMAT.read(&bytes)?;
+        let body = MANIFEST_FORMAT.upgrade(version, body.to_vec())?;
+        let manifest = bincode::deserialize(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if version < MANIFEST_FORMAT.version() {
+            write_atomic(&path, &MANIFEST_FORMAT.encode(&body))?;
+        }
+        Ok(manifest)
+    }
+
+    /// Check the data against the manifest piece by piece, a snapshot may be far larger than memory
+    fn read_valid(dir: &Path) -> io::Result<Snapshot> {
+        let manifest = Self::read_manifest(dir)?;
+        let mut data = BufReader::new(File::open(dir.join(DATA_FILE))?);
+        let (mut len, mut checksum) = (0u64, 0u32);
+        loop {
+            let piece = data.fill_buf()?;
+            if piece.is_empty() {
+                break;
+            }
+            checksum = crc32c_append(checksum, piece);
+            len += piece.len() as u64;
+            let consumed = piece.len();
+            data.consume(consumed);
+        }
+        if len != manifest.size || checksum != manifest.checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "data does not match the manifest"));
+        }
+        data.rewind()?;
+        Ok(Snapshot { manifest, data })
+    }
+
+    /// The newest snapshot whose data matches its manifest, `None` if there is none
+    pub fn load(&self) -> io::Result<Option<Snapshot>> {
+        for (_, _, dir) in self.list()? {
+            match Self::read_valid(&dir) {
+                Ok(snapshot) => return Ok(Some(snapshot)),
+                // Written by a newer release, skipping it would silently roll the state back
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => return Err(e),
+                Err(e) => warn!("Skipping invalid snapshot {}: {}", dir.display(), e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Open the newest snapshot for shipping it to a follower
+    pub fn reader(&self) -> io::Result<SnapshotReader> {
+        let (_, _, dir) = self.list()?.into_iter().next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no snapshot stored"))?;
+        let manifest = Self::read_manifest(&dir)?;
+        let file = File::open(dir.join(DATA_FILE))?;
+        Ok(SnapshotReader { file, manifest })
+    }
+}
+
+/// Reads the data of a stored snapshot piece by piece
+///
+/// The file stays open, so pruning or a newer snapshot does not disturb a running transfer.
+pub struct SnapshotReader {
+    file: File,
+    manifest: SnapshotManifest,
+}
+
+impl SnapshotReader {
+    pub fn manifest(&self) -> &SnapshotManifest {
+        &self.manifest
+    }
+
+    /// Up to `max` bytes of data starting at `offset`
+    pub fn read_at(&mut self, offset: u64, max: usize) -> io::Result<Vec<u8>> {
+        let size = self.manifest.size.saturating_sub(offset).min(max as u64) as usize;
+        let mut buf = vec![0u8; size];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// A snapshot being written, see [`SnapshotStore::writer`]
+pub struct SnapshotWriter {
+    dir: PathBuf,
+    file: File,
+    index: u64,
+    term: u64,
+    len: u64,
+    checksum: u32,
+}
+
+impl SnapshotWriter {
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Bytes of data written so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// Appends the next piece of data
+impl Write for SnapshotWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(data)?;
+        self.len += written as u64;
+        self.checksum = crc32c_append(self.checksum, &data[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::NodeId;
+
+    fn store(name: &str, retain: usize) -> SnapshotStore {
+        let dir = PathBuf::from(format!("/tmp/raft/snapshot_test/{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        SnapshotStore::open(dir, retain).unwrap()
+    }
+
+    /// Index and data of the newest valid snapshot
+    fn load(store: &SnapshotStore) -> Option<(u64, Vec<u8>)> {
+        let mut snapshot = store.load().unwrap()?;
+        let mut data = Vec::new();
+        snapshot.data.read_to_end(&mut data).unwrap();
+        Some((snapshot.manifest.index, data))
+    }
+
+    #[test]
+    fn test_save_load() {
+        let store = store("save_load", 2);
+        assert!(store.load().unwrap().is_none());
+
+        let members = vec![Endpoint::new(NodeId::new(1), "localhost".into(), 5001)];
+        let manifest = store.save(7, 2, members.clone(), |w| w.write_all(b"state")).unwrap();
+        assert_eq!((manifest.index, manifest.term, manifest.size, &manifest.members), (7, 2, 5, &members));
+        assert_eq!(store.load().unwrap().unwrap().manifest, manifest);
+        assert_eq!(load(&store), Some((7, b"state".to_vec())));
+    }
+
+    #[test]
+    fn test_load_streams_large_data() {
+        let store = store("large", 2);
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        store.save(3, 1, vec![], |w| w.write_all(&data)).unwrap();
+        assert_eq!(load(&store), Some((3, data.clone())));
+
+        // A flipped byte far beyond the first read is still caught
+        let mut corrupt = data;
+        corrupt[70_000] ^= 1;
+        std::fs::write(store.dir.join(SnapshotStore::name(3, 1)).join(DATA_FILE), corrupt).unwrap();
+        assert_eq!(load(&store), None);
+    }
+
+    #[test]
+    fn test_retention_and_cleanup() {
+        let store = store("retention", 2);
+        for index in 1..=4 {
+            store.save(index, 1, vec![], |w| w.write_all(format!("state{}", index).as_bytes())).unwrap();
+        }
+        let kept: Vec<u64> = store.list().unwrap().iter().map(|(index, _, _)| *index).collect();
+        assert_eq!(kept, vec![4, 3]);
+
+        // A snapshot interrupted by a crash is gone after reopening
+        let mut writer = store.writer(5, 1).unwrap();
+        writer.write_all(b"half").unwrap();
+        let store = SnapshotStore::open(store.dir.clone(), 2).unwrap();
+        assert_eq!(std::fs::read_dir(&store.dir).unwrap().count(), 2);
+        assert_eq!(store.load().unwrap().unwrap().manifest.index, 4);
+    }
+
+    #[test]
+    fn test_corrupt_snapshot_falls_back() {
+        let store = store("corrupt", 3);
+        store.save(3, 1, vec![], |w| w.write_all(b"older")).unwrap();
+        store.save(5, 2, vec![], |w| w.write_all(b"newer")).unwrap();
+        std::fs::write(store.dir.join(SnapshotStore::name(5, 2)).join(DATA_FILE), b"newex").unwrap();
+
+        assert_eq!(load(&store), Some((3, b"older".to_vec())));
+    }
+
+    #[test]
+    fn test_migrate_headerless_manifest() {
+        let store = store("migrate", 2);
+        store.save(4, 1, vec![], |w| w.write_all(b"state")).unwrap();
+        let path = store.dir.join(SnapshotStore::name(4, 1)).join(MANIFEST_FILE);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, MANIFEST_FORMAT.read(&bytes).unwrap().1).unwrap();
+
+        assert_eq!(load(&store), Some((4, b"state".to_vec())));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        let mut newer = bytes;
+        newer[4] += 1;
+        std::fs::write(&path, &newer).unwrap();
+        assert_eq!(store.load().unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_migrate_manifest_with_narrow_node_ids() {
+        let store = store("migrate_ids", 2);
+        let members = vec![Endpoint::new(NodeId::new(200), "localhost".into(), 5001)];
+        store.save(4, 1, members.clone(), |w| w.write_all(b"state")).unwrap();
+        let path = store.dir.join(SnapshotStore::name(4, 1)).join(MANIFEST_FILE);
+        let bytes = std::fs::read(&path).unwrap();
+        let manifest: SnapshotManifest = bincode::deserialize(MANIFEST_FORMAT.read(&bytes).unwrap().1).unwrap();
+        let v1 = ManifestV1 {
+            index: manifest.index,
+            term: manifest.term,
+            members: vec![LegacyEndpoint {
+                id: 200,
+                host: "localhost".into(),
+                port: 5001,
+            }],
+            size: manifest.size,
+            checksum: manifest.checksum,
+        };
+        let mut old = MANIFEST_FORMAT.encode(&bincode::serialize(&v1).unwrap());
+        old[4..6].copy_from_slice(&1u16.to_le_bytes());
+        std::fs::write(&path, old).unwrap();
+
+        assert_eq!(store.load().unwrap().unwrap().manifest.members, members);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_stream() {
+        let source = store("stream_source", 2);
+        let target = store("stream_target", 2);
+        let data: Vec<u8> = (0..100u8).collect();
+        source.save(9, 3, vec![], |w| w.write_all(&data)).unwrap();
+
+        let mut reader = source.reader().unwrap();
+        let mut writer = target.writer(9, 3).unwrap();
+        while writer.len() < reader.manifest().size {
+            writer.write_all(&reader.read_at(writer.len(), 40).unwrap()).unwrap();
+        }
+        let manifest = target.finish(writer, vec![]).unwrap();
+        assert_eq!(&manifest, reader.manifest());
+        assert_eq!(load(&target), Some((9, data)));
+    }
+}
@@ -0,0 +1,192 @@
+//! NDJSON traces of Raft state transitions, validated against the Parallel-Raft TLA+ specification
+//!
+//! With [`crate::ConfigBuilder::trace_file`] set, a node appends one line per
+//! action of the spec it takes (`Timeout`, `BecomeLeader`,
+//! `HandleAppendEntriesRequest`, ...) with its state after the action. Nodes
+//! of one cluster may share a file, which gives a total order of their
+//! actions. [`check_file`] replays a trace through [`SpecChecker`].
+
+mod json;
+mod spec;
+
+pub use crate::trace::json::Json;
+pub use crate::trace::spec::SpecChecker;
+
+use crate::rpc::NodeId;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::warn;
+
+/// A message as seen by the node handling it
+///
+/// Field meanings follow the message type:
+/// - `RequestVoteRequest`: `index`/`log_term` are the candidate's last log index/term,
+///   `success` whether the vote was granted
+/// - `AppendEntriesRequest`: `index`/`log_term` are prevLogIndex/prevLogTerm, `entries`
+///   the terms of the shipped entries, `commit` the leader commit, `success` our answer
+/// - `AppendEntriesResponse`: `index` is the follower's last log index, `success` its answer
+/// - `InstallSnapshotRequest`: `index`/`log_term` are the last index/term the snapshot covers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceMessage {
+    pub kind: String,
+    pub from: NodeId,
+    pub term: u64,
+    pub index: u64,
+    pub log_term: u64,
+    pub entries: Vec<u64>,
+    pub commit: u64,
+    pub success: bool,
+}
+
+/// One action of a node and its state right after it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub node: NodeId,
+    pub action: String,
+    pub term: u64,
+    pub role: String,
+    pub voted_for: Option<NodeId>,
+    /// Number of entries compacted into a snapshot, they are not listed in `log`
+    pub log_start: u64,
+    /// Term of every retained log entry, the entry at index `i` is `log[i - log_start - 1]`
+    pub log: Vec<u64>,
+    pub commit: u64,
+    /// Nodes backing the action: voters for `BecomeLeader`, replicas for `AdvanceCommitIndex`
+    pub quorum: Vec<NodeId>,
+    pub msg: Option<TraceMessage>,
+}
+
+fn numbers(values: &[u64]) -> Json {
+    Json::Array(values.iter().map(|v| Json::Number(*v)).collect())
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("missing field '{}'", key))
+}
+
+fn u64_field(json: &Json, key: &str) -> Result<u64, String> {
+    field(json, key)?.as_u64().ok_or_else(|| format!("field '{}' is not a number", key))
+}
+
+fn str_field(json: &Json, key: &str) -> Result<String, String> {
+    Ok(field(json, key)?.as_str().ok_or_else(|| format!("field '{}' is not a string", key))?.to_string())
+}
+
+fn numbers_field(json: &Json, key: &str) -> Result<Vec<u64>, String> {
+    let items = field(json, key)?.as_array().ok_or_else(|| format!("field '{}' is not an array", key))?;
+    items.iter().map(|v| v.as_u64().ok_or_else(|| format!("field '{}' holds a non-number", key))).collect()
+}
+
+impl TraceMessage {
+    pub fn to_json(&self) -> Json {
+        Json::Object(vec![
+            ("type".into(), Json::String(self.kind.clone())),
+            ("from".into(), Json::Number(self.from.get())),
+            ("term".into(), Json::Number(self.term)),
+            ("index".into(), Json::Number(self.index)),
+            ("log_term".into(), Json::Number(self.log_term)),
+            ("entries".into(), numbers(&self.entries)),
+            ("commit".into(), Json::Number(self.commit)),
+            ("success".into(), Json::Bool(self.success)),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        Ok(TraceMessage {
+            kind: str_field(json, "type")?,
+            from: u64_field(json, "from")?.into(),
+            term: u64_field(json, "term")?,
+            index: u64_field(json, "index")?,
+            log_term: u64_field(json, "log_term")?,
+            entries: numbers_field(json, "entries")?,
+            commit: u64_field(json, "commit")?,
+            success: field(json, "success")?.as_bool().ok_or("field 'success' is not a bool")?,
+        })
+    }
+}
+
+impl TraceEvent {
+    pub fn to_json(&self) -> Json {
+        Json::Object(vec![
+            ("node".into(), Json::Number(self.node.get())),
+            ("action".into(), Json::String(self.action.clone())),
+            ("term".into(), Json::Number(self.term)),
+            ("role".into(), Json::String(self.role.clone())),
+            ("voted_for".into(), self.voted_for.map(|id| Json::Number(id.get())).unwrap_or(Json::Null)),
+            ("log_start".into(), Json::Number(self.log_start)),
+            ("log".into(), numbers(&self.log)),
+            ("commit".into(), Json::Number(self.commit)),
+            ("quorum".into(), Json::Array(self.quorum.iter().map(|id| Json::Number(id.get())).collect())),
+            ("msg".into(), self.msg.as_ref().map(|m| m.to_json()).unwrap_or(Json::Null)),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let voted_for = match field(json, "voted_for")? {
+            Json::Null => None,
+            value => Some(value.as_u64().ok_or("field 'voted_for' is not a number")?.into()),
+        };
+        let msg = match field(json, "msg")? {
+            Json::Null => None,
+            value => Some(TraceMessage::from_json(value)?),
+        };
+        Ok(TraceEvent {
+            node: u64_field(json, "node")?.into(),
+            action: str_field(json, "action")?,
+            term: u64_field(json, "term")?,
+            role: str_field(json, "role")?,
+            voted_for,
+            // Absent in traces of nodes without snapshots
+            log_start: if json.get("log_start").is_some() { u64_field(json, "log_start")? } else { 0 },
+            log: numbers_field(json, "log")?,
+            commit: u64_field(json, "commit")?,
+            quorum: numbers_field(json, "quorum")?.into_iter().map(NodeId::from).collect(),
+            msg,
+        })
+    }
+}
+
+/// Appends trace events to a file, one JSON object per line
+pub(crate) struct Tracer {
+    file: File,
+}
+
+impl Tracer {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Tracer { file })
+    }
+
+    /// Tracing is a debugging aid, a failed write is logged and otherwise ignored
+    pub fn record(&self, event: &TraceEvent) {
+        // One write per line, so nodes sharing the file never interleave within a line
+        let line = format!("{}\n", event.to_json());
+        if let Err(e) = (&self.file).write_all(line.as_bytes()) {
+            warn!("Failed to write trace event: {}", e);
+        }
+    }
+}
+
+/// Read a trace written by [`Tracer`]
+pub fn read_file(path: &Path) -> Result<Vec<TraceEvent>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read trace {}: {}", path.display(), e))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| Json::parse(line).and_then(|json| TraceEvent::from_json(&json)).map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect()
+}
+
+/// Replay a trace file against the spec for a cluster of `members` node ids, returning the number of events checked
+pub fn check_file(path: &Path, members: &[NodeId]) -> Result<usize, String> {
+    let events = read_file(path)?;
+    let mut checker = SpecChecker::new(members);
+    for event in &events {
+        checker.step(event)?;
+    }
+    Ok(events.len())
+}
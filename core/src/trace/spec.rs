@@ -0,0 +1,410 @@
+use crate::rpc::NodeId;
+use crate::trace::{TraceEvent, TraceMessage};
+use std::collections::HashMap;
+
+/// Per node variables of the spec: currentTerm, state, votedFor, log and commitIndex
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct NodeVars {
+    term: u64,
+    role: String,
+    voted_for: Option<NodeId>,
+    log: Vec<u64>,
+    commit: u64,
+}
+
+impl NodeVars {
+    /// The node's variables after `event`, entries compacted into its snapshot are taken from the committed log
+    fn of(event: &TraceEvent, committed: &[u64]) -> Result<Self, String> {
+        let start = event.log_start as usize;
+        if start > committed.len() {
+            return Err(format!("{} entries are compacted but only {} are known to be committed", start, committed.len()));
+        }
+        Ok(NodeVars {
+            term: event.term,
+            role: event.role.clone(),
+            voted_for: event.voted_for,
+            log: committed[..start].iter().chain(&event.log).copied().collect(),
+            commit: event.commit,
+        })
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().copied().unwrap_or(0)
+    }
+}
+
+/// Rust model of the spec's actions, replaying a trace one event at a time
+///
+/// Each event is checked against the enabling condition and effect of the
+/// action it names, given the node's previous state. Across nodes the checker
+/// keeps the votes cast and the leaders elected per term plus the committed
+/// log, and checks ElectionSafety, LeaderCompleteness and StateMachineSafety.
+///
+/// Two deliberate differences from the spec: `BecomeLeader` also appends the
+/// leader's no-op entry, and a `Restart` keeps the commit index because this
+/// implementation persists it. `InstallSnapshot` goes beyond the spec, which
+/// has no compaction.
+pub struct SpecChecker {
+    members: Vec<NodeId>,
+    nodes: HashMap<NodeId, NodeVars>,
+    /// votedFor history: (voter, term) -> candidate
+    votes: HashMap<(NodeId, u64), NodeId>,
+    leaders: HashMap<u64, NodeId>,
+    /// Terms of the entries known to be committed anywhere
+    committed: Vec<u64>,
+    steps: usize,
+}
+
+impl SpecChecker {
+    pub fn new(members: &[NodeId]) -> Self {
+        SpecChecker {
+            members: members.to_vec(),
+            nodes: HashMap::new(),
+            votes: HashMap::new(),
+            leaders: HashMap::new(),
+            committed: Vec::new(),
+            steps: 0,
+        }
+    }
+
+    fn is_quorum(&self, ids: &[NodeId]) -> bool {
+        let mut ids: Vec<NodeId> = ids.iter().copied().filter(|id| self.members.contains(id)).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.len() > self.members.len() / 2
+    }
+
+    /// Check the next event of the trace
+    pub fn step(&mut self, event: &TraceEvent) -> Result<(), String> {
+        self.steps += 1;
+        let next = self.check(event).map_err(|e| format!("event {} ({} on node {}): {}", self.steps, event.action, event.node, e))?;
+        self.nodes.insert(event.node, next);
+        Ok(())
+    }
+
+    fn check(&mut self, event: &TraceEvent) -> Result<NodeVars, String> {
+        let next = NodeVars::of(event, &self.committed)?;
+        if next.commit > next.log.len() as u64 {
+            return Err(format!("commit index {} is past the end of the log ({})", next.commit, next.log.len()));
+        }
+
+        let Some(prev) = self.nodes.get(&event.node).cloned() else {
+            return match event.action.as_str() {
+                "Restart" => self.restart(None, &next).map(|_| next),
+                _ => Err("first event of a node must be Restart".into()),
+            };
+        };
+        if next.term < prev.term {
+            return Err(format!("term went back from {} to {}", prev.term, next.term));
+        }
+        if next.commit < prev.commit {
+            return Err(format!("commit index went back from {} to {}", prev.commit, next.commit));
+        }
+
+        match event.action.as_str() {
+            "Restart" => self.restart(Some(&prev), &next)?,
+            "Timeout" => timeout(event.node, &prev, &next)?,
+            "UpdateTerm" => update_term(&prev, &next)?,
+            "HandleRequestVoteRequest" => self.request_vote(event.node, &prev, &next, message(event, "RequestVoteRequest")?)?,
+            "BecomeLeader" => self.become_leader(event, &prev, &next)?,
+            "ClientRequest" => client_request(&prev, &next)?,
+            "HandleAppendEntriesRequest" => append_entries(&prev, &next, message(event, "AppendEntriesRequest")?)?,
+            "HandleAppendEntriesResponse" => {
+                let msg = message(event, "AppendEntriesResponse")?;
+                expect(prev.role == "Leader", "only a leader handles AppendEntries responses")?;
+                expect(msg.term == prev.term, "response term differs from ours, UpdateTerm should have happened first")?;
+                expect(next == prev, "handling a response changes no spec variable")?;
+            }
+            "AdvanceCommitIndex" => self.advance_commit(event, &prev, &next)?,
+            "InstallSnapshot" => self.install_snapshot(&prev, &next, message(event, "InstallSnapshotRequest")?)?,
+            other => return Err(format!("unknown action '{}'", other)),
+        }
+        self.observe_commit(&next)?;
+        Ok(next)
+    }
+
+    fn restart(&mut self, prev: Option<&NodeVars>, next: &NodeVars) -> Result<(), String> {
+        expect(next.role == "Follower", "a node restarts as follower")?;
+        if let Some(prev) = prev {
+            let persisted = |v: &NodeVars| (v.term, v.voted_for, v.log.clone(), v.commit);
+            expect(persisted(prev) == persisted(next), "persistent state changed across a restart")?;
+        }
+        self.observe_commit(next)
+    }
+
+    fn request_vote(&mut self, node: NodeId, prev: &NodeVars, next: &NodeVars, msg: &TraceMessage) -> Result<(), String> {
+        expect(msg.term <= prev.term, "request term is newer than ours, UpdateTerm should have happened first")?;
+        let log_ok = (msg.log_term, msg.index) >= (prev.last_term(), prev.log.len() as u64);
+        let grant = msg.term == prev.term && log_ok && prev.voted_for.is_none_or(|v| v == msg.from);
+        if msg.success != grant {
+            return Err(format!("vote granted is {} but the spec says {}", msg.success, grant));
+        }
+
+        let expected_vote = if grant { Some(msg.from) } else { prev.voted_for };
+        expect(next.voted_for == expected_vote, "votedFor does not match the vote")?;
+        expect(
+            (next.term, &next.role, &next.log, next.commit) == (prev.term, &prev.role, &prev.log, prev.commit),
+            "a vote changes nothing but votedFor",
+        )?;
+        if grant {
+            let previous = *self.votes.entry((node, prev.term)).or_insert(msg.from);
+            expect(previous == msg.from, "voted twice in the same term")?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, event: &TraceEvent, prev: &NodeVars, next: &NodeVars) -> Result<(), String> {
+        expect(prev.role == "Candidate" && next.role == "Leader", "only a candidate becomes leader")?;
+        expect(next.term == prev.term, "becoming leader keeps the term")?;
+        expect(event.quorum.contains(&event.node), "a candidate votes for itself")?;
+        expect(self.is_quorum(&event.quorum), "votes are not a quorum")?;
+        for voter in event.quorum.iter().filter(|v| **v != event.node) {
+            if self.votes.get(&(*voter, prev.term)) != Some(&event.node) {
+                return Err(format!("node {} never voted for {} in term {}", voter, event.node, prev.term));
+            }
+        }
+        let mut log = prev.log.clone();
+        log.push(prev.term);
+        expect(next.log == log, "the new leader appends exactly its no-op entry")?;
+        expect(next.commit == prev.commit, "becoming leader commits nothing")?;
+
+        // ElectionSafety and LeaderCompleteness
+        let leader = *self.leaders.entry(next.term).or_insert(event.node);
+        if leader != event.node {
+            return Err(format!("nodes {} and {} are both leader in term {}", leader, event.node, next.term));
+        }
+        expect(next.log.starts_with(&self.committed), "the new leader misses committed entries")
+    }
+
+    fn advance_commit(&mut self, event: &TraceEvent, prev: &NodeVars, next: &NodeVars) -> Result<(), String> {
+        expect(prev.role == "Leader", "only a leader advances the commit index")?;
+        expect((next.term, &next.log) == (prev.term, &prev.log), "advancing the commit index changes nothing else")?;
+        expect(next.commit > prev.commit, "the commit index must grow")?;
+        expect(next.log[next.commit as usize - 1] == next.term, "a leader only commits entries of its own term by counting replicas")?;
+        expect(self.is_quorum(&event.quorum), "the entry is not on a quorum")
+    }
+
+    /// A follower takes the leader's snapshot: its log becomes the committed prefix the snapshot covers,
+    /// unless the log already holds the snapshot's last entry, and that prefix counts as committed
+    fn install_snapshot(&self, prev: &NodeVars, next: &NodeVars, msg: &TraceMessage) -> Result<(), String> {
+        expect(prev.role == "Follower" && msg.term == prev.term, "only a follower of the sender's term installs its snapshot")?;
+        expect(
+            (next.term, &next.role, next.voted_for) == (prev.term, &prev.role, prev.voted_for),
+            "installing a snapshot changes no election state",
+        )?;
+        let index = msg.index as usize;
+        expect(index > 0 && self.committed.get(index - 1) == Some(&msg.log_term), "the snapshot does not end in a committed entry")?;
+
+        let log = if prev.log.get(index - 1) == Some(&msg.log_term) {
+            prev.log.clone()
+        } else {
+            self.committed[..index].to_vec()
+        };
+        if next.log != log {
+            return Err(format!("log is {:?} but the spec says {:?}", next.log, log));
+        }
+        expect(next.commit == prev.commit.max(msg.index), "the commit index covers the snapshot")
+    }
+
+    /// StateMachineSafety: every committed prefix agrees with everything committed so far
+    fn observe_commit(&mut self, next: &NodeVars) -> Result<(), String> {
+        let prefix = &next.log[..next.commit as usize];
+        let common = prefix.len().min(self.committed.len());
+        if prefix[..common] != self.committed[..common] {
+            return Err(format!("committed log {:?} diverges from {:?}", prefix, self.committed));
+        }
+        if prefix.len() > self.committed.len() {
+            self.committed = prefix.to_vec();
+        }
+        Ok(())
+    }
+}
+
+fn expect(condition: bool, violation: &str) -> Result<(), String> {
+    if condition { Ok(()) } else { Err(violation.to_string()) }
+}
+
+fn message<'a>(event: &'a TraceEvent, kind: &str) -> Result<&'a TraceMessage, String> {
+    match &event.msg {
+        Some(msg) if msg.kind == kind => Ok(msg),
+        _ => Err(format!("expected a {} message", kind)),
+    }
+}
+
+fn timeout(node: NodeId, prev: &NodeVars, next: &NodeVars) -> Result<(), String> {
+    expect(prev.role == "Follower" || prev.role == "Candidate", "only followers and candidates time out")?;
+    expect(next.role == "Candidate", "a timeout starts a candidacy")?;
+    expect(next.term == prev.term + 1, "a timeout increments the term")?;
+    expect(next.voted_for == Some(node), "a candidate votes for itself")?;
+    expect((&next.log, next.commit) == (&prev.log, prev.commit), "a timeout leaves the log alone")
+}
+
+fn update_term(prev: &NodeVars, next: &NodeVars) -> Result<(), String> {
+    expect(next.term > prev.term, "UpdateTerm needs a newer term")?;
+    expect(
+        next.role == "Follower" || (prev.role == "Learner" && next.role == "Learner"),
+        "UpdateTerm turns the node into a follower",
+    )?;
+    expect(next.voted_for.is_none(), "UpdateTerm clears votedFor")?;
+    expect((&next.log, next.commit) == (&prev.log, prev.commit), "UpdateTerm leaves the log alone")
+}
+
+fn client_request(prev: &NodeVars, next: &NodeVars) -> Result<(), String> {
+    expect(prev.role == "Leader" && next.role == "Leader", "only a leader accepts client requests")?;
+    let mut log = prev.log.clone();
+    log.push(prev.term);
+    expect(next.log == log, "a client request appends one entry of the current term")?;
+    expect((next.term, next.commit) == (prev.term, prev.commit), "a client request changes nothing else")
+}
+
+fn append_entries(prev: &NodeVars, next: &NodeVars, msg: &TraceMessage) -> Result<(), String> {
+    if msg.term < prev.term {
+        expect(!msg.success, "a stale request is rejected")?;
+        return expect(next == prev, "a stale request changes nothing");
+    }
+    expect(msg.term == prev.term, "request term is newer than ours, UpdateTerm should have happened first")?;
+    expect(prev.role != "Leader", "a leader received AppendEntries of its own term")?;
+    expect(next.role == "Follower" || next.role == "Learner", "AppendEntries of the current term makes a candidate follow")?;
+    expect(next.voted_for == prev.voted_for, "AppendEntries leaves votedFor alone")?;
+
+    let prev_index = msg.index as usize;
+    let log_ok = prev_index == 0 || prev.log.get(prev_index - 1) == Some(&msg.log_term);
+    if msg.success != log_ok {
+        return Err(format!("success is {} but the log check says {}", msg.success, log_ok));
+    }
+    if !log_ok {
+        return expect((&next.log, next.commit) == (&prev.log, prev.commit), "a rejected request leaves the log alone");
+    }
+
+    // Keep entries that match, truncate at the first conflict, append what is missing
+    let mut log = prev.log.clone();
+    for (offset, term) in msg.entries.iter().enumerate() {
+        let index = prev_index + offset;
+        match log.get(index) {
+            Some(existing) if existing == term => continue,
+            Some(_) => {
+                log.truncate(index);
+                log.push(*term);
+            }
+            None => log.push(*term),
+        }
+    }
+    if next.log != log {
+        return Err(format!("log is {:?} but the spec says {:?}", next.log, log));
+    }
+    let commit = prev.commit.max(msg.commit.min((prev_index + msg.entries.len()) as u64));
+    if next.commit != commit {
+        return Err(format!("commit index is {} but the spec says {}", next.commit, commit));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(node: u64, action: &str, term: u64, role: &str, voted_for: Option<u64>, log: &[u64], commit: u64) -> TraceEvent {
+        TraceEvent {
+            node: NodeId::new(node),
+            action: action.into(),
+            term,
+            role: role.into(),
+            voted_for: voted_for.map(NodeId::new),
+            log_start: 0,
+            log: log.to_vec(),
+            commit,
+            quorum: vec![],
+            msg: None,
+        }
+    }
+
+    fn vote(from: u64, term: u64, granted: bool) -> Option<TraceMessage> {
+        Some(TraceMessage {
+            kind: "RequestVoteRequest".into(),
+            from: NodeId::new(from),
+            term,
+            index: 0,
+            log_term: 0,
+            entries: vec![],
+            commit: 0,
+            success: granted,
+        })
+    }
+
+    /// Three fresh nodes, node 1 times out and gets node 2's vote
+    fn election() -> Vec<TraceEvent> {
+        let mut events: Vec<TraceEvent> = (1..=3).map(|n| event(n, "Restart", 0, "Follower", None, &[], 0)).collect();
+        events.push(event(1, "Timeout", 1, "Candidate", Some(1), &[], 0));
+        events.push(event(2, "UpdateTerm", 1, "Follower", None, &[], 0));
+        events.push(TraceEvent {
+            msg: vote(1, 1, true),
+            ..event(2, "HandleRequestVoteRequest", 1, "Follower", Some(1), &[], 0)
+        });
+        events
+    }
+
+    fn run(events: &[TraceEvent]) -> Result<(), String> {
+        let mut checker = SpecChecker::new(&[1, 2, 3].map(NodeId::new));
+        events.iter().try_for_each(|e| checker.step(e))
+    }
+
+    #[test]
+    fn test_valid_election() {
+        let mut events = election();
+        events.push(TraceEvent {
+            quorum: vec![NodeId::new(1), NodeId::new(2)],
+            ..event(1, "BecomeLeader", 1, "Leader", Some(1), &[1], 0)
+        });
+        assert_eq!(run(&events), Ok(()));
+    }
+
+    #[test]
+    fn test_leader_without_votes_is_rejected() {
+        let mut events = election();
+        events.push(TraceEvent {
+            quorum: vec![NodeId::new(1), NodeId::new(3)],
+            ..event(1, "BecomeLeader", 1, "Leader", Some(1), &[1], 0)
+        });
+        let err = run(&events).unwrap_err();
+        assert!(err.contains("never voted"), "{}", err);
+    }
+
+    #[test]
+    fn test_double_vote_is_rejected() {
+        let mut events = election();
+        events.push(event(3, "Timeout", 1, "Candidate", Some(3), &[], 0));
+        // Node 2 already voted for 1 in term 1
+        events.push(TraceEvent {
+            msg: vote(3, 1, true),
+            ..event(2, "HandleRequestVoteRequest", 1, "Follower", Some(3), &[], 0)
+        });
+        assert!(run(&events).is_err());
+    }
+
+    #[test]
+    fn test_append_entries_follows_the_spec() {
+        let mut events = election();
+        let request = |entries: Vec<u64>, commit: u64, success: bool| {
+            Some(TraceMessage {
+                kind: "AppendEntriesRequest".into(),
+                from: NodeId::new(1),
+                term: 1,
+                index: 0,
+                log_term: 0,
+                entries,
+                commit,
+                success,
+            })
+        };
+        events.push(TraceEvent {
+            msg: request(vec![1, 1], 1, true),
+            ..event(2, "HandleAppendEntriesRequest", 1, "Follower", Some(1), &[1, 1], 1)
+        });
+        assert_eq!(run(&events), Ok(()));
+
+        // Committing past what the leader said is a divergence
+        events.last_mut().unwrap().commit = 2;
+        let err = run(&events).unwrap_err();
+        assert!(err.contains("commit index is 2"), "{}", err);
+    }
+}
@@ -1,6 +1,6 @@
 use bytes::Bytes;
-use core::rpc::command::{CmdReq, CmdResp};
-use core::rpc::Endpoint;
+use core::rpc::command::CmdReq;
+use core::rpc::{Endpoint, NodeId};
 use core::{Config, Ruft};
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
@@ -10,7 +10,7 @@ async fn main() {
     init_tracing();
     info!("Starting Raft node example");
 
-    let endpoint = Endpoint::new(0, "127.0.0.1".to_string(), 5000);
+    let endpoint = Endpoint::new(NodeId::new(0), "127.0.0.1".to_string(), 5000);
     // Create config using the new builder pattern
     let config = Config::builder().data_dir("/tmp/ruft/node0").members(vec![endpoint.clone()]).heartbeat_interval(1000).build();
 
@@ -37,6 +37,9 @@ async fn main() {
             // Keep running
             tokio::signal::ctrl_c().await.expect("Failed to listen for ctrl-c");
             info!("Shutting down...");
+            if let Err(e) = ruft.shutdown().await {
+                error!("Failed to shut down Raft node: {}", e);
+            }
         }
         Err(e) => {
             eprintln!("Failed to create Raft node: {}", e);